@@ -0,0 +1,188 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// CPU を使う変換処理の同時実行数と待ち行列の長さを制限するアドミッション制御。
+///
+/// - 同時実行数が `max_concurrent` 未満なら即座に実行する
+/// - 埋まっている場合は最大 `max_queue_depth` 件まで待たせる。待ちは到着順 (FIFO)
+/// - 待ち行列も埋まっている場合は即座に拒否する (呼び出し側で 503 を返す)
+///
+/// 過負荷時に待ちリクエストとその入力データがメモリ上に溜まり続けることを防ぐ。
+#[derive(Debug)]
+pub struct AdmissionControl {
+    /// tokio の Semaphore は待ちを到着順に解放するため、そのまま FIFO キューとして使う
+    permits: Arc<Semaphore>,
+    max_queue_depth: usize,
+    /// permit を待っているリクエスト数
+    queued: AtomicUsize,
+}
+
+impl AdmissionControl {
+    /// `max_concurrent` が 0 の場合は同時実行数を制限しない。
+    pub fn new(max_concurrent: usize, max_queue_depth: usize) -> Self {
+        let permits = match max_concurrent {
+            0 => Semaphore::MAX_PERMITS,
+            n => n.min(Semaphore::MAX_PERMITS),
+        };
+        Self {
+            permits: Arc::new(Semaphore::new(permits)),
+            max_queue_depth,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// 実行枠を取得する。待ち行列が埋まっている場合は `None` を返す。
+    ///
+    /// 返した permit を保持している間が実行中として数えられる。
+    /// 変換を別スレッドに渡してもそのまま保持できるよう、所有権を持つ permit を返す。
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        // 待ちがいる間は空きが出てもそちらに先に割り当てられるため、追い越しは起きない
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+
+        let queued = self.queued.fetch_add(1, Ordering::AcqRel);
+        // クライアントの切断で待ちが中断された場合も数を戻す
+        let _slot = QueueSlot(&self.queued);
+        if queued >= self.max_queue_depth {
+            tracing::warn!(
+                queued,
+                max_queue_depth = self.max_queue_depth,
+                "admission queue full, rejecting request"
+            );
+            return None;
+        }
+
+        // Semaphore を close しないため acquire は失敗しない
+        self.permits.clone().acquire_owned().await.ok()
+    }
+}
+
+/// 待ち行列の 1 枠分。drop 時に待ち数を減らす。
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// 実行中の変換が使うメモリの見積もりの合計を予算内に制限する。
+///
+/// 同時実行数だけでは、大きな画像が重なった場合のピークメモリを抑えられないため、
+/// リクエストごとにデコード前の見積もりを予約し、予算を超える場合は拒否する。
+/// 他の予約と合わせて超える場合 (呼び出し側で 503) と、単独で超える場合 (再試行しても通らないため 422) を区別する。
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// 予算 (bytes)。0 の場合は制限しない
+    limit: u64,
+    /// 予約中の合計 (bytes)
+    in_flight: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            in_flight: AtomicU64::new(0),
+        }
+    }
+
+    /// 予算 (bytes)。0 の場合は制限しない
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// `bytes` を予約する。予算を超える場合はエラーを返す。
+    ///
+    /// 返した予約を保持している間が使用中として数えられる。
+    pub fn try_reserve(self: &Arc<Self>, bytes: u64) -> Result<MemoryReservation, ReserveError> {
+        if self.limit == 0 {
+            return Ok(MemoryReservation {
+                budget: self.clone(),
+                bytes: 0,
+            });
+        }
+        if bytes > self.limit {
+            return Err(ReserveError::ExceedsBudget {
+                bytes,
+                limit: self.limit,
+            });
+        }
+        let reserved =
+            self.in_flight
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+                    current
+                        .checked_add(bytes)
+                        .filter(|&total| total <= self.limit)
+                });
+        match reserved {
+            Ok(_) => Ok(MemoryReservation {
+                budget: self.clone(),
+                bytes,
+            }),
+            Err(in_flight) => {
+                tracing::warn!(
+                    requested = bytes,
+                    in_flight,
+                    limit = self.limit,
+                    "memory budget exceeded, rejecting request"
+                );
+                Err(ReserveError::Busy)
+            }
+        }
+    }
+}
+
+/// メモリの予約に失敗した理由。
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReserveError {
+    /// 実行中の予約と合わせると予算を超える。予約が解放されれば通る
+    Busy,
+    /// 単独で予算を超える。再試行しても通らない
+    ExceedsBudget { bytes: u64, limit: u64 },
+}
+
+/// 予約したメモリ。drop 時に予算へ戻す。
+pub struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.budget
+            .in_flight
+            .fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservation_over_the_whole_budget_is_not_busy() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        assert_eq!(
+            budget.try_reserve(101).err(),
+            Some(ReserveError::ExceedsBudget {
+                bytes: 101,
+                limit: 100
+            })
+        );
+
+        let held = budget.try_reserve(60).unwrap();
+        assert_eq!(budget.try_reserve(60).err(), Some(ReserveError::Busy));
+        drop(held);
+        assert!(budget.try_reserve(60).is_ok());
+    }
+
+    #[test]
+    fn zero_limit_reserves_anything() {
+        let budget = Arc::new(MemoryBudget::new(0));
+        assert!(budget.try_reserve(u64::MAX).is_ok());
+    }
+}
@@ -0,0 +1,105 @@
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::transform::{
+    Limits, ResizeSettings, TransformError, calculate_contain_dimensions, decode_image,
+    resize_image, validate_source_dimensions,
+};
+
+/// ヒストグラムを計算する前に縮小する長辺の上限。分布の形はほぼ変わらない
+const ANALYZE_MAX_SIDE: u32 = 1024;
+const CHANNEL_NAMES: [&str; 4] = ["r", "g", "b", "a"];
+
+/// 1 チャンネル分のヒストグラムと統計値。
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelStats {
+    pub name: &'static str,
+    /// 値 0-255 ごとのピクセル数
+    pub histogram: Vec<u64>,
+    pub min: u8,
+    pub max: u8,
+    pub mean: f64,
+}
+
+/// 画像解析の結果。
+#[derive(Debug, Serialize)]
+pub struct Analysis {
+    pub width: u32,
+    pub height: u32,
+    /// 実際に集計した画像のサイズ (大きい画像は縮小してから集計する)
+    pub sampled_width: u32,
+    pub sampled_height: u32,
+    /// アルファを持たない画像では r, g, b の 3 チャンネル
+    pub channels: Vec<ChannelStats>,
+}
+
+/// 画像をデコードし、チャンネルごとのヒストグラムと min/max/mean を返す。
+///
+/// 長辺が `ANALYZE_MAX_SIDE` を超える場合は contain で縮小してから集計する。
+pub fn analyze_image(input: &Bytes, limits: &Limits) -> Result<Analysis, TransformError> {
+    let img = decode_image(input, limits)?.image;
+    let (width, height) = (img.width(), img.height());
+
+    validate_source_dimensions(width, height, limits)?;
+
+    let has_alpha = img.color().has_alpha();
+    let sampled = if width.max(height) > ANALYZE_MAX_SIDE {
+        let (w, h) = calculate_contain_dimensions(
+            width,
+            height,
+            Some(ANALYZE_MAX_SIDE),
+            Some(ANALYZE_MAX_SIDE),
+        );
+        resize_image(&img, w, h, ResizeSettings::default(), None)?.into_rgba8()
+    } else {
+        img.into_rgba8()
+    };
+    let (sampled_width, sampled_height) = sampled.dimensions();
+
+    // 全チャンネルを 1 回の走査で集計する。min/max/mean はヒストグラムから求める
+    let mut histograms = [[0u64; 256]; 4];
+    for pixel in sampled.pixels() {
+        for (histogram, &value) in histograms.iter_mut().zip(pixel.0.iter()) {
+            histogram[value as usize] += 1;
+        }
+    }
+
+    let channel_count = if has_alpha { 4 } else { 3 };
+    let channels = histograms
+        .iter()
+        .zip(CHANNEL_NAMES)
+        .take(channel_count)
+        .map(|(histogram, name)| channel_stats(name, histogram))
+        .collect();
+
+    Ok(Analysis {
+        width,
+        height,
+        sampled_width,
+        sampled_height,
+        channels,
+    })
+}
+
+fn channel_stats(name: &'static str, histogram: &[u64; 256]) -> ChannelStats {
+    let total: u64 = histogram.iter().sum();
+    let sum: u64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(value, &count)| value as u64 * count)
+        .sum();
+    let min = histogram.iter().position(|&c| c > 0).unwrap_or(0) as u8;
+    let max = histogram.iter().rposition(|&c| c > 0).unwrap_or(0) as u8;
+
+    ChannelStats {
+        name,
+        histogram: histogram.to_vec(),
+        min,
+        max,
+        mean: if total == 0 {
+            0.0
+        } else {
+            sum as f64 / total as f64
+        },
+    }
+}
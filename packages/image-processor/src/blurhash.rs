@@ -0,0 +1,119 @@
+use bytes::Bytes;
+
+use crate::transform::{
+    Limits, ResizeSettings, TransformError, calculate_contain_dimensions, decode_image,
+    resize_image, validate_source_dimensions,
+};
+
+/// BlurHash を計算する前に縮小する長辺の上限。成分は低周波のみのため結果はほぼ変わらない
+const BLURHASH_MAX_SIDE: u32 = 32;
+/// 横・縦の成分数のデフォルト
+pub const DEFAULT_COMPONENTS_X: u32 = 4;
+pub const DEFAULT_COMPONENTS_Y: u32 = 3;
+/// 成分数の上限 (BlurHash の仕様)
+const MAX_COMPONENTS: u32 = 9;
+
+/// 画像をデコード・縮小し、`blurhash` crate で BlurHash の文字列を返す。
+///
+/// EXIF の向きを適用してから計算する。アルファを持つ画像は白背景に合成する
+/// (透明な画素は RGB が黒のことが多く、そのままでは暗いプレースホルダになるため)。
+pub fn blurhash(
+    input: &Bytes,
+    components_x: u32,
+    components_y: u32,
+    limits: &Limits,
+) -> Result<String, TransformError> {
+    for (name, value) in [("x", components_x), ("y", components_y)] {
+        if !(1..=MAX_COMPONENTS).contains(&value) {
+            return Err(TransformError::InvalidParams(format!(
+                "{name} must be 1-{MAX_COMPONENTS}, got {value}"
+            )));
+        }
+    }
+
+    let decoded = decode_image(input, limits)?;
+    let mut img = decoded.image;
+    if let Some(orientation) = decoded.orientation {
+        img.apply_orientation(orientation);
+    }
+    let (width, height) = (img.width(), img.height());
+    validate_source_dimensions(width, height, limits)?;
+
+    let sampled = if width.max(height) > BLURHASH_MAX_SIDE {
+        let (w, h) = calculate_contain_dimensions(
+            width,
+            height,
+            Some(BLURHASH_MAX_SIDE),
+            Some(BLURHASH_MAX_SIDE),
+        );
+        resize_image(&img, w, h, ResizeSettings::default(), None)?.into_rgba8()
+    } else {
+        img.into_rgba8()
+    };
+
+    let (w, h) = sampled.dimensions();
+    let over_white: Vec<u8> = sampled
+        .pixels()
+        .flat_map(|p| {
+            let alpha = p[3] as u32;
+            let blend = |v: u8| ((v as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8;
+            [blend(p[0]), blend(p[1]), blend(p[2]), 255]
+        })
+        .collect();
+
+    ::blurhash::encode(components_x, components_y, w, h, &over_white)
+        .map_err(|e| TransformError::ProcessingFailed(format!("blurhash failed: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn solid_png(width: u32, height: u32, color: [u8; 4]) -> Bytes {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba(color)));
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, ImageFormat::Png).unwrap();
+        Bytes::from(buf.into_inner())
+    }
+
+    #[test]
+    fn solid_color_has_only_the_dc_component() {
+        // 4x3 成分 ("L")、AC の最大値 0 ("0")、DC = #ff0000 ("TI:j")、AC はすべて 0 ("fQ" x 11)
+        let hash = blurhash(
+            &solid_png(16, 16, [255, 0, 0, 255]),
+            4,
+            3,
+            &Limits::default(),
+        )
+        .unwrap();
+        assert_eq!(hash, format!("L0TI:j{}", "fQ".repeat(11)));
+    }
+
+    #[test]
+    fn transparent_pixels_are_composited_over_white() {
+        let hash = blurhash(&solid_png(16, 16, [0, 0, 0, 0]), 4, 3, &Limits::default()).unwrap();
+        assert_eq!(hash, format!("L0TSUA{}", "fQ".repeat(11)));
+    }
+
+    #[test]
+    fn hash_length_follows_component_count() {
+        let input = solid_png(40, 20, [10, 120, 200, 255]);
+        for (x, y) in [(1, 1), (4, 3), (9, 9)] {
+            let hash = blurhash(&input, x, y, &Limits::default()).unwrap();
+            assert_eq!(hash.len() as u32, 4 + 2 * x * y, "{x}x{y}");
+        }
+    }
+
+    #[test]
+    fn out_of_range_components_are_rejected() {
+        let input = solid_png(4, 4, [0, 0, 0, 255]);
+        for (x, y) in [(0, 3), (4, 0), (10, 3), (4, 10)] {
+            let err = blurhash(&input, x, y, &Limits::default()).unwrap_err();
+            assert!(matches!(err, TransformError::InvalidParams(_)), "{err:?}");
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// 連続失敗回数で開閉するシンプルなサーキットブレーカー。
+///
+/// - Closed: 通常どおり通す。連続失敗が `threshold` に達すると Open へ
+/// - Open: `cooldown` の間は即座に拒否する
+/// - クールダウン経過後は 1 リクエストだけ試行 (probe) を通し、
+///   成功すれば Closed、失敗すれば再び Open に戻る
+///
+/// ロックを使わず atomic のみで状態を管理する。
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    /// 連続失敗回数
+    failures: AtomicU32,
+    /// Open 状態が終わる時刻 (`epoch` からのミリ秒)。0 は Closed
+    open_until: AtomicU64,
+    epoch: Instant,
+}
+
+impl CircuitBreaker {
+    /// `threshold` が 0 の場合は常に Closed (無効) として振る舞う。
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            failures: AtomicU32::new(0),
+            open_until: AtomicU64::new(0),
+            epoch: Instant::now(),
+        }
+    }
+
+    /// リクエストを通してよいかを返す。
+    ///
+    /// クールダウン経過後は最初の 1 リクエストのみ probe として通し、
+    /// 結果が記録されるまでの間は再度クールダウン分だけ他のリクエストを拒否する。
+    pub fn allow(&self) -> bool {
+        let open_until = self.open_until.load(Ordering::Acquire);
+        if open_until == 0 {
+            return true;
+        }
+        let now = self.now_millis();
+        if now < open_until {
+            return false;
+        }
+        let probe = self
+            .open_until
+            .compare_exchange(
+                open_until,
+                now + self.cooldown_millis(),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok();
+        if probe {
+            tracing::info!("circuit breaker half-open, probing storage");
+        }
+        probe
+    }
+
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::Release);
+        if self.open_until.swap(0, Ordering::AcqRel) != 0 {
+            tracing::info!("circuit breaker closed");
+        }
+    }
+
+    pub fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let failures = self
+            .failures
+            .fetch_add(1, Ordering::AcqRel)
+            .saturating_add(1);
+        if failures >= self.threshold {
+            let open_until = self.now_millis() + self.cooldown_millis();
+            if self.open_until.swap(open_until, Ordering::AcqRel) == 0 {
+                tracing::warn!(
+                    failures,
+                    cooldown_secs = self.cooldown.as_secs(),
+                    "circuit breaker opened"
+                );
+            }
+        }
+    }
+
+    fn now_millis(&self) -> u64 {
+        // 0 は Closed を表すため、起動直後でも 1 以上になるようにする
+        self.epoch.elapsed().as_millis() as u64 + 1
+    }
+
+    fn cooldown_millis(&self) -> u64 {
+        self.cooldown.as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    /// `threshold` 回失敗させて Open にしたブレーカー
+    fn opened(threshold: u32) -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(threshold, COOLDOWN);
+        for _ in 0..threshold {
+            breaker.record_failure();
+        }
+        breaker
+    }
+
+    fn wait_cooldown() {
+        std::thread::sleep(COOLDOWN * 2);
+    }
+
+    #[test]
+    fn opens_when_failures_reach_threshold() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn success_resets_consecutive_failures() {
+        let breaker = CircuitBreaker::new(2, COOLDOWN);
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn rejects_during_cooldown() {
+        let breaker = opened(2);
+        for _ in 0..3 {
+            assert!(!breaker.allow());
+        }
+    }
+
+    #[test]
+    fn allows_only_one_probe_after_cooldown() {
+        let breaker = opened(2);
+        wait_cooldown();
+        assert!(breaker.allow());
+        // probe の結果が記録されるまでは他のリクエストを拒否する
+        assert!(!breaker.allow());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn successful_probe_closes() {
+        let breaker = opened(2);
+        wait_cooldown();
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+        // 失敗回数もリセットされ、再び threshold 回で開く
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let breaker = opened(2);
+        wait_cooldown();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(!breaker.allow());
+        // 再びクールダウンを待てば次の probe を通す
+        wait_cooldown();
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn zero_threshold_never_opens() {
+        let breaker = opened(0);
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert!(breaker.allow());
+    }
+}
@@ -0,0 +1,314 @@
+/// テキスト判定に使う先頭バイト数
+const TEXT_PROBE_BYTES: usize = 512;
+
+/// マジックバイトから判別した画像フォーマット。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SniffedFormat {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+    Avif,
+}
+
+impl SniffedFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Gif => "image/gif",
+            Self::WebP => "image/webp",
+            Self::Avif => "image/avif",
+        }
+    }
+}
+
+/// 先頭のマジックバイトからフォーマットを判別する。
+pub fn sniff_format(data: &[u8]) -> Option<SniffedFormat> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(SniffedFormat::Jpeg)
+    } else if data.starts_with(&[0x89, 0x50, 0x4E, 0x47]) {
+        Some(SniffedFormat::Png)
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(SniffedFormat::Gif)
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some(SniffedFormat::WebP)
+    } else if data.len() >= 12 && (&data[4..12] == b"ftypavif" || &data[4..12] == b"ftypavis") {
+        Some(SniffedFormat::Avif)
+    } else {
+        None
+    }
+}
+
+/// 先頭の数百バイトがテキスト (HTML, JSON 等) に見えるかを判定する。
+///
+/// 画像のマジックバイトに一致せず、NUL を含まない UTF-8 であればテキストとみなす。
+/// 途中で切れたマルチバイト文字は許容する。
+pub fn is_probably_text(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(TEXT_PROBE_BYTES)];
+    if head.is_empty() || sniff_format(head).is_some() || head.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // 末尾のマルチバイト文字が途中で切れているだけなら UTF-8 とみなす
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// 先頭数 KB からアニメーション画像かどうかを判定する。
+///
+/// 全体をデコードせず、各フォーマットのアニメーション用ヘッダの有無のみを見る。
+/// - GIF: NETSCAPE2.0 アプリケーション拡張 (ループ指定)
+/// - PNG: IDAT より前の acTL チャンク (APNG)
+/// - WebP: VP8X チャンクのアニメーションフラグ
+/// - AVIF: ftyp ボックスのメジャー/互換ブランドに `avis` (画像シーケンス) を含む
+pub fn is_animated(data: &[u8], format: SniffedFormat) -> bool {
+    match format {
+        SniffedFormat::Jpeg => false,
+        SniffedFormat::Gif => data.windows(11).any(|w| w == b"NETSCAPE2.0"),
+        SniffedFormat::Png => png_has_actl(data),
+        SniffedFormat::WebP => data.len() >= 21 && &data[12..16] == b"VP8X" && data[20] & 0x02 != 0,
+        SniffedFormat::Avif => avif_is_sequence(data),
+    }
+}
+
+/// AVIF の ftyp ボックスのメジャーブランドまたは互換ブランドに `avis` があるかを返す。
+///
+/// メジャーブランドが `avif` でも、互換ブランドに `avis` を持つアニメーション AVIF がある。
+pub fn avif_is_sequence(data: &[u8]) -> bool {
+    if data.len() < 16 || &data[4..8] != b"ftyp" {
+        return false;
+    }
+    // ボックスサイズ (4) + "ftyp" (4) + メジャーブランド (4) + マイナーバージョン (4) + 互換ブランド (4 * n)
+    let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let end = size.min(data.len());
+    &data[8..12] == b"avis"
+        || data
+            .get(16..end)
+            .is_some_and(|brands| brands.chunks_exact(4).any(|b| b == b"avis"))
+}
+
+/// 先頭数 KB から読み取れる符号化方式の詳細。判別できない・該当しない項目は None。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncodingInfo {
+    /// JPEG: プログレッシブなら true、ベースラインなら false
+    pub progressive: Option<bool>,
+    /// PNG: Adam7 インターレースなら true
+    pub interlaced: Option<bool>,
+    /// WebP: VP8L (ロスレス) なら true、VP8 (ロッシー) なら false
+    pub lossless: Option<bool>,
+}
+
+/// 再エンコードの要否判断に使える符号化方式の情報をベストエフォートで返す。
+///
+/// AVIF のロスレス判定には AV1 のシーケンスヘッダの解析が必要なため対応しない。
+pub fn encoding_info(data: &[u8], format: SniffedFormat) -> EncodingInfo {
+    match format {
+        SniffedFormat::Jpeg => EncodingInfo {
+            progressive: jpeg_is_progressive(data),
+            ..EncodingInfo::default()
+        },
+        SniffedFormat::Png => EncodingInfo {
+            // シグネチャ (8) + 長さ (4) + "IHDR" (4) + 幅・高さ等 (12) の次がインターレース方式
+            interlaced: (data.len() > 28 && &data[12..16] == b"IHDR").then(|| data[28] == 1),
+            ..EncodingInfo::default()
+        },
+        SniffedFormat::WebP => EncodingInfo {
+            lossless: webp_is_lossless(data, 12),
+            ..EncodingInfo::default()
+        },
+        SniffedFormat::Gif | SniffedFormat::Avif => EncodingInfo::default(),
+    }
+}
+
+/// JPEG のマーカーを先頭から走査し、最初の SOF がプログレッシブかを返す。
+fn jpeg_is_progressive(data: &[u8]) -> Option<bool> {
+    // SOF0-15 (DHT, JPG, DAC を除く)。SOF2/6/10/14 がプログレッシブ
+    JpegSegments::new(data)
+        .find(|s| matches!(s.marker, 0xC0..=0xCF) && !matches!(s.marker, 0xC4 | 0xC8 | 0xCC))
+        .map(|s| matches!(s.marker, 0xC2 | 0xC6 | 0xCA | 0xCE))
+}
+
+/// WebP のチャンクを `offset` から走査し、最初の画像データが VP8L かを返す。
+///
+/// アニメーションの場合は最初の ANMF フレーム内のチャンクを見る。
+fn webp_is_lossless(data: &[u8], mut offset: usize) -> Option<bool> {
+    while offset + 8 <= data.len() {
+        let size = u32::from_le_bytes([
+            data[offset + 4],
+            data[offset + 5],
+            data[offset + 6],
+            data[offset + 7],
+        ]) as usize;
+        match &data[offset..offset + 4] {
+            b"VP8L" => return Some(true),
+            b"VP8 " => return Some(false),
+            // フレームヘッダ (16 bytes) の後にフレームのチャンクが続く
+            b"ANMF" => return webp_is_lossless(data, offset + 8 + 16),
+            _ => {}
+        }
+        // チャンクは偶数バイトにパディングされる
+        offset = offset
+            .saturating_add(8)
+            .saturating_add(size)
+            .saturating_add(size % 2);
+    }
+    None
+}
+
+/// JPEG の最初の SOS より後ろに EOI マーカーがあるかを返す。
+///
+/// 途中で切れたファイルの検出に使う。EOI の後ろに続くデータ (Motion Photo の動画や
+/// カメラ・編集ソフトのトレーラ等) は許容するため、末尾ではなく SOS 以降のどこかにあればよい。
+/// エントロピー符号化データ内の 0xFF は 0xFF 0x00 にエスケープされるため、EOI と紛れない。
+/// SOS の前の APP1 には EOI を含むサムネイルがありうるため探索の対象外とする。
+/// SOS を見つけられない場合は先頭から探す。
+pub fn jpeg_has_eoi(data: &[u8]) -> bool {
+    let start = jpeg_sos_offset(data).unwrap_or(2);
+    data.get(start..)
+        .is_some_and(|scan| scan.windows(2).any(|w| w == [0xFF, 0xD9]))
+}
+
+/// JPEG のマーカーを先頭から走査し、最初の SOS マーカーの位置を返す。
+fn jpeg_sos_offset(data: &[u8]) -> Option<usize> {
+    JpegSegments::new(data)
+        .find(|s| s.marker == 0xDA)
+        .map(|s| s.offset)
+}
+
+/// JPEG のマーカーセグメント。
+pub struct JpegSegment<'a> {
+    /// セグメント先頭 (0xFF) の位置
+    pub offset: usize,
+    pub marker: u8,
+    /// 長さフィールドの後ろのデータ。SOS と EOI では空
+    pub payload: &'a [u8],
+}
+
+/// JPEG のマーカーセグメントを SOI の後ろから順に返すイテレータ。
+///
+/// フィル用の 0xFF と長さを持たないマーカー (RSTn, TEM) は読み飛ばす。
+/// SOS か EOI を返した時点、またはマーカーでないバイトや壊れた長さに達した時点で終わる。
+/// 途中で切れたセグメントは返さず、[`JpegSegments::complete`] が false になる。
+pub struct JpegSegments<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+    complete: bool,
+}
+
+impl<'a> JpegSegments<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 2,
+            done: false,
+            complete: false,
+        }
+    }
+
+    /// データが途中で切れずに走査を終えたかを返す。走査を終える前は false。
+    pub fn complete(&self) -> bool {
+        self.complete
+    }
+
+    fn finish(&mut self, complete: bool) {
+        self.done = true;
+        self.complete = complete;
+    }
+}
+
+impl<'a> Iterator for JpegSegments<'a> {
+    type Item = JpegSegment<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.data;
+        while !self.done && self.offset + 4 <= data.len() {
+            let offset = self.offset;
+            if data[offset] != 0xFF {
+                self.finish(true);
+                return None;
+            }
+            let marker = data[offset + 1];
+            match marker {
+                // フィル用の 0xFF
+                0xFF => {
+                    self.offset += 1;
+                    continue;
+                }
+                // 長さを持たないマーカー (RSTn, TEM)
+                0xD0..=0xD7 | 0x01 => {
+                    self.offset += 2;
+                    continue;
+                }
+                // 以降はエントロピー符号化データ (またはファイルの終わり)
+                0xDA | 0xD9 => {
+                    self.finish(true);
+                    return Some(JpegSegment {
+                        offset,
+                        marker,
+                        payload: &[],
+                    });
+                }
+                _ => {}
+            }
+            let length = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+            // 長さはフィールド自身の 2 bytes を含む。それ未満は壊れたデータのため走査をやめる
+            if length < 2 {
+                self.finish(true);
+                return None;
+            }
+            let end = offset + 2 + length;
+            let Some(payload) = data.get(offset + 4..end) else {
+                self.finish(false);
+                return None;
+            };
+            self.offset = end;
+            return Some(JpegSegment {
+                offset,
+                marker,
+                payload,
+            });
+        }
+        self.done = true;
+        None
+    }
+}
+
+/// PNG の IHDR のカラータイプがインデックスカラー (3) かを返す。
+pub fn png_is_indexed(data: &[u8]) -> bool {
+    // シグネチャ (8) + 長さ (4) + "IHDR" (4) + 幅 (4) + 高さ (4) + ビット深度 (1) の次がカラータイプ
+    data.len() > 25 && &data[12..16] == b"IHDR" && data[25] == 3
+}
+
+/// PNG のチャンクを先頭から走査し、IDAT より前に acTL があるかを返す。
+fn png_has_actl(data: &[u8]) -> bool {
+    // シグネチャ (8 bytes) の後にチャンク (長さ 4 + 種別 4 + データ + CRC 4) が続く
+    let mut offset = 8;
+    while offset + 8 <= data.len() {
+        let length = u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ]) as usize;
+        match &data[offset + 4..offset + 8] {
+            b"acTL" => return true,
+            b"IDAT" => return false,
+            _ => {}
+        }
+        offset = offset.saturating_add(12).saturating_add(length);
+    }
+    false
+}
@@ -0,0 +1,135 @@
+use bytes::Bytes;
+use image::{DynamicImage, Rgba, RgbaImage};
+
+use crate::transform::{
+    EmbeddedMetadata, EncodeOptions, EncoderConfig, Limits, MAX_DIMENSION, OutputFormat,
+    ResizeSettings, TransformConfig, TransformError, calculate_contain_dimensions, decode_image,
+    encode_image, resize_image, resolve_quality, validate_source_dimensions,
+};
+
+/// 比較サイズ (長辺) のデフォルト
+pub const DEFAULT_DIFF_SIZE: u32 = 1024;
+/// 差分のない画素を薄く表示するため、輝度をこの割合で白に寄せる
+const FADE: f32 = 0.75;
+/// 差分のある画素の赤の不透明度の下限 (小さな差分も見えるようにする)
+const MIN_HIGHLIGHT: f32 = 0.3;
+
+/// 差分画像と比較結果。
+pub struct DiffOutput {
+    /// 差分画像 (PNG)
+    pub bytes: Bytes,
+    pub width: u32,
+    pub height: u32,
+    /// 全画素・全チャンネル (RGBA) の差の絶対値の平均を 0-1 に正規化した値
+    pub mean_absolute_error: f64,
+    /// いずれかのチャンネルが異なる画素数
+    pub changed_pixels: u64,
+}
+
+/// 2 枚の画像を同じサイズにそろえ、差分画像と平均絶対誤差を返す。
+///
+/// 比較サイズは 1 枚目を長辺 `size` に contain で縮小したサイズ (拡大はしない)。
+/// 2 枚目はアスペクト比にかかわらずそのサイズに拡縮する。
+/// 差分画像は、差分のない画素を 1 枚目の薄いグレースケールで、差分のある画素を差の大きさに応じた赤で示す。
+pub fn diff_images(
+    base: &Bytes,
+    compare: &Bytes,
+    size: u32,
+    limits: &Limits,
+    config: &TransformConfig,
+) -> Result<DiffOutput, TransformError> {
+    if size == 0 || size > MAX_DIMENSION {
+        return Err(TransformError::InvalidParams(format!(
+            "size must be 1-{MAX_DIMENSION}, got {size}"
+        )));
+    }
+
+    let base = decode_oriented(base, limits)?;
+    let compare = decode_oriented(compare, limits)?;
+
+    let (src_w, src_h) = (base.width(), base.height());
+    let (width, height) = if src_w.max(src_h) > size {
+        calculate_contain_dimensions(src_w, src_h, Some(size), Some(size))
+    } else {
+        (src_w, src_h)
+    };
+    let base = fit_to(&base, width, height, config)?;
+    let compare = fit_to(&compare, width, height, config)?;
+
+    // 差分画像の生成と誤差の集計を 1 回の走査で行う
+    let mut total_error: u64 = 0;
+    let mut changed_pixels: u64 = 0;
+    let diff = RgbaImage::from_fn(width, height, |x, y| {
+        let a = base.get_pixel(x, y).0;
+        let b = compare.get_pixel(x, y).0;
+        let mut max_delta = 0u8;
+        for (&va, &vb) in a.iter().zip(b.iter()) {
+            let delta = va.abs_diff(vb);
+            total_error += delta as u64;
+            max_delta = max_delta.max(delta);
+        }
+
+        let luma = 0.299 * a[0] as f32 + 0.587 * a[1] as f32 + 0.114 * a[2] as f32;
+        let faded = luma + (255.0 - luma) * FADE;
+        if max_delta == 0 {
+            let v = faded.round() as u8;
+            return Rgba([v, v, v, 255]);
+        }
+        changed_pixels += 1;
+        let t = MIN_HIGHLIGHT + (1.0 - MIN_HIGHLIGHT) * max_delta as f32 / 255.0;
+        let blend = |target: f32| (faded + (target - faded) * t).round() as u8;
+        Rgba([blend(255.0), blend(0.0), blend(0.0), 255])
+    });
+
+    let pixels = width as u64 * height as u64;
+    let mean_absolute_error = total_error as f64 / (pixels * 4 * 255) as f64;
+
+    let options = EncodeOptions {
+        quality: resolve_quality(OutputFormat::Png, None)?,
+        subsampling: None,
+        progressive: false,
+        metadata: EmbeddedMetadata::default(),
+        near_lossless: None,
+        prefer_palette: false,
+        encoder: EncoderConfig::default(),
+        colors: None,
+    };
+    let bytes = encode_image(&DynamicImage::ImageRgba8(diff), OutputFormat::Png, &options)?;
+
+    Ok(DiffOutput {
+        bytes: Bytes::from(bytes),
+        width,
+        height,
+        mean_absolute_error,
+        changed_pixels,
+    })
+}
+
+fn decode_oriented(input: &Bytes, limits: &Limits) -> Result<DynamicImage, TransformError> {
+    let decoded = decode_image(input, limits)?;
+    let mut img = decoded.image;
+    if let Some(orientation) = decoded.orientation {
+        img.apply_orientation(orientation);
+    }
+    validate_source_dimensions(img.width(), img.height(), limits)?;
+    Ok(img)
+}
+
+/// 比較サイズに拡縮した RGBA 画像を返す。同じサイズならリサイズしない。
+fn fit_to(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    config: &TransformConfig,
+) -> Result<RgbaImage, TransformError> {
+    if (img.width(), img.height()) == (width, height) {
+        return Ok(img.to_rgba8());
+    }
+    let settings = ResizeSettings {
+        premultiply_alpha: img.color().has_alpha(),
+        downscale_filter: config.downscale_filter,
+        upscale_filter: config.upscale_filter,
+        ..ResizeSettings::default()
+    };
+    Ok(resize_image(img, width, height, settings, None)?.into_rgba8())
+}
@@ -0,0 +1,618 @@
+use serde::Serialize;
+
+use crate::detect::{JpegSegments, SniffedFormat, sniff_format};
+
+/// JPEG の APP1 で EXIF を示す識別子
+const EXIF_IDENTIFIER: &[u8] = b"Exif\0\0";
+/// JPEG の APP1 / PNG の iTXt で XMP を示す識別子
+const XMP_IDENTIFIER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+
+// IFD0
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_SOFTWARE: u16 = 0x0131;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+// EXIF IFD
+const TAG_EXPOSURE_TIME: u16 = 0x829A;
+const TAG_F_NUMBER: u16 = 0x829D;
+const TAG_ISO: u16 = 0x8827;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_DATE_TIME_DIGITIZED: u16 = 0x9004;
+const TAG_FOCAL_LENGTH: u16 = 0x920A;
+const TAG_LENS_MAKE: u16 = 0xA433;
+const TAG_LENS_MODEL: u16 = 0xA434;
+// GPS IFD
+const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+const TAG_GPS_LATITUDE: u16 = 0x0002;
+const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+const TAG_GPS_LONGITUDE: u16 = 0x0004;
+const TAG_GPS_ALTITUDE_REF: u16 = 0x0005;
+const TAG_GPS_ALTITUDE: u16 = 0x0006;
+
+/// 画像ファイルから切り出した EXIF / XMP のセグメント。
+#[derive(Debug, Default)]
+pub struct MetadataSegments<'a> {
+    /// TIFF ヘッダから始まる生の EXIF (`Exif\0\0` は含まない)
+    pub exif: Option<&'a [u8]>,
+    pub xmp: Option<&'a [u8]>,
+    /// メタデータが置かれうる範囲を最後まで走査できたか。
+    /// false の場合、途中で切れたデータのため後ろにメタデータがある可能性がある
+    pub complete: bool,
+}
+
+/// JPEG / PNG / WebP のコンテナを走査し、EXIF と XMP のセグメントを探す。
+///
+/// 先頭の一部だけのデータも受け付け、足りない場合は `complete` を false にする。
+/// JPEG は SOS、PNG は IDAT より後ろは走査しない (メタデータは画像データより前に置かれる)。
+/// WebP はメタデータのチャンクが画像データの後ろに置かれるため最後まで走査する。
+pub fn find_metadata(data: &[u8]) -> MetadataSegments<'_> {
+    match sniff_format(data) {
+        Some(SniffedFormat::Jpeg) => jpeg_metadata(data),
+        Some(SniffedFormat::Png) => png_metadata(data),
+        Some(SniffedFormat::WebP) => webp_metadata(data),
+        // AVIF / GIF のメタデータには対応しない
+        _ => MetadataSegments {
+            complete: true,
+            ..MetadataSegments::default()
+        },
+    }
+}
+
+fn jpeg_metadata(data: &[u8]) -> MetadataSegments<'_> {
+    let mut segments = MetadataSegments::default();
+    let mut jpeg = JpegSegments::new(data);
+    for segment in jpeg.by_ref().filter(|s| s.marker == 0xE1) {
+        if let Some(exif) = segment.payload.strip_prefix(EXIF_IDENTIFIER) {
+            segments.exif.get_or_insert(exif);
+        } else if let Some(xmp) = segment.payload.strip_prefix(XMP_IDENTIFIER) {
+            segments.xmp.get_or_insert(xmp);
+        }
+    }
+    segments.complete = jpeg.complete();
+    segments
+}
+
+fn png_metadata(data: &[u8]) -> MetadataSegments<'_> {
+    let mut segments = MetadataSegments::default();
+    let mut offset = 8;
+    while offset + 8 <= data.len() {
+        let length = u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ]) as usize;
+        let kind = &data[offset + 4..offset + 8];
+        if kind == b"IDAT" || kind == b"IEND" {
+            segments.complete = true;
+            return segments;
+        }
+        let start = offset + 8;
+        let Some(chunk) = data.get(start..start.saturating_add(length)) else {
+            return segments;
+        };
+        match kind {
+            b"eXIf" => {
+                segments.exif.get_or_insert(chunk);
+            }
+            // キーワード、圧縮フラグ (1)、圧縮方式 (1)、言語タグ、翻訳キーワードの後にテキストが続く。
+            // 圧縮された XMP は扱わない
+            b"iTXt" => {
+                if let Some(rest) = chunk.strip_prefix(XMP_PNG_KEYWORD)
+                    && rest.first() == Some(&0)
+                {
+                    let text = rest
+                        .get(2..)
+                        .and_then(|r| skip_nul_terminated(r, 2))
+                        .unwrap_or_default();
+                    segments.xmp.get_or_insert(text);
+                }
+            }
+            _ => {}
+        }
+        offset = start.saturating_add(length).saturating_add(4);
+    }
+    segments
+}
+
+/// NUL 終端の文字列を `count` 個読み飛ばした残りを返す。
+fn skip_nul_terminated(mut data: &[u8], count: usize) -> Option<&[u8]> {
+    for _ in 0..count {
+        let nul = data.iter().position(|&b| b == 0)?;
+        data = &data[nul + 1..];
+    }
+    Some(data)
+}
+
+fn webp_metadata(data: &[u8]) -> MetadataSegments<'_> {
+    let mut segments = MetadataSegments::default();
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let size = u32::from_le_bytes([
+            data[offset + 4],
+            data[offset + 5],
+            data[offset + 6],
+            data[offset + 7],
+        ]) as usize;
+        let start = offset + 8;
+        let kind = &data[offset..offset + 4];
+        if matches!(kind, b"EXIF" | b"XMP ") {
+            let Some(chunk) = data.get(start..start.saturating_add(size)) else {
+                return segments;
+            };
+            if kind == b"EXIF" {
+                // 仕様外だが `Exif\0\0` 付きで書き込むエンコーダがある
+                segments
+                    .exif
+                    .get_or_insert(chunk.strip_prefix(EXIF_IDENTIFIER).unwrap_or(chunk));
+            } else {
+                segments.xmp.get_or_insert(chunk);
+            }
+        }
+        offset = start.saturating_add(size).saturating_add(size % 2);
+    }
+    segments.complete = offset >= data.len();
+    segments
+}
+
+/// `/exif` が返すメタデータ。含まれない項目は省略する。
+#[derive(Debug, Default, Serialize)]
+pub struct ExifSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera: Option<Camera>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lens: Option<Lens>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exposure: Option<Exposure>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps: Option<Gps>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamps: Option<Timestamps>,
+    /// EXIF の Orientation (1-8)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<u32>,
+    /// XMP パケット (XML) をそのまま返す
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xmp: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Camera {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub software: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Lens {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// 焦点距離 (mm)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focal_length: Option<f64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Exposure {
+    /// 露出時間 (秒)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exposure_time: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub f_number: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iso: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Gps {
+    /// 緯度 (南緯は負)
+    pub latitude: f64,
+    /// 経度 (西経は負)
+    pub longitude: f64,
+    /// 海抜 (m、海面下は負)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Timestamps {
+    /// 撮影日時 (DateTimeOriginal)。EXIF の `YYYY:MM:DD HH:MM:SS` 形式のまま返す
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original: Option<String>,
+    /// デジタル化日時 (DateTimeDigitized)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digitized: Option<String>,
+    /// 更新日時 (DateTime)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<String>,
+}
+
+/// 切り出したセグメントから EXIF / XMP を読み取る。どちらもない場合は空の `ExifSummary` を返す。
+///
+/// 壊れた EXIF はエラーにせず、読み取れた項目のみを返す。
+pub fn read_metadata(segments: &MetadataSegments<'_>) -> ExifSummary {
+    let mut summary = segments
+        .exif
+        .and_then(Tiff::new)
+        .map(|tiff| tiff.summary())
+        .unwrap_or_default();
+    summary.xmp = segments.xmp.map(|xmp| {
+        String::from_utf8_lossy(xmp)
+            .trim_end_matches('\0')
+            .to_string()
+    });
+    summary
+}
+
+/// IFD のエントリ。値が 4 bytes 以下の場合は `value_offset` がエントリ内を指す。
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value_offset: usize,
+}
+
+/// TIFF 構造の EXIF を読むための最小限のパーサ。
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// `offset` の IFD のエントリを読む。範囲外の場合は読めた分のみ返す
+    fn ifd(&self, offset: usize) -> Vec<Entry> {
+        let Some(count) = self.u16_at(offset) else {
+            return Vec::new();
+        };
+        (0..count as usize)
+            .map_while(|i| {
+                let entry = offset + 2 + i * 12;
+                let kind = self.u16_at(entry + 2)?;
+                let count = self.u32_at(entry + 4)?;
+                let size = type_size(kind).saturating_mul(count as usize);
+                let value_offset = if size <= 4 {
+                    entry + 8
+                } else {
+                    self.u32_at(entry + 8)? as usize
+                };
+                Some(Entry {
+                    tag: self.u16_at(entry)?,
+                    kind,
+                    count,
+                    value_offset,
+                })
+            })
+            .collect()
+    }
+
+    fn ascii(&self, entry: &Entry) -> Option<String> {
+        if entry.kind != 2 {
+            return None;
+        }
+        let bytes = self
+            .data
+            .get(entry.value_offset..entry.value_offset.checked_add(entry.count as usize)?)?;
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim_end_matches('\0').trim();
+        (!text.is_empty()).then(|| text.to_string())
+    }
+
+    fn uint(&self, entry: &Entry) -> Option<u32> {
+        match entry.kind {
+            1 | 7 => self.data.get(entry.value_offset).map(|&b| b as u32),
+            3 => self.u16_at(entry.value_offset).map(u32::from),
+            4 => self.u32_at(entry.value_offset),
+            _ => None,
+        }
+    }
+
+    /// RATIONAL / SRATIONAL の `index` 番目の値
+    fn rational(&self, entry: &Entry, index: u32) -> Option<f64> {
+        if !matches!(entry.kind, 5 | 10) || index >= entry.count {
+            return None;
+        }
+        let offset = entry.value_offset + index as usize * 8;
+        let (numerator, denominator) = (self.u32_at(offset)?, self.u32_at(offset + 4)?);
+        if denominator == 0 {
+            return None;
+        }
+        Some(if entry.kind == 10 {
+            numerator as i32 as f64 / denominator as i32 as f64
+        } else {
+            numerator as f64 / denominator as f64
+        })
+    }
+
+    /// 度・分・秒の 3 つの RATIONAL を度に換算する
+    fn degrees(&self, entry: &Entry) -> Option<f64> {
+        let d = self.rational(entry, 0)?;
+        let m = self.rational(entry, 1).unwrap_or(0.0);
+        let s = self.rational(entry, 2).unwrap_or(0.0);
+        Some(d + m / 60.0 + s / 3600.0)
+    }
+
+    fn summary(&self) -> ExifSummary {
+        let ifd0 = self
+            .u32_at(4)
+            .map(|o| self.ifd(o as usize))
+            .unwrap_or_default();
+        let find = |entries: &[Entry], tag: u16| entries.iter().find(|e| e.tag == tag).copied();
+        let sub_ifd = |tag: u16| {
+            find(&ifd0, tag)
+                .and_then(|e| self.uint(&e))
+                .map(|o| self.ifd(o as usize))
+                .unwrap_or_default()
+        };
+        let exif = sub_ifd(TAG_EXIF_IFD);
+        let gps = sub_ifd(TAG_GPS_IFD);
+
+        let ascii = |entries: &[Entry], tag: u16| find(entries, tag).and_then(|e| self.ascii(&e));
+        let uint = |entries: &[Entry], tag: u16| find(entries, tag).and_then(|e| self.uint(&e));
+        let rational =
+            |entries: &[Entry], tag: u16| find(entries, tag).and_then(|e| self.rational(&e, 0));
+
+        let camera = Camera {
+            make: ascii(&ifd0, TAG_MAKE),
+            model: ascii(&ifd0, TAG_MODEL),
+            software: ascii(&ifd0, TAG_SOFTWARE),
+        };
+        let lens = Lens {
+            make: ascii(&exif, TAG_LENS_MAKE),
+            model: ascii(&exif, TAG_LENS_MODEL),
+            focal_length: rational(&exif, TAG_FOCAL_LENGTH),
+        };
+        let exposure = Exposure {
+            exposure_time: rational(&exif, TAG_EXPOSURE_TIME),
+            f_number: rational(&exif, TAG_F_NUMBER),
+            iso: uint(&exif, TAG_ISO),
+        };
+        let timestamps = Timestamps {
+            original: ascii(&exif, TAG_DATE_TIME_ORIGINAL),
+            digitized: ascii(&exif, TAG_DATE_TIME_DIGITIZED),
+            modified: ascii(&ifd0, TAG_DATE_TIME),
+        };
+
+        // 緯度・経度の片方しかない場合は位置として扱わない
+        let coordinate = |value: u16, reference: u16, negative: &str| {
+            let degrees = find(&gps, value).and_then(|e| self.degrees(&e))?;
+            let sign = if ascii(&gps, reference).as_deref() == Some(negative) {
+                -1.0
+            } else {
+                1.0
+            };
+            Some(sign * degrees)
+        };
+        let gps = match (
+            coordinate(TAG_GPS_LATITUDE, TAG_GPS_LATITUDE_REF, "S"),
+            coordinate(TAG_GPS_LONGITUDE, TAG_GPS_LONGITUDE_REF, "W"),
+        ) {
+            (Some(latitude), Some(longitude)) => Some(Gps {
+                latitude,
+                longitude,
+                altitude: rational(&gps, TAG_GPS_ALTITUDE).map(|altitude| {
+                    if uint(&gps, TAG_GPS_ALTITUDE_REF) == Some(1) {
+                        -altitude
+                    } else {
+                        altitude
+                    }
+                }),
+            }),
+            _ => None,
+        };
+
+        ExifSummary {
+            camera: (camera.make.is_some() || camera.model.is_some() || camera.software.is_some())
+                .then_some(camera),
+            lens: (lens.make.is_some() || lens.model.is_some() || lens.focal_length.is_some())
+                .then_some(lens),
+            exposure: (exposure.exposure_time.is_some()
+                || exposure.f_number.is_some()
+                || exposure.iso.is_some())
+            .then_some(exposure),
+            gps,
+            timestamps: (timestamps.original.is_some()
+                || timestamps.digitized.is_some()
+                || timestamps.modified.is_some())
+            .then_some(timestamps),
+            orientation: uint(&ifd0, TAG_ORIENTATION),
+            xmp: None,
+        }
+    }
+}
+
+/// TIFF のデータ型ごとの 1 要素のバイト数
+fn type_size(kind: u16) -> usize {
+    match kind {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// リトルエンディアンの TIFF を組み立てる。IFD0 はオフセット 8 に置き、
+    /// `extra` は IFD0 の直後 (`ifd_end(entries.len())` の位置) に続ける
+    fn tiff(entries: &[(u16, u16, u32, u32)], extra: &[u8]) -> Vec<u8> {
+        let mut data = b"II*\0".to_vec();
+        data.extend(8u32.to_le_bytes());
+        data.extend((entries.len() as u16).to_le_bytes());
+        for &(tag, kind, count, value) in entries {
+            data.extend(tag.to_le_bytes());
+            data.extend(kind.to_le_bytes());
+            data.extend(count.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        }
+        // 次の IFD なし
+        data.extend(0u32.to_le_bytes());
+        data.extend(extra);
+        data
+    }
+
+    fn ifd_end(entries: usize) -> u32 {
+        (8 + 2 + entries * 12 + 4) as u32
+    }
+
+    /// APP1 に `payload` を入れた JPEG (SOS 以降は省略)
+    fn jpeg_with_app1(payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        data.extend(((payload.len() + 2) as u16).to_be_bytes());
+        data.extend(payload);
+        data.extend([0xFF, 0xDA, 0x00, 0x02]);
+        data
+    }
+
+    /// Make と Orientation を持つ EXIF 入りの JPEG
+    fn sample_jpeg() -> Vec<u8> {
+        let exif = tiff(
+            &[(TAG_MAKE, 2, 6, ifd_end(2)), (TAG_ORIENTATION, 3, 1, 6)],
+            b"Canon\0",
+        );
+        jpeg_with_app1(&[EXIF_IDENTIFIER, &exif].concat())
+    }
+
+    #[test]
+    fn reads_exif_from_jpeg_app1() {
+        let data = sample_jpeg();
+        let segments = find_metadata(&data);
+        assert!(segments.complete);
+        let summary = read_metadata(&segments);
+        assert_eq!(
+            summary.camera.and_then(|c| c.make).as_deref(),
+            Some("Canon")
+        );
+        assert_eq!(summary.orientation, Some(6));
+    }
+
+    #[test]
+    fn app1_shorter_than_its_length_field_is_ignored() {
+        for length in [0u8, 1] {
+            let data = [0xFF, 0xD8, 0xFF, 0xE1, 0x00, length, b'E', b'x', b'i', b'f'];
+            let segments = find_metadata(&data);
+            assert!(segments.exif.is_none(), "length {length}");
+        }
+
+        // 長さがデータの終端を超える (途中で切れた) APP1
+        let data = sample_jpeg();
+        let segments = find_metadata(&data[..data.len() / 2]);
+        assert!(segments.exif.is_none());
+        assert!(!segments.complete);
+    }
+
+    #[test]
+    fn malformed_ifd_offsets_and_counts_are_ignored() {
+        let cases = [
+            // IFD0 がデータの外
+            {
+                let mut data = tiff(&[], &[]);
+                data[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+                data
+            },
+            // エントリ数がデータより多い
+            {
+                let mut data = tiff(&[(TAG_ORIENTATION, 3, 1, 6)], &[]);
+                data[8..10].copy_from_slice(&u16::MAX.to_le_bytes());
+                data
+            },
+            // 値のオフセット・個数が範囲外
+            tiff(
+                &[
+                    (TAG_MAKE, 2, u32::MAX, u32::MAX),
+                    (TAG_MODEL, 2, 16, u32::MAX - 4),
+                    (TAG_SOFTWARE, 2, u32::MAX, 8),
+                ],
+                &[],
+            ),
+            // サブ IFD がデータの外、または IFD0 自身を指す
+            tiff(
+                &[(TAG_EXIF_IFD, 4, 1, u32::MAX), (TAG_GPS_IFD, 4, 1, 8)],
+                &[],
+            ),
+            // RATIONAL の個数に対してデータが足りない
+            tiff(
+                &[
+                    (TAG_GPS_IFD, 4, 1, 8),
+                    (TAG_GPS_LATITUDE, 5, u32::MAX, ifd_end(2)),
+                ],
+                &[0; 4],
+            ),
+            // 未知の型
+            tiff(&[(TAG_ORIENTATION, 0xFFFF, u32::MAX, 0)], &[]),
+        ];
+        for (i, exif) in cases.iter().enumerate() {
+            let segments = MetadataSegments {
+                exif: Some(exif.as_slice()),
+                ..MetadataSegments::default()
+            };
+            let summary = read_metadata(&segments);
+            assert!(summary.camera.is_none(), "case {i}: {summary:?}");
+            assert!(summary.gps.is_none(), "case {i}: {summary:?}");
+        }
+    }
+
+    #[test]
+    fn truncated_and_mutated_input_does_not_panic() {
+        let data = sample_jpeg();
+        for end in 0..data.len() {
+            read_metadata(&find_metadata(&data[..end]));
+        }
+
+        // 固定シードの xorshift で 1-4 バイトを書き換える
+        let mut seed = 0x9E37_79B9u32;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for _ in 0..10_000 {
+            let mut mutated = data.clone();
+            for _ in 0..=next() % 4 {
+                let i = next() as usize % mutated.len();
+                mutated[i] = next() as u8;
+            }
+            read_metadata(&find_metadata(&mutated));
+        }
+    }
+}
@@ -1,15 +1,80 @@
-use axum::extract::{Path, Query, State};
-use axum::http::{StatusCode, header};
+use axum::extract::multipart::{MultipartError, MultipartRejection};
+use axum::extract::{Multipart, Path, Query, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header};
 use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::Bytes;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::Instrument;
 
 use crate::AppState;
-use crate::storage::StorageError;
-use crate::transform::{OutputFormat, TransformError, TransformParams};
+use crate::admission::{MemoryReservation, ReserveError};
+use crate::blurhash::{DEFAULT_COMPONENTS_X, DEFAULT_COMPONENTS_Y};
+use crate::detect::{SniffedFormat, encoding_info, is_animated, is_probably_text, sniff_format};
+use crate::diff::DEFAULT_DIFF_SIZE;
+use crate::montage::MontageParams;
+use crate::remote::RemoteError;
+use crate::storage::{MAX_INPUT_SIZE, StorageError, StoredObject};
+use crate::tile::{DEFAULT_TILE_SIZE, TileParams};
+use crate::transform::{
+    BlurRegion, Channels, ChromaSubsampling, EncoderConfig, Fit, Limits, MAX_DIMENSION, Mask,
+    Orient, OutputFormat, PngCompression, PngFilter, QualityMode, Roi, Strip, TransformConfig,
+    TransformError, TransformOutput, TransformParams,
+};
 
 const CACHE_CONTROL_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// `/optimize` のリクエストボディの上限。multipart の境界やヘッダの分だけ入力上限より大きくする
+pub const OPTIMIZE_BODY_LIMIT: usize = MAX_INPUT_SIZE as usize + 64 * 1024;
+/// `/optimize` で画像を受け取る multipart のフィールド名
+const OPTIMIZE_FILE_FIELD: &str = "file";
+/// `on_error=image` のプレースホルダのデフォルトサイズ (`w`/`h` 未指定時)
+const ERROR_IMAGE_DEFAULT_SIZE: u32 = 64;
+/// `on_error=image` のプレースホルダの 1 辺の上限
+const ERROR_IMAGE_MAX_SIZE: u32 = 1024;
+/// `/detect` で取得する先頭バイト数
+const DETECT_PROBE_BYTES: u64 = 4096;
+/// `/exif` で最初に取得する先頭バイト数。メタデータがこの範囲を超える場合のみ全体を取得する
+const EXIF_PROBE_BYTES: u64 = 256 * 1024;
+/// 1x1 の透明 GIF (トラッキングピクセル用)
+const PIXEL_GIF: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xFF, 0xFF, 0xFF, 0x21, 0xF9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2C, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3B,
+];
+/// 1x1 の透明 PNG (トラッキングピクセル用)
+const PIXEL_PNG: &[u8] = &[
+    0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1F, 0x15, 0xC4,
+    0x89, 0x00, 0x00, 0x00, 0x0B, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA, 0x63, 0x60, 0x00, 0x02, 0x00,
+    0x00, 0x05, 0x00, 0x01, 0xE9, 0xFA, 0xDC, 0xD8, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44,
+    0xAE, 0x42, 0x60, 0x82,
+];
+const X_IMAGE_WIDTH: HeaderName = HeaderName::from_static("x-image-width");
+const X_IMAGE_HEIGHT: HeaderName = HeaderName::from_static("x-image-height");
+const X_IMAGE_QUALITY: HeaderName = HeaderName::from_static("x-image-quality");
+const X_IMAGE_FORMAT: HeaderName = HeaderName::from_static("x-image-format");
+const X_AUTO_COMPRESS: HeaderName = HeaderName::from_static("x-auto-compress");
+const X_OUTPUT_WIDTH: HeaderName = HeaderName::from_static("x-output-width");
+const X_OUTPUT_HEIGHT: HeaderName = HeaderName::from_static("x-output-height");
+const X_SOURCE_FORMAT: HeaderName = HeaderName::from_static("x-source-format");
+const X_DIFF_MAE: HeaderName = HeaderName::from_static("x-diff-mae");
+const X_DIFF_CHANGED_PIXELS: HeaderName = HeaderName::from_static("x-diff-changed-pixels");
+const X_DEADLINE_MS: HeaderName = HeaderName::from_static("x-deadline-ms");
+const X_SPRITE_LAYOUT: HeaderName = HeaderName::from_static("x-sprite-layout");
+const X_INTERNAL_TOKEN: HeaderName = HeaderName::from_static("x-internal-token");
+const SEC_CH_DPR: HeaderName = HeaderName::from_static("sec-ch-dpr");
+const DPR: HeaderName = HeaderName::from_static("dpr");
+const ACCEPT_CH: HeaderName = HeaderName::from_static("accept-ch");
+/// 代表色 JSON のデフォルトの色数
+const DEFAULT_PALETTE_COLORS: u16 = 8;
+/// `dpr` パラメータ・ヘッダの上限
+const MAX_DPR: f32 = 4.0;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct TransformQuery {
     #[serde(rename = "w")]
     pub width: Option<u32>,
@@ -18,23 +83,1993 @@ pub struct TransformQuery {
     #[serde(rename = "f")]
     pub format: Option<String>,
     #[serde(rename = "q")]
-    pub quality: Option<u8>,
+    pub quality: Option<f32>,
+    #[serde(rename = "premultiply")]
+    pub premultiply_alpha: Option<bool>,
+    pub linear_resize: Option<bool>,
+    pub mask: Option<String>,
+    pub radius: Option<u32>,
+    pub subsampling: Option<String>,
+    pub orient: Option<String>,
+    /// 取得するオブジェクトのバージョン ID (未指定時は最新)
+    pub version: Option<String>,
+    pub strip: Option<String>,
+    pub max_bytes: Option<usize>,
+    /// `q` の解釈 (`raw` / `perceptual`)。デフォルトは `raw`
+    pub quality_mode: Option<String>,
+    pub fit: Option<String>,
+    pub no_upscale: Option<bool>,
+    /// `fit=fill` のパディング色 (`RRGGBB` または `RRGGBBAA`)
+    pub bg: Option<String>,
+    /// `fit=fill` で出力に残す領域 (`x,y,w,h`、0-1 の相対座標)
+    pub roi: Option<String>,
+    /// リサイズ後にぼかす領域 (`x,y,w,h`、0-1 の相対座標またはピクセル座標)
+    pub blur_region: Option<String>,
+    pub near_lossless: Option<u8>,
+    /// 出力のカラータイプ (`rgb` / `rgba` / `gray` / `graya`)
+    pub channels: Option<String>,
+    /// `dataurl` の場合は画像を `data:` URI の文字列で返す
+    pub encode: Option<String>,
+    /// true の場合は他のパラメータを無視して原本を返す
+    pub original: Option<bool>,
+    /// プログレッシブ JPEG で出力する (JPEG のみ)
+    pub progressive: Option<bool>,
+    /// 名前付きのパラメータセット (`web`)。明示指定したパラメータが優先される
+    pub preset: Option<String>,
+    /// true の場合は `Content-Disposition: attachment` を付けて返す
+    pub download: Option<bool>,
+    /// `image` の場合、エラー時に JSON ではなくプレースホルダ画像を返す (デフォルト: `json`)
+    pub on_error: Option<String>,
+    /// デバイスピクセル比。`w`/`h` に乗算する (0 より大きく `MAX_DPR` 以下)
+    pub dpr: Option<f32>,
+    /// 代表色 JSON (`Accept: application/json`) で返す色数 (1-256)
+    pub palette: Option<u16>,
+    /// `compress` の場合、変換結果が原本より大きければ原本を返す
+    pub auto: Option<String>,
+    /// 表示密度の倍率 (1-4)。`w`/`h` に乗算し、PNG に密度を書き込む。`dpr` とは併用不可
+    pub retina: Option<u8>,
+    /// ソースの幅の下限。下回る場合は 422
+    pub min_source_width: Option<u32>,
+    /// `dimensions` の場合、変換後のサイズのみをヘッダで返す (デコード・エンコードしない)
+    pub probe: Option<String>,
+    /// AVIF のエンコード速度 (1-10)。`AVIF_SPEED` を上書きする
+    pub avif_speed: Option<u8>,
+    /// PNG の圧縮レベル (`fast` / `default` / `best`)。`PNG_COMPRESSION` を上書きする
+    pub png_compression: Option<String>,
+    /// PNG の行フィルタ (`none` / `sub` / `up` / `avg` / `paeth` / `adaptive`)。`PNG_FILTER` を上書きする
+    pub png_filter: Option<String>,
+    /// WebP の method (0-6)。`WEBP_METHOD` を上書きする
+    pub webp_method: Option<u8>,
+    /// PNG 出力で減色する色数 (2-256)
+    pub colors: Option<u16>,
+    /// true の場合は変換パラメータがなくても再エンコードする (未指定時は `FORCE_REENCODE`)
+    pub force_reencode: Option<bool>,
+    /// true の場合は最も暗い値を黒、最も明るい値を白に引き伸ばす
+    pub normalize: Option<bool>,
+    /// true の場合は途中で切れた JPEG もデコードできた範囲で変換する
+    pub allow_partial: Option<bool>,
+    /// 取得元の署名付き URL (`/transform` のみ。許可リストのホストに限る)
+    pub src: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HealthQuery {
+    /// true の場合は各出力フォーマットのエンコードまで確認する
+    pub deep: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PixelQuery {
+    /// `gif` (デフォルト) または `png`
+    #[serde(rename = "f")]
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TileQuery {
+    pub z: u32,
+    pub x: u32,
+    pub y: u32,
+    pub size: Option<u32>,
+    #[serde(rename = "f")]
+    pub format: Option<String>,
+    #[serde(rename = "q")]
+    pub quality: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MontageQuery {
+    /// カンマ区切りのキー一覧。左上から行優先で配置する
+    pub keys: String,
+    pub cols: u32,
+    /// 省略時はキーの数と `cols` から決める
+    pub rows: Option<u32>,
+    pub cell: u32,
+    #[serde(rename = "f")]
+    pub format: Option<String>,
+    #[serde(rename = "q")]
+    pub quality: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    /// 比較対象のキー
+    pub compare: String,
+    /// 比較サイズ (長辺)。デフォルトは `DEFAULT_DIFF_SIZE`
+    pub size: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlurhashQuery {
+    /// 横方向の成分数 (1-9)。デフォルトは `DEFAULT_COMPONENTS_X`
+    pub x: Option<u32>,
+    /// 縦方向の成分数 (1-9)。デフォルトは `DEFAULT_COMPONENTS_Y`
+    pub y: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpriteQuery {
+    /// カンマ区切りのサイズ一覧 (例: `16,32,64`)
+    pub sizes: String,
+}
+
+/// ヘルスチェック。
+///
+/// デフォルトは即座に `ok` を返す。`deep=true` の場合は全出力フォーマットで
+/// 合成画像をエンコードし、フォーマットごとの結果を返す (1 つでも失敗すれば 503)。
+pub async fn health(Query(query): Query<HealthQuery>) -> Response {
+    if !query.deep.unwrap_or(false) {
+        return (StatusCode::OK, "ok").into_response();
+    }
+
+    let mut healthy = true;
+    let formats: serde_json::Map<_, _> = OutputFormat::ALL
+        .into_iter()
+        .map(|format| {
+            let status = match crate::transform::check_encoder(format) {
+                Ok(()) => "ok".to_string(),
+                Err(e) => {
+                    tracing::error!(format = format.name(), error = %e, "encoder health check failed");
+                    healthy = false;
+                    e.to_string()
+                }
+            };
+            (format.name().to_string(), serde_json::Value::String(status))
+        })
+        .collect();
+
+    let (status, label) = if healthy {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+    };
+    let body = serde_json::json!({ "status": label, "formats": formats });
+    (status, axum::Json(body)).into_response()
 }
 
-pub async fn health() -> impl IntoResponse {
-    (StatusCode::OK, "ok")
+/// ビルドと対応コーデックの情報を返す。デプロイの確認用。
+///
+/// コミットはビルド時の環境変数 `GIT_COMMIT` (未設定時は null)。
+/// デコーダは `image` crate で有効なフォーマット、エンコーダは出力フォーマットの一覧。
+/// AVIF は常にエンコーダを組み込み、HEIC / JPEG XL / mozjpeg は組み込んでいないため常に false。
+pub async fn version() -> Response {
+    // `image` の avif feature はエンコーダのみで、reading_enabled は true でもデコードできない
+    let decoders: Vec<_> = image::ImageFormat::all()
+        .filter(|&f| f.reading_enabled() && f != image::ImageFormat::Avif)
+        .map(|f| format!("{f:?}").to_lowercase())
+        .collect();
+    let encoders: Vec<_> = OutputFormat::ALL.into_iter().map(|f| f.name()).collect();
+    let body = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": option_env!("GIT_COMMIT"),
+        "decoders": decoders,
+        "encoders": encoders,
+        "codecs": {
+            "avif": true,
+            "heic": false,
+            "jxl": false,
+            "mozjpeg": false,
+        },
+    });
+    axum::Json(body).into_response()
 }
 
 pub async fn transform(
     State(state): State<AppState>,
     Path(key): Path<String>,
     Query(query): Query<TransformQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let error_image = match query.on_error.as_deref() {
+        None | Some("json") => false,
+        Some("image") => true,
+        Some(e) => {
+            return Err(AppError::BadRequest(format!(
+                "unsupported on_error '{e}'. supported: json, image"
+            )));
+        }
+    };
+    let (width, height) = (query.width, query.height);
+
+    match transform_object(&state, &key, query, &headers).await {
+        Err(err) if error_image => Ok(error_image_response(err, width, height)),
+        result => result,
+    }
+}
+
+/// パスで指定したバケットのオブジェクトを変換する (`/b/{bucket}/transform/{*key}`)。
+///
+/// バケットは `ALLOWED_BUCKETS` に含まれるもののみ許可し、それ以外は 403。
+/// 変換パラメータと動作は `/transform/{*key}` と同じ。
+pub async fn transform_in_bucket(
+    State(state): State<AppState>,
+    Path((bucket, key)): Path<(String, String)>,
+    query: Query<TransformQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if !state.allowed_buckets.contains(&bucket) {
+        return Err(AppError::Forbidden("bucket is not allowed".to_string()));
+    }
+    let state = AppState {
+        r2_client: state.r2_client.with_bucket(&bucket),
+        ..state
+    };
+    transform(State(state), Path(key), query, headers).await
+}
+
+/// `<img>` で表示できるよう、エラーをプレースホルダ画像として返す。
+///
+/// ステータスコードは元のエラーのまま。サイズはリクエストの `w`/`h` に合わせる (上限あり)。
+fn error_image_response(err: AppError, width: Option<u32>, height: Option<u32>) -> Response {
+    // ログ出力とステータスコードの決定は JSON の場合と共通
+    let status = err.into_response().status();
+
+    let clamp = |v: u32| v.clamp(1, ERROR_IMAGE_MAX_SIZE);
+    let (width, height) = match (width, height) {
+        (Some(w), Some(h)) => (clamp(w), clamp(h)),
+        (Some(side), None) | (None, Some(side)) => (clamp(side), clamp(side)),
+        (None, None) => (ERROR_IMAGE_DEFAULT_SIZE, ERROR_IMAGE_DEFAULT_SIZE),
+    };
+
+    match crate::transform::placeholder_image(width, height) {
+        Ok(bytes) => (
+            status,
+            [
+                (header::CONTENT_TYPE, OutputFormat::Png.content_type()),
+                // エラーは一時的な場合があるため、キャッシュさせない
+                (header::CACHE_CONTROL, "no-store"),
+            ],
+            bytes,
+        )
+            .into_response(),
+        Err(e) => AppError::Internal(format!("failed to render error image: {e}")).into_response(),
+    }
+}
+
+/// クエリ文字列を使わず、パスセグメントでパラメータを受け取る。
+///
+/// クエリ文字列を削除する CDN 向けの代替ルート (`/t/{params}/{*key}`)。
+pub async fn transform_with_path_params(
+    State(state): State<AppState>,
+    Path((params, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let query = parse_path_params(&params)?;
+    transform_object(&state, &key, query, &headers).await
+}
+
+async fn transform_object(
+    state: &AppState,
+    key: &str,
+    mut query: TransformQuery,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    validate_key(state, key)?;
+    if query.src.is_some() {
+        return Err(AppError::BadRequest(
+            "src cannot be combined with an object key".to_string(),
+        ));
+    }
+
+    let download = query.download.unwrap_or(false);
+    let deadline = request_deadline(state, headers)?;
+
+    // ダウンロード用途など、変換パラメータが付いていても原本を返す
+    if query.original.unwrap_or(false) {
+        let response = passthrough(state, key, query.version.as_deref(), headers).await?;
+        return Ok(with_download_filename(response, download, key, None));
+    }
+
+    apply_preset(&mut query)?;
+    query.force_reencode.get_or_insert(state.force_reencode);
+    if let Some(dpr) = resolve_dpr(state, &query, headers)? {
+        apply_dpr(&mut query, dpr);
+    }
+    let params = TransformParams {
+        avif_unsupported: avif_unsupported(&state.avif_unsupported_user_agents, headers),
+        ..build_transform_params(&query, extension_hint(key))?
+    };
+    let avif_by_user_agent = varies_by_user_agent(state, params.format);
+
+    let data_url = match query.encode.as_deref() {
+        None => false,
+        Some("dataurl") => true,
+        Some(e) => {
+            return Err(AppError::BadRequest(format!(
+                "unsupported encode '{e}'. supported: dataurl"
+            )));
+        }
+    };
+    if data_url && download {
+        return Err(AppError::BadRequest(
+            "download cannot be combined with encode=dataurl".to_string(),
+        ));
+    }
+
+    let version = query.version.as_deref();
+
+    match query.probe.as_deref() {
+        None => {}
+        Some("dimensions") => {
+            let input_bytes = fetch_verified(state, key, version).await?;
+            ensure_image(&input_bytes)?;
+            let (width, height) = crate::transform::output_dimensions(&input_bytes, &params)?;
+            return Ok((
+                StatusCode::OK,
+                [
+                    (header::CACHE_CONTROL, state.cache_policy.immutable()),
+                    (X_OUTPUT_WIDTH, width.to_string()),
+                    (X_OUTPUT_HEIGHT, height.to_string()),
+                ],
+            )
+                .into_response());
+        }
+        Some(p) => {
+            return Err(AppError::BadRequest(format!(
+                "unsupported probe '{p}'. supported: dimensions"
+            )));
+        }
+    }
+
+    if wants_palette_json(state, headers) {
+        if data_url || download {
+            return Err(AppError::BadRequest(
+                "encode and download cannot be combined with a JSON palette".to_string(),
+            ));
+        }
+        return palette_response(state, key, version, &params, query.palette, headers).await;
+    }
+
+    // AVIF を表示できないクライアントには AVIF の原本を返さないよう、取得して判別する
+    if !params.needs_transform() && !params.avif_unsupported {
+        if data_url {
+            let body = fetch_verified(state, key, version).await?;
+            return Ok(data_url_response(&infer_content_type(&body), &body));
+        }
+        let mut response = passthrough(state, key, version, headers).await?;
+        if avif_by_user_agent {
+            insert_vary(&mut response, &[header::USER_AGENT]);
+        }
+        return Ok(with_download_filename(response, download, key, None));
+    }
+
+    // 入力を取得する前に枠を確保し、待ち中のリクエストがメモリを占有しないようにする
+    let permit = within_deadline(deadline, "admission", admit(state)).await?;
+    let input_bytes =
+        within_deadline(deadline, "fetch", fetch_verified(state, key, version)).await?;
+    ensure_image(&input_bytes)?;
+
+    if !params.needs_transform() && !is_avif(&input_bytes) {
+        if data_url {
+            return Ok(data_url_response(
+                &infer_content_type(&input_bytes),
+                &input_bytes,
+            ));
+        }
+        let mut response = original_response(state, input_bytes);
+        if avif_by_user_agent {
+            insert_vary(&mut response, &[header::USER_AGENT]);
+        }
+        return Ok(with_download_filename(response, download, key, None));
+    }
+
+    // 一致すればデコード・エンコードを省いて 304 を返す。data URL は本文の形式が異なるため対象外
+    let etag = (!data_url).then(|| weak_etag(&input_bytes, &params, &state.transform_config));
+    if let Some(etag) = &etag
+        && matches_if_none_match(headers, etag)
+    {
+        return Ok(not_modified_response(
+            &state.cache_policy,
+            etag,
+            &response_vary(state, avif_by_user_agent),
+        ));
+    }
+
+    tracing::info!(
+        key = %key,
+        version = ?version,
+        w = ?params.width,
+        h = ?params.height,
+        f = ?params.format,
+        q = ?params.quality,
+        "transforming image"
+    );
+
+    let reservation = reserve_memory(state, &input_bytes, &params)?;
+    let limits = Limits {
+        deadline,
+        ..request_limits(state, headers)
+    };
+    let output = run_transform(
+        state,
+        &input_bytes,
+        &params,
+        limits,
+        tracing::info_span!("transform", key = %key),
+        (permit, reservation),
+    )
+    .await?;
+
+    tracing::info!(
+        key = %key,
+        source_format = ?output.source_format,
+        content_type = output.content_type,
+        "transformed image"
+    );
+    let (output, auto_choice) = smaller_of_original(&input_bytes, output, &params);
+
+    if data_url {
+        return Ok(data_url_response(output.content_type, &output.bytes));
+    }
+
+    let extension = OutputFormat::ALL
+        .into_iter()
+        .find(|f| f.content_type() == output.content_type)
+        .map(|f| f.extension());
+
+    let mut response = transformed_response(state, output, avif_by_user_agent);
+    if let Some(value) = etag.and_then(|etag| HeaderValue::from_str(&etag).ok()) {
+        response.headers_mut().insert(header::ETAG, value);
+    }
+    if let Some(choice) = auto_choice {
+        response
+            .headers_mut()
+            .insert(X_AUTO_COMPRESS, HeaderValue::from_static(choice));
+    }
+    Ok(with_download_filename(response, download, key, extension))
+}
+
+/// `Accept` が画像より JSON を優先しているかを返す。`PALETTE_NEGOTIATION` が無効なら常に false。
+///
+/// `application/json` の q 値が画像 (`image/*`、`*/*` を含む) の最大の q 値以上なら JSON とする。
+/// ブラウザの `<img>` は JSON を Accept に含めないため、画像の取得には影響しない。
+fn wants_palette_json(state: &AppState, headers: &HeaderMap) -> bool {
+    if !state.palette_negotiation {
+        return false;
+    }
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+
+    let (mut json_q, mut image_q) = (0.0f32, 0.0f32);
+    for (media_type, q) in parse_accept(accept) {
+        if media_type == "application/json" {
+            json_q = json_q.max(q);
+        } else if media_type == "*/*" || media_type.starts_with("image/") {
+            image_q = image_q.max(q);
+        }
+    }
+    json_q > 0.0 && json_q >= image_q
+}
+
+/// `Accept` ヘッダをメディアタイプ (小文字) と q 値の組に分解し、q 値の降順に並べる。
+///
+/// q 値が同じ場合はヘッダ内の順序を保つ。q 値は 0-1 に丸め、数値として読めない
+/// メディアレンジは無視する (不正な q 値を最優先の 1 として扱わないため)。
+fn parse_accept(accept: &str) -> Vec<(String, f32)> {
+    let mut ranges: Vec<(String, f32)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next().filter(|t| !t.is_empty())?.to_ascii_lowercase();
+            let q = match parts.find_map(|p| {
+                let (name, value) = p.split_once('=')?;
+                name.trim().eq_ignore_ascii_case("q").then(|| value.trim())
+            }) {
+                Some(q) => q
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|q| q.is_finite())?
+                    .clamp(0.0, 1.0),
+                None => 1.0,
+            };
+            Some((media_type, q))
+        })
+        .collect();
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges
+}
+
+/// 変換結果の代表色を JSON で返す。
+///
+/// 代表色はピクセルから求めるため、エンコード用のパラメータ (`f` `q` `max_bytes` 等) は無視し、
+/// PNG 出力として加工した (エンコード前の) 画像から減色する。
+async fn palette_response(
+    state: &AppState,
+    key: &str,
+    version: Option<&str>,
+    params: &TransformParams,
+    colors: Option<u16>,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let colors = colors.unwrap_or(DEFAULT_PALETTE_COLORS);
+    if !(1..=256).contains(&colors) {
+        return Err(AppError::BadRequest(format!(
+            "palette must be 1-256, got {colors}"
+        )));
+    }
+    let params = TransformParams {
+        format: Some(OutputFormat::Png),
+        quality: None,
+        subsampling: None,
+        progressive: false,
+        max_bytes: None,
+        near_lossless: None,
+        encoder: EncoderConfig::default(),
+        retina: None,
+        auto_compress: false,
+        strip: None,
+        // 変換パラメータがなくても PNG に変換するため常に変換経路に乗る
+        ..params.clone()
+    };
+
+    let deadline = request_deadline(state, headers)?;
+    let permit = within_deadline(deadline, "admission", admit(state)).await?;
+    let input_bytes =
+        within_deadline(deadline, "fetch", fetch_verified(state, key, version)).await?;
+    ensure_image(&input_bytes)?;
+
+    tracing::info!(key = %key, colors, "extracting palette");
+
+    let reservation = reserve_memory(state, &input_bytes, &params)?;
+    let limits = Limits {
+        deadline,
+        ..request_limits(state, headers)
+    };
+    let config = state.transform_config.clone();
+    let (width, height, palette) = run_blocking(
+        deadline,
+        "palette",
+        tracing::info_span!("palette", key = %key),
+        (permit, reservation),
+        move || {
+            // ピクセルだけが必要なため、エンコードせずに加工後の画像から減色する
+            let image =
+                crate::transform::prepare_image(&input_bytes, &params, &limits, &config)?.image;
+            Ok((
+                image.width(),
+                image.height(),
+                crate::quantize::palette(&image, colors),
+            ))
+        },
+    )
+    .await?;
+
+    let total = width as f64 * height as f64;
+    let colors: Vec<_> = palette
+        .iter()
+        .map(|c| {
+            let [r, g, b, _] = c.rgba;
+            serde_json::json!({
+                "hex": format!("#{r:02x}{g:02x}{b:02x}"),
+                "rgba": c.rgba,
+                "frequency": c.count as f64 / total,
+            })
+        })
+        .collect();
+    let body = serde_json::json!({
+        "width": width,
+        "height": height,
+        "colors": colors,
+    });
+
+    let mut response = (
+        StatusCode::OK,
+        [(header::CACHE_CONTROL, state.cache_policy.immutable())],
+        axum::Json(body),
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("Accept"));
+    Ok(response)
+}
+
+/// `src` で指定された署名付き URL (R2/S3 の presigned URL 等) の画像を変換する。
+///
+/// サービスの R2 認証情報は使わず、URL に直接アクセスして取得する。
+/// `REMOTE_SOURCE_HOSTS` が未設定の場合は 404。
+/// キーに紐づく機能 (`version`, `original`, `probe`, `download`, `encode`) は使えない。
+pub async fn transform_remote(
+    State(state): State<AppState>,
+    Query(mut query): Query<TransformQuery>,
+    headers: HeaderMap,
 ) -> Result<Response, AppError> {
-    validate_key(&key)?;
+    let Some(fetcher) = state.remote_fetcher.clone() else {
+        return Err(AppError::NotFound("not found".to_string()));
+    };
+    let src = query
+        .src
+        .take()
+        .ok_or_else(|| AppError::BadRequest("src is required".to_string()))?;
+    let unsupported = [
+        ("version", query.version.is_some()),
+        ("original", query.original.is_some()),
+        ("probe", query.probe.is_some()),
+        ("download", query.download.is_some()),
+        ("encode", query.encode.is_some()),
+        ("on_error", query.on_error.is_some()),
+    ];
+    if let Some((name, _)) = unsupported.into_iter().find(|(_, set)| *set) {
+        return Err(AppError::BadRequest(format!(
+            "{name} cannot be combined with src"
+        )));
+    }
+
+    let url = fetcher.validate(&src)?;
+    let deadline = request_deadline(&state, &headers)?;
+
+    apply_preset(&mut query)?;
+    query.force_reencode.get_or_insert(state.force_reencode);
+    if let Some(dpr) = resolve_dpr(&state, &query, &headers)? {
+        apply_dpr(&mut query, dpr);
+    }
+    let params = TransformParams {
+        avif_unsupported: avif_unsupported(&state.avif_unsupported_user_agents, &headers),
+        ..build_transform_params(&query, extension_hint(url.path()))?
+    };
+    let avif_by_user_agent = varies_by_user_agent(&state, params.format);
+
+    let permit = within_deadline(deadline, "admission", admit(&state)).await?;
+    // クエリ文字列には署名が含まれるため、ログにはホストとパスのみ出す
+    let source = format!("{}{}", url.host_str().unwrap_or_default(), url.path());
+    tracing::info!(src = %source, "fetching remote source");
+    let fetch = async {
+        Ok(fetcher
+            .fetch(&url)
+            .instrument(tracing::info_span!("remote_fetch", src = %source))
+            .await?)
+    };
+    let input_bytes = within_deadline(deadline, "fetch", fetch).await?;
+    ensure_image(&input_bytes)?;
+
+    // AVIF を表示できないクライアントには、AVIF の原本を変換経路で WebP にして返す
+    if !params.needs_transform() && !(params.avif_unsupported && is_avif(&input_bytes)) {
+        let mut response = original_response(&state, input_bytes);
+        if avif_by_user_agent {
+            insert_vary(&mut response, &[header::USER_AGENT]);
+        }
+        return Ok(response);
+    }
+
+    let etag = weak_etag(&input_bytes, &params, &state.transform_config);
+    if matches_if_none_match(&headers, &etag) {
+        return Ok(not_modified_response(
+            &state.cache_policy,
+            &etag,
+            &response_vary(&state, avif_by_user_agent),
+        ));
+    }
+
+    let reservation = reserve_memory(&state, &input_bytes, &params)?;
+    let limits = Limits {
+        deadline,
+        ..request_limits(&state, &headers)
+    };
+    let output = run_transform(
+        &state,
+        &input_bytes,
+        &params,
+        limits,
+        tracing::info_span!("transform", src = %source),
+        (permit, reservation),
+    )
+    .await?;
+
+    tracing::info!(
+        src = %source,
+        source_format = ?output.source_format,
+        content_type = output.content_type,
+        "transformed image"
+    );
+    let (output, auto_choice) = smaller_of_original(&input_bytes, output, &params);
+
+    let mut response = transformed_response(&state, output, avif_by_user_agent);
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        response.headers_mut().insert(header::ETAG, value);
+    }
+    if let Some(choice) = auto_choice {
+        response
+            .headers_mut()
+            .insert(X_AUTO_COMPRESS, HeaderValue::from_static(choice));
+    }
+    Ok(response)
+}
+
+/// 取得した原本と変換パラメータから弱い ETag を計算する。
+///
+/// R2 への HEAD を使わず、原本の内容に `etag_key` の文字列を加えてハッシュする。
+/// AVIF のタイムアウト時のフォールバック等で同じ入力でもバイト列が一致しない場合が
+/// あるため、弱い ETag とする。
+fn weak_etag(input: &[u8], params: &TransformParams, config: &TransformConfig) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(input);
+    hasher.update(etag_key(params, config).as_bytes());
+    let digest = hasher.finalize();
+    format!("W/\"{}\"", hex::encode(&digest[..16]))
+}
+
+/// ETag に含める、出力を決めるパラメータと設定を `name=value;` の形で並べる。
+///
+/// フィールドを追加した際にここを見直すよう、構造体は網羅的に分解する。
+/// 設定のうちエラーにするかどうかだけを決める項目 (禁止する変換、品質の下限等) は含めない。
+fn etag_key(params: &TransformParams, config: &TransformConfig) -> String {
+    let TransformParams {
+        width,
+        height,
+        format,
+        quality,
+        premultiply_alpha,
+        linear_resize,
+        mask,
+        subsampling,
+        progressive,
+        orient,
+        extension_hint,
+        strip,
+        max_bytes,
+        quality_mode,
+        fit,
+        no_upscale,
+        background,
+        roi,
+        blur_region,
+        near_lossless,
+        channels,
+        min_source_width: _,
+        encoder,
+        colors,
+        force_reencode,
+        normalize,
+        retina,
+        auto_compress,
+        allow_partial,
+        avif_unsupported,
+    } = params;
+    let TransformConfig {
+        forbidden_conversions: _,
+        min_quality_jpeg: _,
+        min_quality_avif: _,
+        quality_curve,
+        downscale_filter,
+        upscale_filter,
+        max_output_bytes: _,
+        max_upscale: _,
+        avif_encode_timeout,
+        max_abandoned_avif_encodes: _,
+        encoder: default_encoder,
+        verify_output: _,
+    } = config;
+
+    let fields: &[(&str, &dyn std::fmt::Debug)] = &[
+        ("w", width),
+        ("h", height),
+        ("f", format),
+        ("q", quality),
+        ("premultiply", premultiply_alpha),
+        ("linear", linear_resize),
+        ("mask", mask),
+        ("subsampling", subsampling),
+        ("progressive", progressive),
+        ("orient", orient),
+        ("extension_hint", extension_hint),
+        ("strip", strip),
+        ("max_bytes", max_bytes),
+        ("quality_mode", quality_mode),
+        ("fit", fit),
+        ("no_upscale", no_upscale),
+        ("bg", background),
+        ("roi", roi),
+        ("blur_region", blur_region),
+        ("near_lossless", near_lossless),
+        ("channels", channels),
+        ("encoder", encoder),
+        ("colors", colors),
+        ("force_reencode", force_reencode),
+        ("normalize", normalize),
+        ("retina", retina),
+        ("auto", auto_compress),
+        ("allow_partial", allow_partial),
+        ("avif_unsupported", avif_unsupported),
+        ("quality_curve", quality_curve),
+        ("downscale_filter", downscale_filter),
+        ("upscale_filter", upscale_filter),
+        ("avif_timeout", avif_encode_timeout),
+        ("default_encoder", default_encoder),
+    ];
+    fields
+        .iter()
+        .map(|(name, value)| format!("{name}={value:?};"))
+        .collect()
+}
+
+/// `If-None-Match` のいずれかのタグが `etag` に弱い比較で一致するかを返す。
+fn matches_if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| {
+        let tag = tag.trim();
+        tag.strip_prefix("W/").unwrap_or(tag).to_string()
+    };
+    let expected = opaque(etag);
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == expected)
+}
+
+/// 304 を返す。キャッシュが 200 と同じキーで扱えるよう、`vary` には 200 と同じものを渡す。
+fn not_modified_response(cache: &CachePolicy, etag: &str, vary: &[HeaderName]) -> Response {
+    let mut response = (
+        StatusCode::NOT_MODIFIED,
+        [
+            (header::ETAG, etag.to_string()),
+            (header::CACHE_CONTROL, cache.immutable()),
+        ],
+    )
+        .into_response();
+    insert_vary(&mut response, vary);
+    response
+}
+
+/// 変換結果のレスポンスを組み立て、出力が依存するリクエストヘッダを `Vary` に付ける。
+fn transformed_response(
+    state: &AppState,
+    output: TransformOutput,
+    avif_by_user_agent: bool,
+) -> Response {
+    let mut response = image_response(
+        &state.cache_policy,
+        output,
+        &response_vary(state, avif_by_user_agent),
+    );
+    if state.client_hints {
+        // 以降のリクエストでブラウザに DPR を送らせる
+        response
+            .headers_mut()
+            .insert(ACCEPT_CH, HeaderValue::from_static("Sec-CH-DPR, DPR"));
+    }
+    response
+}
+
+/// 変換結果が依存するリクエストヘッダ (`Vary` に並べるもの) を返す。
+fn response_vary(state: &AppState, avif_by_user_agent: bool) -> Vec<HeaderName> {
+    // クライアントヒントが有効な場合、出力サイズは DPR ヘッダに依存する
+    let mut vary = Vec::new();
+    if state.client_hints {
+        vary.extend([SEC_CH_DPR, DPR]);
+    }
+    if avif_by_user_agent {
+        vary.push(header::USER_AGENT);
+    }
+    // 同じ URL で Accept によって代表色の JSON を返しうる
+    if state.palette_negotiation {
+        vary.push(header::ACCEPT);
+    }
+    vary
+}
+
+/// `auto=compress` で、変換結果より原本の方が小さければ原本を返す。
+///
+/// 原本を返すのは、見た目を変えるパラメータ (マスク、向きの上書き、カラータイプ、減色、
+/// normalize、リニア光でのリサイズ、retina の密度) がなく、サイズ (向き適用後) が変換結果と同じで、
+/// フォーマットを判別できる場合のみ。原本は EXIF (GPS を含む)・XMP・ICC プロファイルを
+/// すべて保持しているため、`strip=none` 以外 (デフォルトの `all` や `preset=web` を含む) では返さない。
+/// `auto=compress` の場合は選んだ側 (`original` / `transformed`) を `X-Auto-Compress` 用に返す。
+/// AVIF を表示できないクライアントには AVIF の原本を返さない。
+fn smaller_of_original(
+    input: &Bytes,
+    output: TransformOutput,
+    params: &TransformParams,
+) -> (TransformOutput, Option<&'static str>) {
+    if !params.auto_compress {
+        return (output, None);
+    }
+    // ぼかした領域を原本で戻さないよう、blur_region 指定時は原本を返さない
+    let same_content = params.mask.is_none()
+        && params.blur_region.is_none()
+        && matches!(params.orient, None | Some(Orient::Auto))
+        && params.channels.is_none()
+        && params.colors.is_none()
+        && !params.normalize
+        && !params.linear_resize
+        && params.retina.is_none()
+        && params.strip == Some(Strip::None)
+        && !(params.avif_unsupported && is_avif(input));
+    let same_size = crate::transform::header_dimensions(input, Orient::Auto)
+        == Some((output.width, output.height));
+    match sniff_format(input) {
+        Some(format) if same_content && same_size && input.len() <= output.bytes.len() => {
+            tracing::info!(
+                original_size = input.len(),
+                transformed_size = output.bytes.len(),
+                "transformed image is not smaller, serving original"
+            );
+            let original = TransformOutput {
+                bytes: input.clone(),
+                content_type: format.content_type(),
+                quality: None,
+                ..output
+            };
+            (original, Some("original"))
+        }
+        _ => (output, Some("transformed")),
+    }
+}
+
+/// AVIF を表示できないクライアントかを User-Agent から判定する。
+///
+/// `AVIF_UNSUPPORTED_USER_AGENTS` のいずれかが User-Agent に含まれる場合に true。
+/// 結果は各ルートのパラメータに渡し、出力フォーマットの決定 (`determine_output_format`) で
+/// AVIF を WebP に置き換える。
+fn avif_unsupported(patterns: &[String], headers: &HeaderMap) -> bool {
+    if patterns.is_empty() {
+        return false;
+    }
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let unsupported = patterns
+        .iter()
+        .any(|pattern| user_agent.contains(pattern.as_str()));
+    if unsupported {
+        tracing::debug!(
+            user_agent,
+            "AVIF unsupported by client, falling back to WebP"
+        );
+    }
+    unsupported
+}
+
+/// 出力が User-Agent に依存する (`Vary: User-Agent` が必要な) 場合に true を返す。
+///
+/// `AVIF_UNSUPPORTED_USER_AGENTS` の設定時、`f=avif` または `f` の省略 (AVIF の原本を維持しうる) が対象。
+fn varies_by_user_agent(state: &AppState, format: Option<OutputFormat>) -> bool {
+    !state.avif_unsupported_user_agents.is_empty()
+        && matches!(format, None | Some(OutputFormat::Avif))
+}
+
+/// マジックバイトが AVIF かを返す。
+fn is_avif(data: &[u8]) -> bool {
+    sniff_format(data) == Some(SniffedFormat::Avif)
+}
+
+/// `w`/`h` に乗算するデバイスピクセル比を決める。
+///
+/// `dpr` パラメータを優先し、未指定かつクライアントヒントが有効な場合は
+/// `Sec-CH-DPR` (なければ旧名の `DPR`) ヘッダを使う。
+/// ヘッダはブラウザが自動で送るため、不正な値は無視し、範囲外の値は丸める。
+fn resolve_dpr(
+    state: &AppState,
+    query: &TransformQuery,
+    headers: &HeaderMap,
+) -> Result<Option<f32>, AppError> {
+    // retina は build_transform_params で w/h に乗算するため、二重に拡大しない
+    if query.retina.is_some() {
+        if query.dpr.is_some() {
+            return Err(AppError::BadRequest(
+                "retina cannot be combined with dpr".to_string(),
+            ));
+        }
+        return Ok(None);
+    }
+    if let Some(dpr) = query.dpr {
+        if !(dpr > 0.0 && dpr <= MAX_DPR) {
+            return Err(AppError::BadRequest(format!(
+                "dpr must be greater than 0 and at most {MAX_DPR}, got {dpr}"
+            )));
+        }
+        return Ok(Some(dpr));
+    }
+    if !state.client_hints {
+        return Ok(None);
+    }
+
+    Ok(header_dpr(headers))
+}
+
+/// `Sec-CH-DPR` (なければ旧名の `DPR`) ヘッダの値を 1 から `MAX_DPR` の範囲に丸めて返す。
+fn header_dpr(headers: &HeaderMap) -> Option<f32> {
+    headers
+        .get(SEC_CH_DPR)
+        .or_else(|| headers.get(DPR))
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<f32>().ok())
+        .filter(|dpr| dpr.is_finite() && *dpr > 0.0)
+        .map(|dpr| dpr.clamp(1.0, MAX_DPR))
+}
+
+/// `w`/`h` にデバイスピクセル比を乗算する。
+///
+/// `retina` と同じく、拡大後のサイズは `MAX_DIMENSION` に収める。クライアントが指定していない
+/// サイズで 400 にしないため、上限を超える場合は縦横比を保つよう倍率の方を下げる。
+/// `w`/`h` 自体が上限を超えている場合は拡大しないだけで、検証はそのまま変換側に任せる。
+fn apply_dpr(query: &mut TransformQuery, dpr: f32) {
+    let largest = query
+        .width
+        .into_iter()
+        .chain(query.height)
+        .max()
+        .unwrap_or(1);
+    let dpr = dpr.min((MAX_DIMENSION as f32 / largest as f32).max(1.0));
+    let scale = |v: u32| ((v as f32 * dpr).round() as u32).clamp(1, MAX_DIMENSION.max(v));
+    query.width = query.width.map(scale);
+    query.height = query.height.map(scale);
+}
+
+/// `download` が true の場合、キーのベース名をファイル名とした `Content-Disposition` を付ける。
+///
+/// `extension` を指定した場合はベース名の拡張子を置き換える。
+/// `filename` はヘッダインジェクションを防ぐため、ASCII 英数字と `-` `_` `.` 以外は `_` に置き換える。
+/// 元の名前は RFC 6266 の `filename*` に UTF-8 でパーセントエンコードして付ける。
+fn with_download_filename(
+    mut response: Response,
+    download: bool,
+    key: &str,
+    extension: Option<&str>,
+) -> Response {
+    if !download {
+        return response;
+    }
+
+    let name = key.rsplit('/').next().unwrap_or(key);
+    let stem = match extension {
+        Some(_) => name.rsplit_once('.').map_or(name, |(stem, _)| stem),
+        None => name,
+    };
+    let mut filename: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if filename.is_empty() {
+        filename = "image".to_string();
+    }
+    let mut original = if stem.is_empty() { "image" } else { stem }.to_string();
+    if let Some(extension) = extension {
+        filename = format!("{filename}.{extension}");
+        original = format!("{original}.{extension}");
+    }
+
+    let value = format!(
+        "attachment; filename=\"{filename}\"; filename*=UTF-8''{}",
+        percent_encode_filename(&original)
+    );
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_DISPOSITION, value);
+    }
+    response
+}
+
+/// RFC 8187 の `attr-char` 以外のバイトを `%XX` にエンコードする。
+fn percent_encode_filename(name: &str) -> String {
+    name.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect()
+}
+
+/// multipart でアップロードされた画像を変換して返す。R2 にはアクセスしない。
+///
+/// 変換パラメータは `/transform` と同じクエリで指定する。R2 のオブジェクトやレスポンス形式に
+/// 関わるパラメータ (`version` `original` `probe` `download` `encode` `on_error` `palette`
+/// `force_reencode` `src`) は指定すると 400。
+pub async fn optimize(
+    State(state): State<AppState>,
+    Query(mut query): Query<TransformQuery>,
+    headers: HeaderMap,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, AppError> {
+    let multipart = multipart
+        .map_err(|_| AppError::UnsupportedMediaType("expected multipart/form-data".to_string()))?;
+    // アップロードを読む前に、黙って無視することになるパラメータを弾く
+    let unsupported = [
+        ("version", query.version.is_some()),
+        ("original", query.original.is_some()),
+        ("probe", query.probe.is_some()),
+        ("download", query.download.is_some()),
+        ("encode", query.encode.is_some()),
+        ("on_error", query.on_error.is_some()),
+        ("palette", query.palette.is_some()),
+        ("force_reencode", query.force_reencode.is_some()),
+        ("src", query.src.is_some()),
+    ];
+    if let Some((name, _)) = unsupported.into_iter().find(|(_, set)| *set) {
+        return Err(AppError::BadRequest(format!(
+            "{name} is not supported for uploads"
+        )));
+    }
+    let deadline = request_deadline(&state, &headers)?;
+    let input_bytes = multipart_file(multipart).await?;
+    if input_bytes.len() as u64 > MAX_INPUT_SIZE {
+        return Err(AppError::PayloadTooLarge(format!(
+            "upload too large: {} bytes (max: {MAX_INPUT_SIZE} bytes)",
+            input_bytes.len()
+        )));
+    }
+    if sniff_format(&input_bytes).is_none() {
+        return Err(AppError::UnsupportedMediaType(
+            "uploaded file is not a supported image".to_string(),
+        ));
+    }
+
+    apply_preset(&mut query)?;
+    if let Some(dpr) = resolve_dpr(&state, &query, &headers)? {
+        apply_dpr(&mut query, dpr);
+    }
+    let params = TransformParams {
+        avif_unsupported: avif_unsupported(&state.avif_unsupported_user_agents, &headers),
+        ..build_transform_params(&query, None)?
+    };
+
+    let permit = within_deadline(deadline, "admission", admit(&state)).await?;
+
+    tracing::info!(
+        size = input_bytes.len(),
+        w = ?params.width,
+        h = ?params.height,
+        f = ?params.format,
+        q = ?params.quality,
+        "optimizing uploaded image"
+    );
+
+    let reservation = reserve_memory(&state, &input_bytes, &params)?;
+    let limits = Limits {
+        deadline,
+        ..request_limits(&state, &headers)
+    };
+    let output = run_transform(
+        &state,
+        &input_bytes,
+        &params,
+        limits,
+        tracing::info_span!("transform"),
+        (permit, reservation),
+    )
+    .await?;
+    let (output, auto_choice) = smaller_of_original(&input_bytes, output, &params);
+
+    let vary = varies_by_user_agent(&state, params.format).then_some(header::USER_AGENT);
+    let mut response = image_response(&state.cache_policy, output, vary.as_slice());
+    // アップロードごとに内容が異なるため、キャッシュさせない
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    if let Some(choice) = auto_choice {
+        response
+            .headers_mut()
+            .insert(X_AUTO_COMPRESS, HeaderValue::from_static(choice));
+    }
+    Ok(response)
+}
+
+/// `multipart/form-data` のボディから `file` フィールドの内容を取り出す。
+///
+/// フィールド名が一致するパートのみを対象とし、`filename` は見ない。
+/// 先行するパートは読み飛ばし、`file` 以降のパートは読まない。
+async fn multipart_file(mut multipart: Multipart) -> Result<Bytes, AppError> {
+    let invalid = |e: MultipartError| {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge(format!("upload too large (max: {MAX_INPUT_SIZE} bytes)"))
+        } else {
+            AppError::BadRequest(format!("malformed multipart body: {}", e.body_text()))
+        }
+    };
+
+    while let Some(field) = multipart.next_field().await.map_err(invalid)? {
+        if field.name() == Some(OPTIMIZE_FILE_FIELD) {
+            return field.bytes().await.map_err(invalid);
+        }
+    }
+
+    Err(AppError::BadRequest(format!(
+        "multipart field '{OPTIMIZE_FILE_FIELD}' is required"
+    )))
+}
+
+/// `preset` で指定されたパラメータセットを、明示指定されていないパラメータに適用する。
+///
+/// - `web`: EXIF の向きを適用し、メタデータをすべて削除して JPEG (プログレッシブ, q=80) で出力する。
+///   `f` を明示した場合、`q` は JPEG/AVIF、`progressive` は JPEG のときのみ適用する
+fn apply_preset(query: &mut TransformQuery) -> Result<(), AppError> {
+    match query.preset.as_deref() {
+        None => {}
+        Some("web") => {
+            let format =
+                OutputFormat::from_str_param(query.format.get_or_insert_with(|| "jpg".to_string()));
+            query.orient.get_or_insert_with(|| "auto".to_string());
+            query.strip.get_or_insert_with(|| "all".to_string());
+            if matches!(format, Some(OutputFormat::Jpeg | OutputFormat::Avif)) {
+                query.quality.get_or_insert(80.0);
+            }
+            if format == Some(OutputFormat::Jpeg) {
+                query.progressive.get_or_insert(true);
+            }
+        }
+        Some(p) => {
+            return Err(AppError::BadRequest(format!(
+                "unsupported preset '{p}'. supported: web"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// クエリパラメータを検証し、`TransformParams` を組み立てる。
+fn build_transform_params(
+    query: &TransformQuery,
+    extension_hint: Option<OutputFormat>,
+) -> Result<TransformParams, AppError> {
+    let format = parse_format(query.format.as_deref())?;
+
+    let mask = parse_mask(query.mask.as_deref(), query.radius)?;
+
+    let auto_compress = match query.auto.as_deref() {
+        None => false,
+        Some("compress") => true,
+        Some(a) => {
+            return Err(AppError::BadRequest(format!(
+                "unsupported auto '{a}'. supported: compress"
+            )));
+        }
+    };
+
+    // 倍率の範囲は validate_params で検証する。拡大後のサイズは上限に収める
+    let retina_scale = |v: u32| match query.retina {
+        Some(retina) => v.saturating_mul(retina as u32).min(MAX_DIMENSION),
+        None => v,
+    };
+
+    let subsampling = query
+        .subsampling
+        .as_deref()
+        .map(|s| {
+            ChromaSubsampling::from_str_param(s).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported subsampling '{s}'. supported: 420, 422, 444"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let orient = query
+        .orient
+        .as_deref()
+        .map(|o| {
+            Orient::from_str_param(o).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported orient '{o}'. supported: auto, none, 0, 90, 180, 270"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let strip = query
+        .strip
+        .as_deref()
+        .map(|s| {
+            Strip::from_str_param(s).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported strip '{s}'. supported: all, none, metadata"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let fit = query
+        .fit
+        .as_deref()
+        .map(|f| {
+            Fit::from_str_param(f).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported fit '{f}'. supported: contain, fill, inside, outside"
+                ))
+            })
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let background = query.bg.as_deref().map(parse_background).transpose()?;
+    if background.is_some() && fit != Fit::Fill {
+        return Err(AppError::BadRequest(
+            "bg parameter is only supported for fit=fill".to_string(),
+        ));
+    }
+
+    let quality_mode = query
+        .quality_mode
+        .as_deref()
+        .map(|m| {
+            QualityMode::from_str_param(m).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported quality_mode '{m}'. supported: raw, perceptual"
+                ))
+            })
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let roi = query
+        .roi
+        .as_deref()
+        .map(|r| {
+            Roi::from_str_param(r).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "invalid roi '{r}'. expected x,y,w,h in 0-1 with x+w <= 1 and y+h <= 1"
+                ))
+            })
+        })
+        .transpose()?;
+    if roi.is_some() && fit != Fit::Fill {
+        return Err(AppError::BadRequest(
+            "roi parameter is only supported for fit=fill".to_string(),
+        ));
+    }
+
+    let blur_region = query
+        .blur_region
+        .as_deref()
+        .map(|r| {
+            BlurRegion::from_str_param(r).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "invalid blur_region '{r}'. expected x,y,w,h in 0-1 (x+w <= 1, y+h <= 1) or x,y,w,hpx in pixels"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let channels = query
+        .channels
+        .as_deref()
+        .map(|c| {
+            Channels::from_str_param(c).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported channels '{c}'. supported: rgb, rgba, gray, graya"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let png_compression = query
+        .png_compression
+        .as_deref()
+        .map(|c| {
+            PngCompression::from_str_param(c).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported png_compression '{c}'. supported: fast, default, best"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let png_filter = query
+        .png_filter
+        .as_deref()
+        .map(|f| {
+            PngFilter::from_str_param(f).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported png_filter '{f}'. supported: none, sub, up, avg, paeth, adaptive"
+                ))
+            })
+        })
+        .transpose()?;
+
+    Ok(TransformParams {
+        width: query.width.map(retina_scale),
+        height: query.height.map(retina_scale),
+        format,
+        quality: query.quality,
+        premultiply_alpha: query.premultiply_alpha,
+        linear_resize: query.linear_resize.unwrap_or(false),
+        mask,
+        subsampling,
+        progressive: query.progressive.unwrap_or(false),
+        orient,
+        extension_hint,
+        strip,
+        max_bytes: query.max_bytes,
+        quality_mode,
+        fit,
+        no_upscale: query.no_upscale.unwrap_or(false),
+        background,
+        roi,
+        blur_region,
+        near_lossless: query.near_lossless,
+        channels,
+        min_source_width: query.min_source_width,
+        encoder: EncoderConfig {
+            avif_speed: query.avif_speed,
+            png_compression,
+            png_filter,
+            webp_method: query.webp_method,
+        },
+        colors: query.colors,
+        force_reencode: query.force_reencode.unwrap_or(false),
+        normalize: query.normalize.unwrap_or(false),
+        retina: query.retina,
+        auto_compress,
+        allow_partial: query.allow_partial.unwrap_or(false),
+        // User-Agent による判定は呼び出し側で行う
+        avif_unsupported: false,
+    })
+}
+
+/// レスポンスに付ける `Cache-Control` の設定。各項目は 0 の場合は付けない。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CachePolicy {
+    /// 画像レスポンスに付ける `stale-while-revalidate` (秒)
+    pub stale_while_revalidate: u64,
+    /// 画像レスポンスに付ける `stale-if-error` (秒)
+    pub stale_if_error: u64,
+    /// `AppError::NotFound` のレスポンスに付ける `max-age` (秒)
+    pub not_found_max_age: u64,
+}
+
+impl CachePolicy {
+    /// 画像レスポンスの `Cache-Control`。設定に応じて stale 系のディレクティブを付ける。
+    fn immutable(&self) -> String {
+        let mut value = CACHE_CONTROL_IMMUTABLE.to_string();
+        if self.stale_while_revalidate > 0 {
+            value.push_str(&format!(
+                ", stale-while-revalidate={}",
+                self.stale_while_revalidate
+            ));
+        }
+        if self.stale_if_error > 0 {
+            value.push_str(&format!(", stale-if-error={}", self.stale_if_error));
+        }
+        value
+    }
+}
+
+/// ビーコン用の 1x1 透明画像を返す。R2 にはアクセスしない。
+pub async fn pixel(Query(query): Query<PixelQuery>) -> Result<Response, AppError> {
+    let (content_type, body) = match query.format.as_deref() {
+        None | Some("gif") => ("image/gif", PIXEL_GIF),
+        Some("png") => ("image/png", PIXEL_PNG),
+        Some(f) => {
+            return Err(AppError::BadRequest(format!(
+                "unsupported format '{f}'. supported: gif, png"
+            )));
+        }
+    };
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type),
+            // リクエストごとに到達させるため、ブラウザ・CDN にキャッシュさせない
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// オブジェクトの先頭数 KB のみを取得し、フォーマットとアニメーションの有無を返す。
+///
+/// 全体をダウンロード・デコードしないため、ルーティング判定などに安価に使える。
+pub async fn detect(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Response, AppError> {
+    validate_key(&state, &key)?;
+
+    let range = format!("bytes=0-{}", DETECT_PROBE_BYTES - 1);
+    tracing::info!(key = %key, range = %range, "fetching object head from R2");
+    let head = get_object(&state, &key, None, Some(&range)).await?.body;
+
+    let format = sniff_format(&head);
+    let encoding = format.map(|f| encoding_info(&head, f)).unwrap_or_default();
+    let body = serde_json::json!({
+        "format": format.map(|f| f.name()),
+        "content_type": format.map_or("application/octet-stream", |f| f.content_type()),
+        "animated": format.is_some_and(|f| is_animated(&head, f)),
+        "progressive": encoding.progressive,
+        "interlaced": encoding.interlaced,
+        "lossless": encoding.lossless,
+    });
+
+    Ok((StatusCode::OK, axum::Json(body)).into_response())
+}
+
+/// オブジェクトの EXIF / XMP を読み取り、カメラ・レンズ・GPS・日時等を JSON で返す。
+///
+/// 変換時には削除されるメタデータの確認用。まず先頭のみを取得し、メタデータが
+/// その範囲を超えている場合 (WebP の末尾の EXIF チャンク等) のみ全体を取得する。
+/// メタデータがない画像は空のオブジェクトを返す。
+pub async fn exif(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Response, AppError> {
+    validate_key(&state, &key)?;
+
+    let range = format!("bytes=0-{}", EXIF_PROBE_BYTES - 1);
+    tracing::info!(key = %key, range = %range, "fetching object head from R2");
+    let head = get_object(&state, &key, None, Some(&range)).await?.body;
+    ensure_image(&head)?;
+
+    let segments = crate::exif::find_metadata(&head);
+    let summary = if segments.complete || (head.len() as u64) < EXIF_PROBE_BYTES {
+        crate::exif::read_metadata(&segments)
+    } else {
+        let body = fetch_verified(&state, &key, None).await?;
+        crate::exif::read_metadata(&crate::exif::find_metadata(&body))
+    };
+
+    Ok((StatusCode::OK, axum::Json(summary)).into_response())
+}
+
+/// 画像をデコードし、チャンネルごとのヒストグラムと統計値を JSON で返す。
+pub async fn analyze(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    validate_key(&state, &key)?;
+
+    let deadline = request_deadline(&state, &headers)?;
+    let permit = within_deadline(deadline, "admission", admit(&state)).await?;
+    let input_bytes =
+        within_deadline(deadline, "fetch", fetch_verified(&state, &key, None)).await?;
+    ensure_image(&input_bytes)?;
+
+    tracing::info!(key = %key, "analyzing image");
+
+    let reservation = reserve_decode_memory(&state, &[&input_bytes])?;
+    let limits = Limits {
+        deadline,
+        ..request_limits(&state, &headers)
+    };
+    let analysis = run_blocking(
+        deadline,
+        "analyze",
+        tracing::info_span!("analyze", key = %key),
+        (permit, reservation),
+        move || Ok(crate::analyze::analyze_image(&input_bytes, &limits)?),
+    )
+    .await?;
+
+    Ok((StatusCode::OK, axum::Json(analysis)).into_response())
+}
+
+/// 画像の BlurHash (プレースホルダ用の短い文字列) を text/plain で返す。
+pub async fn blurhash(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<BlurhashQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    validate_key(&state, &key)?;
+
+    let deadline = request_deadline(&state, &headers)?;
+    let permit = within_deadline(deadline, "admission", admit(&state)).await?;
+    let input_bytes =
+        within_deadline(deadline, "fetch", fetch_verified(&state, &key, None)).await?;
+    ensure_image(&input_bytes)?;
+
+    tracing::info!(key = %key, "computing blurhash");
+
+    let reservation = reserve_decode_memory(&state, &[&input_bytes])?;
+    let limits = Limits {
+        deadline,
+        ..request_limits(&state, &headers)
+    };
+    let (x, y) = (
+        query.x.unwrap_or(DEFAULT_COMPONENTS_X),
+        query.y.unwrap_or(DEFAULT_COMPONENTS_Y),
+    );
+    let hash = run_blocking(
+        deadline,
+        "blurhash",
+        tracing::info_span!("blurhash", key = %key),
+        (permit, reservation),
+        move || Ok(crate::blurhash::blurhash(&input_bytes, x, y, &limits)?),
+    )
+    .await?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/plain; charset=utf-8".to_string(),
+            ),
+            (header::CACHE_CONTROL, state.cache_policy.immutable()),
+        ],
+        hash,
+    )
+        .into_response())
+}
+
+/// Deep Zoom ビューア (OpenSeadragon 等) 向けに 1 枚のタイルを返す。
+pub async fn tile(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<TileQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    validate_key(&state, &key)?;
+
+    let params = TileParams {
+        level: query.z,
+        x: query.x,
+        y: query.y,
+        size: query.size.unwrap_or(DEFAULT_TILE_SIZE),
+        format: parse_format(query.format.as_deref())?,
+        quality: query.quality,
+        avif_unsupported: avif_unsupported(&state.avif_unsupported_user_agents, &headers),
+    };
+    let vary = varies_by_user_agent(&state, params.format).then_some(header::USER_AGENT);
+
+    let deadline = request_deadline(&state, &headers)?;
+    let permit = within_deadline(deadline, "admission", admit(&state)).await?;
+    let input_bytes =
+        within_deadline(deadline, "fetch", fetch_verified(&state, &key, None)).await?;
+    ensure_image(&input_bytes)?;
+
+    tracing::info!(
+        key = %key,
+        z = params.level,
+        x = params.x,
+        y = params.y,
+        size = params.size,
+        "rendering tile"
+    );
+
+    let reservation = reserve_decode_memory(&state, &[&input_bytes])?;
+    let limits = Limits {
+        deadline,
+        ..request_limits(&state, &headers)
+    };
+    let config = state.transform_config.clone();
+    let abandoned_avif = state.abandoned_avif.clone();
+    let output = run_blocking(
+        deadline,
+        "tile",
+        tracing::info_span!("tile", key = %key),
+        (permit, reservation),
+        move || {
+            Ok(crate::tile::render_tile(
+                &input_bytes,
+                &params,
+                &limits,
+                &config,
+                &abandoned_avif,
+            )?)
+        },
+    )
+    .await?;
+
+    Ok(image_response(&state.cache_policy, output, vary.as_slice()))
+}
+
+/// 複数サイズを横一列に並べた PNG スプライトシートを返す。
+///
+/// 各画像の配置は `X-Sprite-Layout` ヘッダに JSON 配列で返す。
+pub async fn sprite(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<SpriteQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    validate_key(&state, &key)?;
+
+    let sizes = query
+        .sizes
+        .split(',')
+        .map(|s| {
+            s.trim()
+                .parse::<u32>()
+                .map_err(|_| AppError::BadRequest(format!("invalid size '{s}' in sizes")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let deadline = request_deadline(&state, &headers)?;
+    let permit = within_deadline(deadline, "admission", admit(&state)).await?;
+    let input_bytes =
+        within_deadline(deadline, "fetch", fetch_verified(&state, &key, None)).await?;
+    ensure_image(&input_bytes)?;
+
+    tracing::info!(key = %key, sizes = ?sizes, "rendering sprite sheet");
+
+    let reservation = reserve_decode_memory(&state, &[&input_bytes])?;
+    let limits = Limits {
+        deadline,
+        ..request_limits(&state, &headers)
+    };
+    let config = state.transform_config.clone();
+    let output = run_blocking(
+        deadline,
+        "sprite",
+        tracing::info_span!("sprite", key = %key),
+        (permit, reservation),
+        move || {
+            Ok(crate::sprite::render_sprite(
+                &input_bytes,
+                &sizes,
+                &limits,
+                &config,
+            )?)
+        },
+    )
+    .await?;
+
+    let layout = serde_json::to_string(&output.frames)
+        .map_err(|e| AppError::Internal(format!("failed to serialize sprite layout: {e}")))?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                OutputFormat::Png.content_type().to_string(),
+            ),
+            (header::CACHE_CONTROL, state.cache_policy.immutable()),
+            (X_IMAGE_WIDTH, output.width.to_string()),
+            (X_IMAGE_HEIGHT, output.height.to_string()),
+            (X_SPRITE_LAYOUT, layout),
+        ],
+        output.bytes,
+    )
+        .into_response())
+}
+
+/// 複数の画像をグリッド状に並べた 1 枚の画像を返す。
+pub async fn montage(
+    State(state): State<AppState>,
+    Query(query): Query<MontageQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let keys: Vec<&str> = query
+        .keys
+        .split(',')
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .collect();
+    for key in &keys {
+        validate_key(&state, key)?;
+    }
+
+    let params = MontageParams {
+        cols: query.cols,
+        rows: query
+            .rows
+            .unwrap_or_else(|| (keys.len() as u32).div_ceil(query.cols.max(1))),
+        cell: query.cell,
+        format: parse_format(query.format.as_deref())?,
+        quality: query.quality,
+        avif_unsupported: avif_unsupported(&state.avif_unsupported_user_agents, &headers),
+    };
+    // 出力は省略時 PNG のため、AVIF を明示した場合のみ User-Agent に依存する
+    let vary = (params.format.is_some() && varies_by_user_agent(&state, params.format))
+        .then_some(header::USER_AGENT);
+    // 取得前にレイアウトを検証し、不正なリクエストで R2 にアクセスしない
+    params.validate(keys.len())?;
+
+    let deadline = request_deadline(&state, &headers)?;
+    let permit = within_deadline(deadline, "admission", admit(&state)).await?;
+    let fetch =
+        futures::future::try_join_all(keys.iter().map(|key| fetch_verified(&state, key, None)));
+    let inputs = within_deadline(deadline, "fetch", fetch).await?;
+    for input in &inputs {
+        ensure_image(input)?;
+    }
+
+    tracing::info!(
+        keys = keys.len(),
+        cols = params.cols,
+        rows = params.rows,
+        cell = params.cell,
+        "rendering montage"
+    );
+
+    let reservation = reserve_decode_memory(&state, &inputs.iter().collect::<Vec<_>>())?;
+    let limits = Limits {
+        deadline,
+        ..request_limits(&state, &headers)
+    };
+    let config = state.transform_config.clone();
+    let abandoned_avif = state.abandoned_avif.clone();
+    let output = run_blocking(
+        deadline,
+        "montage",
+        tracing::info_span!("montage", keys = keys.len()),
+        (permit, reservation),
+        move || {
+            Ok(crate::montage::render_montage(
+                &inputs,
+                &params,
+                &limits,
+                &config,
+                &abandoned_avif,
+            )?)
+        },
+    )
+    .await?;
+
+    Ok(image_response(&state.cache_policy, output, vary.as_slice()))
+}
+
+/// 2 枚の画像の差分画像 (PNG) を返す。ビジュアルリグレッションテスト向け。
+///
+/// 平均絶対誤差 (0-1) を `X-Diff-Mae`、異なる画素数を `X-Diff-Changed-Pixels` ヘッダで返す。
+pub async fn diff(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<DiffQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    validate_key(&state, &key)?;
+    validate_key(&state, &query.compare)?;
+
+    let deadline = request_deadline(&state, &headers)?;
+    let permit = within_deadline(deadline, "admission", admit(&state)).await?;
+    let fetch = futures::future::try_join(
+        fetch_verified(&state, &key, None),
+        fetch_verified(&state, &query.compare, None),
+    );
+    let (base, compare) = within_deadline(deadline, "fetch", fetch).await?;
+    ensure_image(&base)?;
+    ensure_image(&compare)?;
+
+    tracing::info!(key = %key, compare = %query.compare, "diffing images");
+
+    let reservation = reserve_decode_memory(&state, &[&base, &compare])?;
+    let limits = Limits {
+        deadline,
+        ..request_limits(&state, &headers)
+    };
+    let size = query.size.unwrap_or(DEFAULT_DIFF_SIZE);
+    let config = state.transform_config.clone();
+    let output = run_blocking(
+        deadline,
+        "diff",
+        tracing::info_span!("diff", key = %key, compare = %query.compare),
+        (permit, reservation),
+        move || {
+            Ok(crate::diff::diff_images(
+                &base, &compare, size, &limits, &config,
+            )?)
+        },
+    )
+    .await?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                OutputFormat::Png.content_type().to_string(),
+            ),
+            (header::CACHE_CONTROL, state.cache_policy.immutable()),
+            (X_IMAGE_WIDTH, output.width.to_string()),
+            (X_IMAGE_HEIGHT, output.height.to_string()),
+            (X_DIFF_MAE, format!("{:.6}", output.mean_absolute_error)),
+            (X_DIFF_CHANGED_PIXELS, output.changed_pixels.to_string()),
+        ],
+        output.bytes,
+    )
+        .into_response())
+}
+
+/// 変換結果からレスポンスを組み立てる。
+///
+/// `vary` には出力内容が依存するリクエストヘッダ (Accept, DPR 等) を渡す。
+/// CDN が別バリアントを同じキャッシュキーで返さないよう、空でなければ Vary を付与する。
+fn image_response(cache: &CachePolicy, output: TransformOutput, vary: &[HeaderName]) -> Response {
+    let mut response = (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, output.content_type.to_string()),
+            (header::CACHE_CONTROL, cache.immutable()),
+            (X_IMAGE_WIDTH, output.width.to_string()),
+            (X_IMAGE_HEIGHT, output.height.to_string()),
+        ],
+        output.bytes,
+    )
+        .into_response();
+
+    // AVIF のタイムアウト時など、要求と異なるフォーマットで返した場合に判別できるようにする
+    if let Some(format) = OutputFormat::ALL
+        .into_iter()
+        .find(|f| f.content_type() == output.content_type)
+    {
+        response
+            .headers_mut()
+            .insert(X_IMAGE_FORMAT, HeaderValue::from_static(format.name()));
+    }
+
+    if let Some(name) = output
+        .source_format
+        .and_then(|f| f.extensions_str().first())
+    {
+        response
+            .headers_mut()
+            .insert(X_SOURCE_FORMAT, HeaderValue::from_static(name));
+    }
+
+    if let Some(quality) = output.quality
+        && let Ok(value) = HeaderValue::from_str(&quality.to_string())
+    {
+        response.headers_mut().insert(X_IMAGE_QUALITY, value);
+    }
+
+    insert_vary(&mut response, vary);
+    response
+}
+
+/// `vary` が空でなければ `Vary` ヘッダに並べる。
+fn insert_vary(response: &mut Response, vary: &[HeaderName]) {
+    if vary.is_empty() {
+        return;
+    }
+    let value = vary
+        .iter()
+        .map(HeaderName::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        response.headers_mut().insert(header::VARY, value);
+    }
+}
+
+/// キーの拡張子から出力フォーマットのヒントを得る。対応していない拡張子は None。
+fn extension_hint(key: &str) -> Option<OutputFormat> {
+    let name = key.rsplit('/').next()?;
+    let (_, ext) = name.rsplit_once('.')?;
+    OutputFormat::from_str_param(ext)
+}
+
+/// 画像を `data:{content_type};base64,...` 形式のテキストで返す。
+///
+/// CSS/HTML への埋め込み用途のため、画像用のキャッシュヘッダや `X-Image-*` は付けない。
+fn data_url_response(content_type: &str, bytes: &[u8]) -> Response {
+    let body = format!("data:{content_type};base64,{}", BASE64.encode(bytes));
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
 
-    let format = query
-        .format
-        .as_deref()
+/// `f` パラメータを解釈する。
+fn parse_format(format: Option<&str>) -> Result<Option<OutputFormat>, AppError> {
+    format
         .map(|f| {
             OutputFormat::from_str_param(f).ok_or_else(|| {
                 AppError::BadRequest(format!(
@@ -42,76 +2077,275 @@ pub async fn transform(
                 ))
             })
         })
-        .transpose()?;
+        .transpose()
+}
 
-    let params = TransformParams {
-        width: query.width,
-        height: query.height,
-        format,
-        quality: query.quality,
+/// リクエストに適用する上限値を返す。信頼済みリクエストのみ緩い上限を使う。
+fn request_limits(state: &AppState, headers: &HeaderMap) -> Limits {
+    if is_trusted(state, headers) {
+        state.trusted_limits
+    } else {
+        state.limits
+    }
+}
+
+/// 内部認証ヘッダが設定済みのトークンと一致するかを判定する。
+///
+/// トークン未設定の場合は常に false (信頼済みリクエストなし)。
+fn is_trusted(state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(expected) = state.internal_token.as_deref() else {
+        return false;
     };
+    headers
+        .get(X_INTERNAL_TOKEN)
+        .is_some_and(|v| constant_time_eq(v.as_bytes(), expected.as_bytes()))
+}
 
-    tracing::info!(key = %key, "fetching object from R2");
-    let input_bytes = state.r2_client.get_object(&key).await?;
+/// タイミング攻撃を避けるため、一致箇所に関わらず全バイトを比較する。
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
 
-    if !params.needs_transform() {
-        let content_type = infer_content_type(&input_bytes);
-        return Ok((
-            StatusCode::OK,
-            [
-                (header::CONTENT_TYPE, content_type),
-                (header::CACHE_CONTROL, CACHE_CONTROL_IMMUTABLE.to_string()),
-            ],
-            input_bytes,
+/// `bg` パラメータ (`RRGGBB` または `RRGGBBAA` の 16 進) を RGBA に変換する。
+fn parse_background(bg: &str) -> Result<[u8; 4], AppError> {
+    let invalid = || {
+        AppError::BadRequest(format!(
+            "invalid bg '{bg}'. expected RRGGBB or RRGGBBAA hex"
+        ))
+    };
+    let bytes = hex::decode(bg).map_err(|_| invalid())?;
+    match bytes[..] {
+        [r, g, b] => Ok([r, g, b, 255]),
+        [r, g, b, a] => Ok([r, g, b, a]),
+        _ => Err(invalid()),
+    }
+}
+
+/// `mask` / `radius` パラメータを解釈する。
+///
+/// `radius` は `mask=rounded` の場合のみ指定でき、その場合は必須。
+fn parse_mask(mask: Option<&str>, radius: Option<u32>) -> Result<Option<Mask>, AppError> {
+    match (mask, radius) {
+        (None, None) => Ok(None),
+        (Some("circle"), None) => Ok(Some(Mask::Circle)),
+        (Some("rounded"), Some(radius)) => Ok(Some(Mask::Rounded { radius })),
+        (Some("rounded"), None) => Err(AppError::BadRequest(
+            "radius parameter is required for mask=rounded".to_string(),
+        )),
+        (Some("circle") | None, Some(_)) => Err(AppError::BadRequest(
+            "radius parameter is only supported for mask=rounded".to_string(),
+        )),
+        (Some(m), _) => Err(AppError::BadRequest(format!(
+            "unsupported mask '{m}'. supported: circle, rounded"
+        ))),
+    }
+}
+
+/// サーキットブレーカーを通して R2 からオブジェクトを取得する。
+///
+/// ブレーカーが Open の間は R2 に問い合わせず 503 を返す。
+async fn get_object(
+    state: &AppState,
+    key: &str,
+    version: Option<&str>,
+    range: Option<&str>,
+) -> Result<StoredObject, AppError> {
+    if !state.r2_breaker.allow() {
+        return Err(AppError::ServiceUnavailable(
+            "storage temporarily unavailable".to_string(),
+        ));
+    }
+
+    let result = state
+        .r2_client
+        .get_object(key, version, range)
+        .instrument(tracing::info_span!("r2_get_object", key = %key, range = ?range))
+        .await;
+    match &result {
+        Err(StorageError::Internal(_) | StorageError::Timeout { .. }) => {
+            state.r2_breaker.record_failure()
+        }
+        _ => state.r2_breaker.record_success(),
+    }
+    Ok(result?)
+}
+
+/// R2 からオブジェクト全体を取得し、コンテンツアドレスのキーであれば内容を検証する。
+async fn fetch_verified(
+    state: &AppState,
+    key: &str,
+    version: Option<&str>,
+) -> Result<Bytes, AppError> {
+    tracing::info!(key = %key, version = ?version, "fetching object from R2");
+    let body = get_object(state, key, version, None).await?.body;
+
+    if let Some(expected) = expected_sha256(key)? {
+        let actual: [u8; 32] = Sha256::digest(&body).into();
+        if actual != expected {
+            tracing::warn!(
+                key = %key,
+                actual = %hex::encode(actual),
+                "object integrity check failed"
+            );
+            return Err(AppError::Conflict(
+                "object content does not match the SHA-256 in the key".to_string(),
+            ));
+        }
+    }
+
+    Ok(body)
+}
+
+/// `sha256/{hash}/{name}` 形式のキーから期待する SHA-256 を取り出す。
+///
+/// 該当しないキーは None。`sha256/` で始まるのにハッシュが不正な場合はエラー。
+fn expected_sha256(key: &str) -> Result<Option<[u8; 32]>, AppError> {
+    let Some(rest) = key.strip_prefix("sha256/") else {
+        return Ok(None);
+    };
+    let invalid = || {
+        AppError::BadRequest(
+            "invalid content-addressed key: expected sha256/{64 hex chars}/{name}".to_string(),
         )
-            .into_response());
+    };
+
+    let (hash, name) = rest.split_once('/').ok_or_else(invalid)?;
+    if name.is_empty() {
+        return Err(invalid());
     }
+    let mut expected = [0u8; 32];
+    hex::decode_to_slice(hash, &mut expected).map_err(|_| invalid())?;
 
-    tracing::info!(
-        key = %key,
-        w = ?params.width,
-        h = ?params.height,
-        f = ?params.format,
-        q = ?params.quality,
-        "transforming image"
-    );
+    Ok(Some(expected))
+}
 
-    let (output_bytes, content_type) = crate::transform::transform(&input_bytes, &params)?;
+/// 変換なしで原本を返す。
+///
+/// Range ヘッダがある場合は R2 に範囲指定で問い合わせ、206 Partial Content を返す。
+async fn passthrough(
+    state: &AppState,
+    key: &str,
+    version: Option<&str>,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    // コンテンツアドレスのキーは全体を検証する必要があるため Range を無視する
+    if expected_sha256(key)?.is_some() {
+        let body = fetch_verified(state, key, version).await?;
+        return Ok(original_response(state, body));
+    }
 
-    Ok((
+    // bytes 以外の単位など解釈できない Range は無視して全体を返す (RFC 9110 14.2)
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|r| r.starts_with("bytes="));
+
+    tracing::info!(key = %key, version = ?version, range = ?range, "fetching object from R2");
+    let object = get_object(state, key, version, range).await?;
+
+    let content_type = passthrough_content_type(&object);
+    let mut response = (
         StatusCode::OK,
         [
-            (header::CONTENT_TYPE, content_type.to_string()),
-            (header::CACHE_CONTROL, CACHE_CONTROL_IMMUTABLE.to_string()),
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, state.cache_policy.immutable()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
         ],
-        output_bytes,
+        object.body,
     )
-        .into_response())
+        .into_response();
+
+    if let Some(content_range) = object.content_range {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        let value = HeaderValue::from_str(&content_range)
+            .map_err(|e| AppError::Internal(format!("invalid Content-Range from R2: {e}")))?;
+        response.headers_mut().insert(header::CONTENT_RANGE, value);
+    }
+
+    Ok(response)
+}
+
+/// 原本の Content-Type を決める。
+///
+/// 範囲取得では先頭のマジックバイトが含まれないことがあるため、R2 の Content-Type を使う。
+/// アップロード時に任意の値 (`text/html` 等) を設定できるため、`image/*` 以外は使わない。
+fn passthrough_content_type(object: &StoredObject) -> String {
+    match (&object.content_range, &object.content_type) {
+        (Some(_), Some(stored)) if stored.starts_with("image/") => stored.clone(),
+        (Some(_), _) => "application/octet-stream".to_string(),
+        (None, _) => infer_content_type(&object.body),
+    }
+}
+
+/// 取得済みの原本をそのまま返す。
+fn original_response(state: &AppState, body: Bytes) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, infer_content_type(&body)),
+            (header::CACHE_CONTROL, state.cache_policy.immutable()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// `w400-h300-q80-fwebp` 形式のパスセグメントを `TransformQuery` に変換する。
+///
+/// 各トークンは先頭1文字がパラメータ名 (w, h, q, f)、残りが値。それ以外のパラメータは受け付けない。
+/// 値の範囲チェックはクエリ経由と同じく `transform` 側の検証に任せる。
+fn parse_path_params(segment: &str) -> Result<TransformQuery, AppError> {
+    let mut query = TransformQuery::default();
+
+    for token in segment.split('-').filter(|t| !t.is_empty()) {
+        let (name, value) = token.split_at(token.chars().next().map_or(0, char::len_utf8));
+        let invalid = || AppError::BadRequest(format!("invalid path parameter '{token}'"));
+
+        match name {
+            "w" => query.width = Some(value.parse().map_err(|_| invalid())?),
+            "h" => query.height = Some(value.parse().map_err(|_| invalid())?),
+            "q" => query.quality = Some(value.parse().map_err(|_| invalid())?),
+            "f" if !value.is_empty() => query.format = Some(value.to_string()),
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(query)
 }
 
 /// パストラバーサル攻撃を防ぐためにオブジェクトキーを検証する。
 ///
-/// URLエンコーディングのバイパスを防ぐため、デコード後の値をチェックする。
-/// ホワイトリスト方式で許可する文字のみを受け入れる。
-fn validate_key(key: &str) -> Result<(), AppError> {
+/// `key` はルーティングでパーセントデコード済みの値で、R2 にはこの値をそのまま渡す
+/// (S3 クライアントが UTF-8 のバイト列としてエンコードし直す)。
+/// 検証する値と R2 に渡す値を一致させるため、ここではデコードしない。
+/// `%` は許可しないため、二重にエンコードされたキー (`%252e` 等) も拒否される。
+/// ホワイトリスト方式で許可する文字のみを受け入れる。Unicode の英数字 (日本語のファイル名等) は許可し、
+/// 正規化 (NFC/NFD) は行わずバイト列で一致させるため、結合文字を含む NFD のキーは拒否される。
+/// 空のセグメント (先頭・末尾の `/`、`//`) と、最後のセグメントが `.` で始まるキー (隠しファイル) は拒否する。
+/// `allowed_key_prefixes` が設定されている場合は、いずれかのプレフィックスで始まるキーのみ許可する。
+fn validate_key(state: &AppState, key: &str) -> Result<(), AppError> {
+    validate_key_with_prefixes(key, &state.allowed_key_prefixes)
+}
+
+/// `validate_key` の本体。`allowed_prefixes` が空の場合はプレフィックスを制限しない。
+fn validate_key_with_prefixes(key: &str, allowed_prefixes: &[String]) -> Result<(), AppError> {
     if key.is_empty() {
         return Err(AppError::BadRequest(
             "key parameter is required".to_string(),
         ));
     }
+    // R2 (S3) のキーの上限は UTF-8 で 1024 バイト
     if key.len() > 1024 {
         return Err(AppError::BadRequest(
             "key parameter too long (max: 1024)".to_string(),
         ));
     }
 
-    // URLデコード後の値をチェック
-    let decoded = urlencoding::decode(key)
-        .map_err(|_| AppError::BadRequest("invalid URL encoding".to_string()))?;
-
     // ホワイトリストアプローチ: 許可する文字のみを許可
-    // 英数字、スラッシュ、ハイフン、アンダースコア、ドットのみ
-    if !decoded
+    // 英数字 (Unicode を含む)、スラッシュ、ハイフン、アンダースコア、ドットのみ
+    if !key
         .chars()
         .all(|c| c.is_alphanumeric() || c == '/' || c == '-' || c == '_' || c == '.')
     {
@@ -121,42 +2355,241 @@ fn validate_key(key: &str) -> Result<(), AppError> {
     }
 
     // パストラバーサルパターンの検出
-    if decoded.contains("..")
-        || decoded.starts_with('/')
-        || decoded.contains("//")
-        || decoded.contains('\\')
-    {
+    if key.contains("..") || key.starts_with('/') || key.contains("//") || key.contains('\\') {
         return Err(AppError::BadRequest(
             "invalid key: path traversal detected".to_string(),
         ));
     }
+    // ディレクトリを指すキーはオブジェクトとして扱わない
+    if key.split('/').any(str::is_empty) {
+        return Err(AppError::BadRequest(
+            "invalid key: empty path segment".to_string(),
+        ));
+    }
+    // `.env` 等の設定ファイルを画像として取得させない
+    if key
+        .rsplit('/')
+        .next()
+        .is_some_and(|name| name.starts_with('.'))
+    {
+        return Err(AppError::BadRequest("invalid key: hidden file".to_string()));
+    }
+
+    if !allowed_prefixes.is_empty()
+        && !allowed_prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    {
+        tracing::warn!(key = %key, "key outside allowed prefixes");
+        return Err(AppError::Forbidden("key is not allowed".to_string()));
+    }
+
+    Ok(())
+}
+
+/// 変換処理の実行枠を取得する。待ち行列が埋まっている場合は 503 を返す。
+async fn admit(state: &AppState) -> Result<OwnedSemaphorePermit, AppError> {
+    state
+        .admission
+        .acquire()
+        .await
+        .ok_or_else(|| AppError::ServiceUnavailable("server is busy".to_string()))
+}
+
+/// リクエスト全体の期限を求める。
+///
+/// `X-Deadline-Ms` は `REQUEST_DEADLINE_MS` より短い場合のみ採用する (クライアントが延ばすことはできない)。
+/// どちらもない場合は None (期限なし)。
+fn request_deadline(state: &AppState, headers: &HeaderMap) -> Result<Option<Instant>, AppError> {
+    let requested = match headers.get(X_DEADLINE_MS) {
+        None => None,
+        Some(value) => {
+            let ms = value
+                .to_str()
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .filter(|&ms| ms > 0)
+                .ok_or_else(|| {
+                    AppError::BadRequest("X-Deadline-Ms must be a positive integer".to_string())
+                })?;
+            Some(Duration::from_millis(ms))
+        }
+    };
+    let budget = match (state.request_deadline, requested) {
+        (Some(default), Some(requested)) => Some(default.min(requested)),
+        (default, requested) => default.or(requested),
+    };
+    Ok(budget.map(|budget| Instant::now() + budget))
+}
+
+/// `deadline` までに `future` が終わらなければ 504 を返す。None の場合は待つだけ。
+async fn within_deadline<T>(
+    deadline: Option<Instant>,
+    stage: &'static str,
+    future: impl Future<Output = Result<T, AppError>>,
+) -> Result<T, AppError> {
+    let Some(deadline) = deadline else {
+        return future.await;
+    };
+    tokio::time::timeout_at(deadline.into(), future)
+        .await
+        .unwrap_or_else(|_| {
+            tracing::warn!(stage, "request deadline exceeded");
+            Err(AppError::GatewayTimeout(
+                "request deadline exceeded".to_string(),
+            ))
+        })
+}
+
+/// 変換を blocking スレッドプールで実行する。
+///
+/// `limits.deadline` を過ぎても終わらない場合は待つのをやめて 504 を返す。変換自体は中断できないが、
+/// 各段階の前とエンコードの試行ごとに期限を確認するため、次の段階に進まずに終わる。
+/// permit とメモリの予約は変換が終わるまでスレッド側で保持する。呼び出し側が結果を
+/// 待たなくなっても、実行中の変換は同時実行数とメモリ予算に数えられたままになる。
+async fn run_transform(
+    state: &AppState,
+    input: &Bytes,
+    params: &TransformParams,
+    limits: Limits,
+    span: tracing::Span,
+    guards: (OwnedSemaphorePermit, Option<MemoryReservation>),
+) -> Result<TransformOutput, AppError> {
+    let (input, params) = (input.clone(), params.clone());
+    let config = state.transform_config.clone();
+    let abandoned_avif = state.abandoned_avif.clone();
+    run_blocking(limits.deadline, "transform", span, guards, move || {
+        Ok(crate::transform::transform(
+            &input,
+            &params,
+            &limits,
+            &config,
+            &abandoned_avif,
+        )?)
+    })
+    .await
+}
+
+/// デコード・リサイズ・エンコードを伴う同期処理を blocking スレッドプールで実行する。
+///
+/// `deadline` を過ぎても終わらない場合は待つのをやめて 504 を返す。
+/// permit とメモリの予約は `work` が終わるまでスレッド側で保持する。
+async fn run_blocking<T: Send + 'static>(
+    deadline: Option<Instant>,
+    stage: &'static str,
+    span: tracing::Span,
+    guards: (OwnedSemaphorePermit, Option<MemoryReservation>),
+    work: impl FnOnce() -> Result<T, AppError> + Send + 'static,
+) -> Result<T, AppError> {
+    let task = tokio::task::spawn_blocking(move || {
+        let _guards = guards;
+        span.in_scope(work)
+    });
+    within_deadline(deadline, stage, async {
+        task.await
+            .map_err(|e| AppError::Internal(format!("{stage} task failed: {e}")))?
+    })
+    .await
+}
+
+/// 変換のメモリ見積もりを予算から予約する。
+///
+/// 実行中の変換と合わせて予算を超える場合は 503、見積もりが単独で予算を超える場合は
+/// 再試行しても通らないため 422 を返す。
+/// ヘッダからサイズを読み取れない場合は予約しない (デコード時のエラーに任せる)。
+fn reserve_memory(
+    state: &AppState,
+    input: &Bytes,
+    params: &TransformParams,
+) -> Result<Option<MemoryReservation>, AppError> {
+    let Some(estimate) = crate::transform::estimate_memory(input, params) else {
+        return Ok(None);
+    };
+    state
+        .memory_budget
+        .try_reserve(estimate)
+        .map(Some)
+        .map_err(AppError::from)
+}
+
+/// 変換パラメータを持たない処理 (解析・タイル・差分等) のメモリ見積もりを予約する。
+///
+/// 各入力を元のサイズでデコードするものとして見積もる。どの入力もヘッダからサイズを
+/// 読み取れない場合は予約しない。
+fn reserve_decode_memory(
+    state: &AppState,
+    inputs: &[&Bytes],
+) -> Result<Option<MemoryReservation>, AppError> {
+    let params = TransformParams::default();
+    let estimates: Vec<u64> = inputs
+        .iter()
+        .filter_map(|input| crate::transform::estimate_memory(input, &params))
+        .collect();
+    if estimates.is_empty() {
+        return Ok(None);
+    }
+    state
+        .memory_budget
+        .try_reserve(estimates.iter().sum())
+        .map(Some)
+        .map_err(AppError::from)
+}
 
+/// 明らかに画像ではない (HTML のエラーページや JSON 等) オブジェクトをデコード前に弾く。
+fn ensure_image(data: &[u8]) -> Result<(), AppError> {
+    if is_probably_text(data) {
+        return Err(AppError::UnsupportedMediaType(
+            "stored object is not an image".to_string(),
+        ));
+    }
     Ok(())
 }
 
 /// マジックバイトから Content-Type を推測する。
 fn infer_content_type(data: &[u8]) -> String {
-    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
-        "image/jpeg".to_string()
-    } else if data.starts_with(&[0x89, 0x50, 0x4E, 0x47]) {
-        "image/png".to_string()
-    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
-        "image/webp".to_string()
-    } else if data.len() >= 12 && &data[4..12] == b"ftypavif" {
-        "image/avif".to_string()
-    } else {
-        "application/octet-stream".to_string()
-    }
+    sniff_format(data)
+        .map_or("application/octet-stream", |f| f.content_type())
+        .to_string()
 }
 
+/// `AppError::NotFound` から組み立てたレスポンスに付ける extension。
+#[derive(Debug, Clone, Copy)]
+pub struct NotFoundResponse;
+
 #[derive(Debug)]
 pub enum AppError {
     BadRequest(String),
+    /// クライアントが判別できるようエラーコード付きで返す 400
+    BadRequestWithCode {
+        code: &'static str,
+        message: String,
+    },
+    Unauthorized(String),
+    Forbidden(String),
     NotFound(String),
+    RangeNotSatisfiable(String),
+    Conflict(String),
+    /// アップロードされたボディが上限を超えた
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
     TransformFailed(String),
+    ServiceUnavailable(String),
+    /// リクエスト全体の期限 (`REQUEST_DEADLINE_MS` / `X-Deadline-Ms`) 切れ
+    GatewayTimeout(String),
     Internal(String),
 }
 
+impl From<ReserveError> for AppError {
+    fn from(err: ReserveError) -> Self {
+        match err {
+            ReserveError::Busy => AppError::ServiceUnavailable("server is busy".to_string()),
+            ReserveError::ExceedsBudget { bytes, limit } => AppError::TransformFailed(format!(
+                "image needs an estimated {bytes} bytes of memory, more than the {limit} byte budget"
+            )),
+        }
+    }
+}
+
 impl From<StorageError> for AppError {
     fn from(err: StorageError) -> Self {
         match err {
@@ -170,6 +2603,13 @@ impl From<StorageError> for AppError {
                 // サイズ情報は許容（DoS対策として有用）
                 AppError::BadRequest(format!("object too large: {size} bytes (max: {max} bytes)"))
             }
+            StorageError::InvalidRange { range } => {
+                AppError::RangeNotSatisfiable(format!("range not satisfiable: {range}"))
+            }
+            StorageError::Timeout { key } => {
+                tracing::error!(key = %key, "timed out reading object body from storage");
+                AppError::ServiceUnavailable("storage timeout".to_string())
+            }
             StorageError::Internal(msg) => {
                 // 詳細なエラーメッセージはログに記録し、クライアントには一般的なメッセージを返す
                 tracing::error!(error = %msg, "storage error");
@@ -179,13 +2619,77 @@ impl From<StorageError> for AppError {
     }
 }
 
+impl From<RemoteError> for AppError {
+    fn from(err: RemoteError) -> Self {
+        match err {
+            err @ RemoteError::InvalidUrl(_) => AppError::BadRequest(err.to_string()),
+            err @ RemoteError::HostNotAllowed { .. } => AppError::Forbidden(err.to_string()),
+            RemoteError::Status { status: 404 } => {
+                AppError::NotFound("source not found".to_string())
+            }
+            // 署名の期限切れ・不正
+            RemoteError::Status { status: 401 | 403 } => {
+                AppError::Forbidden("source access denied".to_string())
+            }
+            RemoteError::TooLarge { size, max } => {
+                AppError::BadRequest(format!("source too large: {size} bytes (max: {max} bytes)"))
+            }
+            err @ (RemoteError::Status { .. } | RemoteError::Request(_)) => {
+                tracing::error!(error = %err, "remote source error");
+                AppError::Internal("failed to fetch source".to_string())
+            }
+        }
+    }
+}
+
 impl From<TransformError> for AppError {
     fn from(err: TransformError) -> Self {
         match err {
             TransformError::InvalidParams(msg) => AppError::BadRequest(msg),
-            TransformError::ResolutionTooLarge { width, height } => AppError::BadRequest(format!(
-                "image resolution {width}x{height} exceeds maximum 4096x4096"
-            )),
+            err @ (TransformError::ResolutionTooLarge { .. }
+            | TransformError::OutputDimensionsTooLarge { .. }) => {
+                AppError::BadRequest(err.to_string())
+            }
+            err @ TransformError::QualityNotSupported { .. } => AppError::BadRequestWithCode {
+                code: "quality_not_supported",
+                message: err.to_string(),
+            },
+            err @ TransformError::ConversionForbidden { .. } => AppError::BadRequestWithCode {
+                code: "conversion_forbidden",
+                message: err.to_string(),
+            },
+            err @ TransformError::QualityBelowMinimum { .. } => {
+                AppError::BadRequest(err.to_string())
+            }
+            err @ TransformError::UpscaleTooLarge { .. } => AppError::BadRequest(err.to_string()),
+            err @ TransformError::AnimatedAvif => AppError::TransformFailed(err.to_string()),
+            TransformError::DecodeLimitExceeded(msg) => {
+                tracing::warn!(error = %msg, "decode memory limit exceeded");
+                AppError::BadRequest("image requires too much memory to decode".to_string())
+            }
+            err @ TransformError::BudgetUnreachable { .. } => {
+                AppError::TransformFailed(err.to_string())
+            }
+            err @ TransformError::OutputTooLarge { .. } => {
+                AppError::TransformFailed(err.to_string())
+            }
+            err @ TransformError::SourceTooSmall { .. } => {
+                AppError::TransformFailed(err.to_string())
+            }
+            TransformError::EmptyInput { .. } => {
+                AppError::TransformFailed("empty or non-image object".to_string())
+            }
+            TransformError::CorruptImage(msg) => {
+                tracing::warn!(error = %msg, "corrupt image data");
+                AppError::TransformFailed("image data appears truncated or corrupt".to_string())
+            }
+            TransformError::AvifEncodersSaturated { .. } => {
+                AppError::ServiceUnavailable("server is busy".to_string())
+            }
+            TransformError::DeadlineExceeded { stage } => {
+                tracing::warn!(stage, "request deadline exceeded during transform");
+                AppError::GatewayTimeout("request deadline exceeded".to_string())
+            }
             TransformError::ProcessingFailed(msg) => AppError::TransformFailed(msg),
         }
     }
@@ -195,8 +2699,34 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status, message) = match self {
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
-            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            AppError::BadRequestWithCode { code, message } => {
+                let body = serde_json::json!({ "error": message, "code": code });
+                return (StatusCode::BAD_REQUEST, axum::Json(body)).into_response();
+            }
+            AppError::Unauthorized(msg) => {
+                let body = serde_json::json!({ "error": msg });
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, "Bearer")],
+                    axum::Json(body),
+                )
+                    .into_response();
+            }
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
+            AppError::NotFound(msg) => {
+                let body = serde_json::json!({ "error": msg });
+                let mut response = (StatusCode::NOT_FOUND, axum::Json(body)).into_response();
+                // `Cache-Control` は `CachePolicy::not_found_max_age` に応じてミドルウェアで付ける
+                response.extensions_mut().insert(NotFoundResponse);
+                return response;
+            }
+            AppError::RangeNotSatisfiable(msg) => (StatusCode::RANGE_NOT_SATISFIABLE, msg),
+            AppError::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            AppError::PayloadTooLarge(msg) => (StatusCode::PAYLOAD_TOO_LARGE, msg),
+            AppError::UnsupportedMediaType(msg) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg),
             AppError::TransformFailed(msg) => (StatusCode::UNPROCESSABLE_ENTITY, msg),
+            AppError::ServiceUnavailable(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg),
+            AppError::GatewayTimeout(msg) => (StatusCode::GATEWAY_TIMEOUT, msg),
             AppError::Internal(msg) => {
                 tracing::error!(error = %msg, "internal server error");
                 (
@@ -210,3 +2740,440 @@ impl IntoResponse for AppError {
         (status, axum::Json(body)).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::AbandonedAvifEncodes;
+
+    fn is_valid(key: &str) -> bool {
+        validate_key_with_prefixes(key, &[]).is_ok()
+    }
+
+    fn is_bad_request(key: &str) -> bool {
+        matches!(
+            validate_key_with_prefixes(key, &[]),
+            Err(AppError::BadRequest(_))
+        )
+    }
+
+    #[test]
+    fn validate_key_accepts_plain_keys() {
+        for key in [
+            "photo.jpg",
+            "images/2024/photo.v2.png",
+            "a-b_c/d.webp",
+            "file",
+        ] {
+            assert!(is_valid(key), "{key}");
+        }
+    }
+
+    #[test]
+    fn validate_key_rejects_hidden_files() {
+        for key in [".env", "images/.env", "images/.hidden.jpg", "..."] {
+            assert!(is_bad_request(key), "{key}");
+        }
+        // 途中のセグメントや拡張子の `.` は隠しファイルではない
+        assert!(is_valid(".well-known/logo.png"));
+    }
+
+    #[test]
+    fn validate_key_rejects_empty_segments() {
+        for key in [
+            "",
+            "/photo.jpg",
+            "images/",
+            "images//photo.jpg",
+            "images/2024/",
+        ] {
+            assert!(is_bad_request(key), "{key}");
+        }
+    }
+
+    #[test]
+    fn validate_key_rejects_traversal_and_invalid_characters() {
+        for key in [
+            "../secret.jpg",
+            "images/../secret.jpg",
+            "images\\photo.jpg",
+            "photo.jpg?x=1",
+            "photo 1.jpg",
+        ] {
+            assert!(is_bad_request(key), "{key}");
+        }
+        assert!(is_bad_request(&"a".repeat(1025)));
+    }
+
+    #[test]
+    fn validate_key_enforces_allowed_prefixes() {
+        let prefixes = ["public/".to_string(), "avatars/".to_string()];
+        assert!(validate_key_with_prefixes("public/photo.jpg", &prefixes).is_ok());
+        assert!(validate_key_with_prefixes("avatars/u1.png", &prefixes).is_ok());
+        for key in ["private/photo.jpg", "publicity/photo.jpg"] {
+            assert!(
+                matches!(
+                    validate_key_with_prefixes(key, &prefixes),
+                    Err(AppError::Forbidden(_))
+                ),
+                "{key}"
+            );
+        }
+    }
+
+    #[test]
+    fn validate_key_accepts_unicode_keys_as_decoded() {
+        for key in ["写真/猫.jpg", "images/café.png", "ファイル名-1_2.webp"] {
+            assert!(is_valid(key), "{key}");
+        }
+        // 正規化しないため、結合文字 (英数字ではない) を含む NFD のキーは拒否される
+        assert!(is_valid("images/ガ.jpg"));
+        assert!(is_bad_request("images/カ\u{3099}.jpg"));
+    }
+
+    #[test]
+    fn validate_key_rejects_percent_encoded_keys() {
+        // ルーティングでデコード済みのため、残った `%` は二重エンコードとみなす
+        for key in [
+            "%E7%8C%AB.jpg",
+            "images/%2e%2e/secret.jpg",
+            "images/%252e.jpg",
+        ] {
+            assert!(is_bad_request(key), "{key}");
+        }
+    }
+
+    #[test]
+    fn validate_key_rejects_non_alphanumeric_unicode() {
+        for key in ["写真　猫.jpg", "images/★.png", "images/猫\u{200b}.jpg"] {
+            assert!(is_bad_request(key), "{key}");
+        }
+    }
+
+    #[test]
+    fn auto_compress_serves_original_only_when_metadata_is_kept() {
+        let input = Bytes::from(crate::transform::placeholder_image(4, 4).unwrap());
+        let transformed = || TransformOutput {
+            bytes: Bytes::from(vec![0; input.len() + 1]),
+            content_type: OutputFormat::Png.content_type(),
+            width: 4,
+            height: 4,
+            source_format: None,
+            quality: None,
+        };
+        let choice = |strip| {
+            let params = TransformParams {
+                auto_compress: true,
+                strip,
+                ..TransformParams::default()
+            };
+            smaller_of_original(&input, transformed(), &params).1
+        };
+
+        // 原本のメタデータが削除されずに返ってしまうため、strip=none 以外では原本を返さない
+        for strip in [None, Some(Strip::All), Some(Strip::Metadata)] {
+            assert_eq!(choice(strip), Some("transformed"), "{strip:?}");
+        }
+        assert_eq!(choice(Some(Strip::None)), Some("original"));
+    }
+
+    #[test]
+    fn header_dpr_is_capped_to_max_dimension() {
+        let mut headers = HeaderMap::new();
+        headers.insert(SEC_CH_DPR, HeaderValue::from_static("3"));
+        let dpr = header_dpr(&headers).unwrap();
+
+        // 2000x1000 を 3 倍すると 6000x3000 になるため、縦横比を保って上限に収める
+        let mut query = TransformQuery {
+            width: Some(2000),
+            height: Some(1000),
+            ..TransformQuery::default()
+        };
+        apply_dpr(&mut query, dpr);
+        assert_eq!((query.width, query.height), (Some(4096), Some(2048)));
+
+        let mut query = TransformQuery {
+            width: Some(1000),
+            ..TransformQuery::default()
+        };
+        apply_dpr(&mut query, dpr);
+        assert_eq!(query.width, Some(3000));
+    }
+
+    #[test]
+    fn not_modified_response_carries_the_same_vary_as_the_image() {
+        let vary = [SEC_CH_DPR, DPR, header::ACCEPT];
+        let response = not_modified_response(&CachePolicy::default(), "W/\"abc\"", &vary);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::VARY], "sec-ch-dpr, dpr, accept");
+
+        let response = not_modified_response(&CachePolicy::default(), "W/\"abc\"", &[]);
+        assert!(!response.headers().contains_key(header::VARY));
+    }
+
+    #[test]
+    fn weak_etag_depends_only_on_output_affecting_settings() {
+        let input = b"image";
+        let params = TransformParams {
+            width: Some(100),
+            ..TransformParams::default()
+        };
+        let config = TransformConfig::default();
+        let etag = weak_etag(input, &params, &config);
+
+        // エラーにするかどうかだけを決める設定は ETag を変えない
+        let strict = TransformConfig {
+            min_quality_jpeg: 50.0,
+            verify_output: true,
+            ..TransformConfig::default()
+        };
+        assert_eq!(weak_etag(input, &params, &strict), etag);
+
+        let wider = TransformParams {
+            width: Some(200),
+            ..params.clone()
+        };
+        assert_ne!(weak_etag(input, &wider, &config), etag);
+        let filtered = TransformConfig {
+            downscale_filter: TransformConfig::parse_filter("box").unwrap(),
+            ..TransformConfig::default()
+        };
+        assert_ne!(weak_etag(input, &params, &filtered), etag);
+    }
+
+    #[test]
+    fn cache_policy_adds_stale_directives_only_when_set() {
+        assert_eq!(CachePolicy::default().immutable(), CACHE_CONTROL_IMMUTABLE);
+        let cache = CachePolicy {
+            stale_while_revalidate: 60,
+            stale_if_error: 86400,
+            ..CachePolicy::default()
+        };
+        assert_eq!(
+            cache.immutable(),
+            format!("{CACHE_CONTROL_IMMUTABLE}, stale-while-revalidate=60, stale-if-error=86400")
+        );
+    }
+
+    #[test]
+    fn download_filename_keeps_the_original_name_in_filename_star() {
+        let disposition = |key: &str, extension: Option<&str>| {
+            let response =
+                with_download_filename(StatusCode::OK.into_response(), true, key, extension);
+            response.headers()[header::CONTENT_DISPOSITION]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            disposition("photos/写真 1.jpg", Some("webp")),
+            "attachment; filename=\"___1.webp\"; filename*=UTF-8''%E5%86%99%E7%9C%9F%201.webp"
+        );
+        // 引用符や改行もエンコードされ、ヘッダを分割できない
+        assert_eq!(
+            disposition("a\"b\r\n.png", None),
+            "attachment; filename=\"a_b__.png\"; filename*=UTF-8''a%22b%0D%0A.png"
+        );
+    }
+
+    #[test]
+    fn avif_unsupported_matches_user_agent_substrings() {
+        let patterns = ["Version/15.".to_string(), "Version/14.".to_string()];
+        let headers = |user_agent: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, HeaderValue::from_static(user_agent));
+            headers
+        };
+        let safari_15 = headers("Mozilla/5.0 (Macintosh) Version/15.6 Safari/605.1.15");
+        assert!(avif_unsupported(&patterns, &safari_15));
+        assert!(!avif_unsupported(
+            &patterns,
+            &headers("Mozilla/5.0 (Macintosh) Version/17.1 Safari/605.1.15")
+        ));
+        assert!(!avif_unsupported(&patterns, &HeaderMap::new()));
+        assert!(!avif_unsupported(&[], &safari_15));
+    }
+
+    #[test]
+    fn avif_is_replaced_with_webp_on_every_route_for_unsupported_clients() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::USER_AGENT,
+            HeaderValue::from_static("Mozilla/5.0 (Macintosh) Version/15.6 Safari/605.1.15"),
+        );
+        let avif_unsupported = avif_unsupported(&["Version/15.".to_string()], &headers);
+        let input = Bytes::from(crate::transform::placeholder_image(16, 16).unwrap());
+        let (limits, config) = (Limits::default(), TransformConfig::default());
+        let abandoned = AbandonedAvifEncodes::default();
+        let webp = OutputFormat::WebP.content_type();
+
+        // /transform, /t, src, /optimize。WebP で使えない AVIF 用の q と max_bytes は無視する
+        let params = TransformParams {
+            format: Some(OutputFormat::Avif),
+            quality: Some(60.0),
+            max_bytes: Some(100_000),
+            avif_unsupported,
+            ..TransformParams::default()
+        };
+        let output =
+            crate::transform::transform(&input, &params, &limits, &config, &abandoned).unwrap();
+        assert_eq!(output.content_type, webp);
+
+        let params = TileParams {
+            level: 0,
+            x: 0,
+            y: 0,
+            size: DEFAULT_TILE_SIZE,
+            format: Some(OutputFormat::Avif),
+            quality: Some(60.0),
+            avif_unsupported,
+        };
+        let output =
+            crate::tile::render_tile(&input, &params, &limits, &config, &abandoned).unwrap();
+        assert_eq!(output.content_type, webp);
+
+        let params = MontageParams {
+            cols: 1,
+            rows: 1,
+            cell: 8,
+            format: Some(OutputFormat::Avif),
+            quality: Some(60.0),
+            avif_unsupported,
+        };
+        let output = crate::montage::render_montage(
+            std::slice::from_ref(&input),
+            &params,
+            &limits,
+            &config,
+            &abandoned,
+        )
+        .unwrap();
+        assert_eq!(output.content_type, webp);
+
+        // 変換パラメータのない AVIF の原本も、ソースのフォーマットを維持せず WebP にする
+        assert_eq!(
+            crate::transform::determine_output_format(
+                Some(image::ImageFormat::Avif),
+                None,
+                None,
+                avif_unsupported
+            ),
+            OutputFormat::WebP
+        );
+    }
+
+    #[test]
+    fn parse_accept_sorts_by_q_and_keeps_header_order_on_ties() {
+        assert_eq!(
+            parse_accept("image/webp;q=0.5, image/avif, */*;q=0.1, image/png;q=0.5, image/jpeg"),
+            [
+                ("image/avif".to_string(), 1.0),
+                ("image/jpeg".to_string(), 1.0),
+                ("image/webp".to_string(), 0.5),
+                ("image/png".to_string(), 0.5),
+                ("*/*".to_string(), 0.1),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_accept_reads_q_case_insensitively() {
+        assert_eq!(
+            parse_accept("Application/JSON; Q=0.2, image/*; charset=utf-8; q = 0.8"),
+            [
+                ("image/*".to_string(), 0.8),
+                ("application/json".to_string(), 0.2),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_accept_clamps_q_into_range() {
+        assert_eq!(
+            parse_accept("image/webp;q=1.5, image/png;q=-0.5"),
+            [
+                ("image/webp".to_string(), 1.0),
+                ("image/png".to_string(), 0.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_accept_drops_ranges_with_malformed_q() {
+        // 不正な q を最優先の 1 として扱わない
+        for q in ["abc", "", "NaN", "inf", "0.5.1"] {
+            assert_eq!(
+                parse_accept(&format!("application/json;q={q}, image/png;q=0.1")),
+                [("image/png".to_string(), 0.1)],
+                "{q}"
+            );
+        }
+        assert!(parse_accept(" , ;q=0.5,").is_empty());
+    }
+
+    #[test]
+    fn path_params_accept_width_height_quality_and_format() {
+        let query = parse_path_params("w400-h300-q80.5-fwebp").unwrap();
+        assert_eq!(query.width, Some(400));
+        assert_eq!(query.height, Some(300));
+        assert_eq!(query.quality, Some(80.5));
+        assert_eq!(query.format.as_deref(), Some("webp"));
+
+        // 空のトークンは読み飛ばす
+        let query = parse_path_params("-w400--").unwrap();
+        assert_eq!(query.width, Some(400));
+        assert_eq!(query.format, None);
+    }
+
+    #[test]
+    fn path_params_reject_other_tokens() {
+        for segment in [
+            // w/h/q/f 以外のパラメータ
+            "dpr2",
+            "w400-x10",
+            "w400-oerror",
+            "W400",
+            // 値がない、数値でない
+            "w",
+            "f",
+            "wabc",
+            "h-1",
+            "q80%",
+            "ｗ400",
+        ] {
+            assert!(
+                matches!(parse_path_params(segment), Err(AppError::BadRequest(_))),
+                "{segment}"
+            );
+        }
+    }
+
+    #[test]
+    fn passthrough_content_type_ignores_non_image_stored_types() {
+        let png = Bytes::from(crate::transform::placeholder_image(1, 1).unwrap());
+        let object = |content_range: Option<&str>, content_type: Option<&str>| StoredObject {
+            body: png.clone(),
+            content_range: content_range.map(str::to_string),
+            content_type: content_type.map(str::to_string),
+        };
+
+        // 全体を取得した場合は R2 の値を使わずマジックバイトから判定する
+        assert_eq!(
+            passthrough_content_type(&object(None, Some("text/html"))),
+            "image/png"
+        );
+        let range = Some("bytes 0-9/100");
+        assert_eq!(
+            passthrough_content_type(&object(range, Some("image/jpeg"))),
+            "image/jpeg"
+        );
+        for stored in [Some("text/html"), Some("application/javascript"), None] {
+            assert_eq!(
+                passthrough_content_type(&object(range, stored)),
+                "application/octet-stream",
+                "{stored:?}"
+            );
+        }
+    }
+}
@@ -1,45 +1,299 @@
+mod admission;
+mod analyze;
+mod blurhash;
+mod breaker;
+mod detect;
+mod diff;
+mod exif;
 mod handler;
+mod montage;
+#[cfg(feature = "otel")]
+mod otel;
+mod quantize;
+mod remote;
+mod sprite;
 mod storage;
+mod tile;
 mod transform;
 
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use axum::Router;
-use axum::routing::get;
+use axum::body::HttpBody;
+use axum::extract::{DefaultBodyLimit, Request, State};
+use axum::http::{HeaderValue, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
 use tokio::signal;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, fmt};
 
+use crate::admission::{AdmissionControl, MemoryBudget};
+use crate::breaker::CircuitBreaker;
+use crate::handler::{AppError, CachePolicy};
+use crate::remote::RemoteFetcher;
 use crate::storage::R2Client;
+use crate::transform::{
+    AbandonedAvifEncodes, DEFAULT_DECODE_MEMORY_LIMIT, DEFAULT_MAX_SOURCE_PIXELS,
+    DEFAULT_TRUSTED_MAX_PIXELS, EncoderConfig, Limits, TransformConfig,
+};
 
 #[derive(Clone)]
 pub struct AppState {
     pub r2_client: R2Client,
+    /// R2 の連続障害時に即座に 503 を返すためのサーキットブレーカー
+    pub r2_breaker: Arc<CircuitBreaker>,
+    /// 内部バッチ等の信頼済みリクエストを識別するトークン (`X-Internal-Token`)
+    pub internal_token: Option<String>,
+    /// 通常のリクエストに適用する上限値
+    pub limits: Limits,
+    /// 信頼済みリクエストに適用する上限値
+    pub trusted_limits: Limits,
+    pub transform_config: Arc<TransformConfig>,
+    /// 制限時間を超えても動き続けている AVIF エンコードの数 (プロセス全体で共有)
+    pub abandoned_avif: AbandonedAvifEncodes,
+    /// 配信を許可するキーのプレフィックス。空の場合はすべて許可
+    pub allowed_key_prefixes: Arc<[String]>,
+    /// `/b/{bucket}/...` で指定できるバケット。空の場合はバケット指定のルートをすべて拒否する
+    pub allowed_buckets: Arc<[String]>,
+    /// 変換処理の同時実行数と待ち行列の上限
+    pub admission: Arc<AdmissionControl>,
+    /// 実行中の変換のメモリ見積もりの合計の上限
+    pub memory_budget: Arc<MemoryBudget>,
+    /// 変換リクエスト全体 (待ち行列・取得・変換) の期限。None の場合は期限なし
+    pub request_deadline: Option<Duration>,
+    /// `Sec-CH-DPR` ヘッダで出力サイズを自動調整する
+    pub client_hints: bool,
+    /// `Accept: application/json` の場合に画像の代わりに代表色の JSON を返す
+    pub palette_negotiation: bool,
+    /// AVIF を表示できないクライアントの User-Agent に含まれる文字列。一致した場合は WebP で返す
+    pub avif_unsupported_user_agents: Arc<[String]>,
+    /// 変換パラメータがないリクエストも原本を返さず再エンコードする (`force_reencode` のデフォルト)
+    pub force_reencode: bool,
+    /// `src` で署名付き URL を指定した場合の取得クライアント。None の場合は `src` を受け付けない
+    pub remote_fetcher: Option<Arc<RemoteFetcher>>,
+    /// 画像レスポンスと 404 に付ける `Cache-Control` の設定
+    pub cache_policy: CachePolicy,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _ = dotenvy::dotenv();
 
-    tracing_subscriber::registry()
-        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
-        .with(fmt::layer().json())
-        .init();
+    let telemetry = init_tracing();
 
     let r2_client = R2Client::from_env().await.map_err(|e| {
         tracing::error!("Failed to initialize R2 client: {}", e);
         e
     })?;
-    let state = AppState { r2_client };
+    let internal_token = std::env::var("INTERNAL_AUTH_TOKEN")
+        .ok()
+        .filter(|t| !t.is_empty());
+    let trusted_max_pixels = env_or("TRUSTED_MAX_PIXELS", DEFAULT_TRUSTED_MAX_PIXELS);
+    let decode_memory_limit = env_or("DECODE_MEMORY_LIMIT", DEFAULT_DECODE_MEMORY_LIMIT);
+    let limits = Limits {
+        max_pixels: env_or("MAX_SOURCE_PIXELS", DEFAULT_MAX_SOURCE_PIXELS),
+        decode_memory_limit,
+        deadline: None,
+    };
+    // デコード結果は RGBA 8bit でピクセルあたり 4 バイト以上になるため、上限を超えるとデコードで失敗する
+    for (name, max_pixels) in [
+        ("MAX_SOURCE_PIXELS", limits.max_pixels),
+        ("TRUSTED_MAX_PIXELS", trusted_max_pixels),
+    ] {
+        if max_pixels.saturating_mul(4) > decode_memory_limit {
+            tracing::warn!(
+                max_pixels,
+                decode_memory_limit,
+                "{name} exceeds what DECODE_MEMORY_LIMIT allows to decode (4 bytes per pixel)"
+            );
+        }
+    }
+
+    let forbidden_conversions = TransformConfig::parse_forbidden_conversions(
+        &std::env::var("FORBID_CONVERSIONS").unwrap_or_default(),
+    )
+    .map_err(|e| {
+        tracing::error!("Invalid FORBID_CONVERSIONS: {}", e);
+        e
+    })?;
+    let quality_curve =
+        TransformConfig::parse_quality_curve(&std::env::var("QUALITY_CURVE").unwrap_or_default())
+            .map_err(|e| {
+            tracing::error!("Invalid QUALITY_CURVE: {}", e);
+            e
+        })?;
+    let resize_filter = |name: &str| {
+        std::env::var(name)
+            .map_or(Ok(Default::default()), |v| {
+                TransformConfig::parse_filter(&v)
+            })
+            .map_err(|e| {
+                tracing::error!("Invalid {}: {}", name, e);
+                e
+            })
+    };
+    let downscale_filter = resize_filter("RESIZE_DOWNSCALE_FILTER")?;
+    let upscale_filter = resize_filter("RESIZE_UPSCALE_FILTER")?;
+
+    let encoder = EncoderConfig::parse(
+        std::env::var("AVIF_SPEED").ok().as_deref(),
+        std::env::var("PNG_COMPRESSION").ok().as_deref(),
+        std::env::var("PNG_FILTER").ok().as_deref(),
+        std::env::var("WEBP_METHOD").ok().as_deref(),
+    )
+    .map_err(|e| {
+        tracing::error!("Invalid encoder config: {}", e);
+        e
+    })?;
+
+    let default_concurrency = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let transform_config = TransformConfig {
+        forbidden_conversions,
+        min_quality_jpeg: env_or("MIN_QUALITY_JPEG", 1.0),
+        min_quality_avif: env_or("MIN_QUALITY_AVIF", 1.0),
+        quality_curve,
+        downscale_filter,
+        upscale_filter,
+        max_output_bytes: Some(env_or("MAX_OUTPUT_BYTES", 0)).filter(|&n| n > 0),
+        max_upscale: Some(env_or("MAX_UPSCALE", 0.0)).filter(|&f| f > 0.0),
+        avif_encode_timeout: Some(env_or("AVIF_ENCODE_TIMEOUT_MS", 0))
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis),
+        max_abandoned_avif_encodes: env_or("MAX_ABANDONED_AVIF_ENCODES", default_concurrency),
+        encoder,
+        verify_output: env_or("VERIFY_OUTPUT", false),
+    };
+
+    let allowed_key_prefixes = env_list("ALLOWED_KEY_PREFIXES");
+
+    let r2_breaker = CircuitBreaker::new(
+        env_or("R2_BREAKER_THRESHOLD", 5),
+        Duration::from_secs(env_or("R2_BREAKER_COOLDOWN_SECS", 30)),
+    );
 
-    let app = Router::new()
+    let admission = AdmissionControl::new(
+        env_or("MAX_CONCURRENT_TRANSFORMS", default_concurrency),
+        env_or("MAX_QUEUE_DEPTH", 64),
+    );
+
+    let memory_budget = MemoryBudget::new(env_or("MEMORY_BUDGET", 0));
+    // 見積もりはソース x 2 + 出力 (最大でソースと同じサイズ) で、単独で予算を超える画像は常に 422 になる
+    let worst_case_estimate = limits.max_pixels.saturating_mul(4 * 3);
+    if memory_budget.limit() > 0 && worst_case_estimate > memory_budget.limit() {
+        tracing::warn!(
+            max_pixels = limits.max_pixels,
+            worst_case_estimate,
+            memory_budget = memory_budget.limit(),
+            "MEMORY_BUDGET is smaller than the estimate for a MAX_SOURCE_PIXELS image (12 bytes per pixel)"
+        );
+    }
+
+    let remote_source_hosts = env_list("REMOTE_SOURCE_HOSTS");
+    let remote_fetcher = (!remote_source_hosts.is_empty())
+        .then(|| Arc::new(RemoteFetcher::new(&remote_source_hosts)));
+
+    let state = AppState {
+        r2_client,
+        r2_breaker: Arc::new(r2_breaker),
+        internal_token,
+        limits,
+        trusted_limits: Limits {
+            max_pixels: trusted_max_pixels,
+            decode_memory_limit,
+            deadline: None,
+        },
+        transform_config: Arc::new(transform_config),
+        abandoned_avif: AbandonedAvifEncodes::default(),
+        allowed_key_prefixes,
+        allowed_buckets: env_list("ALLOWED_BUCKETS"),
+        admission: Arc::new(admission),
+        memory_budget: Arc::new(memory_budget),
+        request_deadline: Some(env_or("REQUEST_DEADLINE_MS", 0))
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis),
+        client_hints: env_or("CLIENT_HINTS", false),
+        palette_negotiation: env_or("PALETTE_NEGOTIATION", false),
+        avif_unsupported_user_agents: env_list("AVIF_UNSUPPORTED_USER_AGENTS"),
+        remote_fetcher,
+        force_reencode: env_or("FORCE_REENCODE", false),
+        cache_policy: CachePolicy {
+            stale_while_revalidate: env_or("SWR_SECONDS", 0),
+            stale_if_error: env_or("SIE_SECONDS", 0),
+            not_found_max_age: env_or("NOT_FOUND_MAX_AGE", 0),
+        },
+    };
+
+    let api = Router::new()
+        .route("/transform", get(handler::transform_remote))
         .route("/transform/{*key}", get(handler::transform))
-        .route("/health", get(handler::health))
-        .layer(TraceLayer::new_for_http())
+        .route(
+            "/b/{bucket}/transform/{*key}",
+            get(handler::transform_in_bucket),
+        )
+        .route(
+            "/t/{params}/{*key}",
+            get(handler::transform_with_path_params),
+        )
+        .route("/tile/{*key}", get(handler::tile))
+        .route("/sprite/{*key}", get(handler::sprite))
+        .route("/montage", get(handler::montage))
+        .route("/detect/{*key}", get(handler::detect))
+        .route("/analyze/{*key}", get(handler::analyze))
+        .route("/exif/{*key}", get(handler::exif))
+        .route("/blurhash/{*key}", get(handler::blurhash))
+        .route("/diff/{*key}", get(handler::diff))
+        .route("/version", get(handler::version))
+        .route(
+            "/optimize",
+            post(handler::optimize).layer(DefaultBodyLimit::max(handler::OPTIMIZE_BODY_LIMIT)),
+        );
+
+    // ビーコンは Authorization を付けられない <img> 等から読み込まれるため、PIXEL_PUBLIC で認証の対象外にできる
+    let pixel_public = env_or("PIXEL_PUBLIC", false);
+    let api = if pixel_public {
+        api
+    } else {
+        api.route("/pixel", get(handler::pixel))
+    };
+
+    // /health (Cloud Run のヘルスチェック用) は認証なしで公開する
+    let api = match std::env::var("AUTH_TOKEN").ok().filter(|t| !t.is_empty()) {
+        Some(token) => api.route_layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            require_auth,
+        )),
+        None => api,
+    };
+
+    let app = api.route("/health", get(handler::health));
+    let app = if pixel_public {
+        app.route("/pixel", get(handler::pixel))
+    } else {
+        app
+    };
+    let trace_layer = TraceLayer::new_for_http();
+    #[cfg(feature = "otel")]
+    let trace_layer = trace_layer.make_span_with(otel::make_span);
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            not_found_cache_control,
+        ))
+        .layer(trace_layer)
         .with_state(state);
 
+    let app = if env_or("ACCESS_LOG", false) {
+        app.layer(middleware::from_fn(access_log))
+    } else {
+        app
+    };
+
     let port: u16 = std::env::var("PORT")
         .unwrap_or_else(|_| "8080".to_string())
         .parse()
@@ -64,9 +318,152 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             e
         })?;
 
+    telemetry.shutdown();
     Ok(())
 }
 
+/// `Authorization: Bearer <AUTH_TOKEN>` を要求する。一致しない場合は 401 を返す。
+async fn require_auth(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|t| handler::constant_time_eq(t.as_bytes(), token.as_bytes()));
+
+    if !authorized {
+        return AppError::Unauthorized("missing or invalid bearer token".to_string())
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+/// `AppError::NotFound` のレスポンスに `NOT_FOUND_MAX_AGE` の `Cache-Control` を付ける。
+///
+/// 存在しないキーへの繰り返しのリクエストを CDN で吸収させる。
+async fn not_found_cache_control(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let max_age = state.cache_policy.not_found_max_age;
+    if max_age > 0
+        && response
+            .extensions()
+            .get::<handler::NotFoundResponse>()
+            .is_some()
+        && let Ok(value) = HeaderValue::from_str(&format!("public, max-age={max_age}"))
+    {
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    response
+}
+
+/// レスポンス生成後に、リクエストごとのアクセスログを 1 行出力する。
+async fn access_log(request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    tracing::info!(
+        target: "access_log",
+        method = %method,
+        path = %path,
+        status = response.status().as_u16(),
+        bytes = response.body().size_hint().exact(),
+        latency_ms = started.elapsed().as_millis() as u64,
+        "access"
+    );
+
+    response
+}
+
+/// 環境変数をパースする。未設定または不正な値の場合はデフォルト値を使う。
+fn env_or<T>(name: &str, default: T) -> T
+where
+    T: std::str::FromStr + std::fmt::Display,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(v) => v.parse().unwrap_or_else(|e| {
+            tracing::warn!("Invalid {} value, using default {}: {}", name, default, e);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// カンマ区切りの環境変数を読み取る。未設定の場合や空の要素は無視する。
+fn env_list(name: &str) -> Arc<[String]> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// 送信し残したスパンを終了時に flush するためのハンドル。OTLP で送信しない場合は何もしない
+#[derive(Default)]
+struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Telemetry {
+    fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider
+            && let Err(e) = provider.shutdown()
+        {
+            tracing::warn!(error = %e, "failed to flush OpenTelemetry spans");
+        }
+    }
+}
+
+/// tracing を初期化する。
+///
+/// `LOG_FORMAT=pretty` で人間向けの出力、それ以外 (未設定を含む) は JSON 出力。
+/// `otel` feature 有効時は、`OTEL_EXPORTER_OTLP_ENDPOINT` が設定されていれば OTLP でも送信する。
+fn init_tracing() -> Telemetry {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry().with(filter);
+    let telemetry = Telemetry::default();
+
+    #[cfg(feature = "otel")]
+    let (registry, telemetry, otel_error) = match otel::layer() {
+        Ok(Some((layer, provider))) => (
+            registry.with(Some(layer)),
+            Telemetry {
+                provider: Some(provider),
+            },
+            None,
+        ),
+        Ok(None) => (registry.with(None), telemetry, None),
+        Err(e) => (registry.with(None), telemetry, Some(e)),
+    };
+
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("pretty") => registry.with(fmt::layer().pretty()).init(),
+        Ok("json") | Err(_) => registry.with(fmt::layer().json()).init(),
+        Ok(other) => {
+            registry.with(fmt::layer().json()).init();
+            tracing::warn!("Invalid LOG_FORMAT value '{}', using json", other);
+        }
+    }
+
+    #[cfg(feature = "otel")]
+    if let Some(e) = otel_error {
+        tracing::warn!(error = %e, "failed to initialize the OTLP exporter, spans are not exported");
+    }
+    telemetry
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
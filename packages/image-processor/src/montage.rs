@@ -0,0 +1,195 @@
+use bytes::Bytes;
+use image::{DynamicImage, Rgba, RgbaImage, imageops};
+
+use crate::transform::{
+    AbandonedAvifEncodes, EmbeddedMetadata, EncodeOptions, Limits, MAX_DIMENSION, OutputFormat,
+    ResizeSettings, TransformConfig, TransformError, TransformOutput, decode_image,
+    determine_output_format, encode_output, fill_image, resolve_quality, validate_quality,
+    validate_source_dimensions,
+};
+
+/// 1 枚のモンタージュに並べられる画像数の上限 (取得する原本の合計サイズを抑えるため)
+pub const MAX_MONTAGE_KEYS: usize = 16;
+
+/// グリッドのレイアウトと出力形式。
+#[derive(Debug, Clone)]
+pub struct MontageParams {
+    pub cols: u32,
+    pub rows: u32,
+    /// 1 マスの 1 辺 (px)
+    pub cell: u32,
+    pub format: Option<OutputFormat>,
+    pub quality: Option<f32>,
+    /// クライアントが AVIF を表示できない。AVIF になる出力は WebP に置き換える
+    pub avif_unsupported: bool,
+}
+
+impl MontageParams {
+    /// レイアウトとキャンバスサイズを検証する。画像の取得前に呼ぶ。
+    pub fn validate(&self, count: usize) -> Result<(), TransformError> {
+        validate_quality(self.quality)?;
+        if self.cols == 0 || self.rows == 0 || self.cell == 0 {
+            return Err(TransformError::InvalidParams(
+                "cols, rows and cell must be at least 1".to_string(),
+            ));
+        }
+        let capacity = self.cols as u64 * self.rows as u64;
+        if count == 0 || count as u64 > capacity || count > MAX_MONTAGE_KEYS {
+            return Err(TransformError::InvalidParams(format!(
+                "keys must contain 1-{} values, got {count}",
+                capacity.min(MAX_MONTAGE_KEYS as u64)
+            )));
+        }
+        let width = self.cols as u64 * self.cell as u64;
+        let height = self.rows as u64 * self.cell as u64;
+        if width > MAX_DIMENSION as u64 || height > MAX_DIMENSION as u64 {
+            return Err(TransformError::OutputDimensionsTooLarge {
+                width: width.min(u32::MAX as u64) as u32,
+                height: height.min(u32::MAX as u64) as u32,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// 複数の画像を `cols` x `rows` のグリッドに並べた 1 枚の画像を生成する。
+///
+/// 各画像はマスいっぱいに cover で拡縮し、はみ出しは中央で切り取る。
+/// 画像は左上から行優先で配置し、余ったマスは背景のまま残す
+/// (アルファを持つ出力では透明、JPEG では白)。
+pub fn render_montage(
+    inputs: &[Bytes],
+    params: &MontageParams,
+    limits: &Limits,
+    config: &TransformConfig,
+    abandoned_avif: &AbandonedAvifEncodes,
+) -> Result<TransformOutput, TransformError> {
+    params.validate(inputs.len())?;
+
+    let requested_format = params.format.unwrap_or(OutputFormat::Png);
+    let output_format =
+        determine_output_format(None, Some(requested_format), None, params.avif_unsupported);
+    // WebP はロスレス固定のため、AVIF から置き換えた場合は AVIF 用の q を使わない
+    let requested_quality = params.quality.filter(|_| output_format == requested_format);
+    config.check_quality(output_format, requested_quality)?;
+    let quality = resolve_quality(output_format, requested_quality)?;
+    // 禁止された変換は、どの画像もデコードする前にマジックバイトから判別して弾く
+    for input in inputs {
+        config.check_conversion(image::guess_format(input).ok(), output_format)?;
+    }
+
+    let (width, height) = (params.cols * params.cell, params.rows * params.cell);
+    let background = match output_format {
+        OutputFormat::Jpeg => Rgba([255, 255, 255, 255]),
+        _ => Rgba([0, 0, 0, 0]),
+    };
+    let mut canvas = RgbaImage::from_pixel(width, height, background);
+
+    for (index, input) in inputs.iter().enumerate() {
+        let decoded = decode_image(input, limits)?;
+        let mut img = decoded.image;
+        if let Some(orientation) = decoded.orientation {
+            img.apply_orientation(orientation);
+        }
+        validate_source_dimensions(img.width(), img.height(), limits)?;
+
+        let settings = ResizeSettings {
+            premultiply_alpha: img.color().has_alpha(),
+            downscale_filter: config.downscale_filter,
+            upscale_filter: config.upscale_filter,
+            ..ResizeSettings::default()
+        };
+        let cell = fill_image(
+            img,
+            params.cell,
+            params.cell,
+            false,
+            background.0,
+            None,
+            settings,
+        )?;
+
+        let (col, row) = (index as u32 % params.cols, index as u32 / params.cols);
+        imageops::overlay(
+            &mut canvas,
+            &cell.to_rgba8(),
+            (col * params.cell) as i64,
+            (row * params.cell) as i64,
+        );
+    }
+
+    let options = EncodeOptions {
+        quality,
+        subsampling: None,
+        progressive: false,
+        metadata: EmbeddedMetadata::default(),
+        near_lossless: None,
+        prefer_palette: false,
+        encoder: config.encoder,
+        colors: None,
+    };
+    let (output_bytes, output_format) = encode_output(
+        &DynamicImage::ImageRgba8(canvas),
+        output_format,
+        &options,
+        limits,
+        config,
+        abandoned_avif,
+    )?;
+
+    Ok(TransformOutput {
+        bytes: Bytes::from(output_bytes),
+        content_type: output_format.content_type(),
+        width,
+        height,
+        source_format: None,
+        quality: config.min_quality(output_format).map(|_| quality),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ImageFormat;
+
+    use super::*;
+    use crate::transform::fixtures::{encode_as, noise_image, truncated_png};
+
+    #[test]
+    fn forbidden_conversion_is_rejected_before_decoding_any_input() {
+        // 先頭のデコードできない画像より、後ろの禁止された変換のエラーが先に返る
+        let inputs = [
+            truncated_png(),
+            encode_as(&noise_image(8, 8), ImageFormat::Jpeg),
+        ];
+        let config = TransformConfig {
+            forbidden_conversions: vec![(ImageFormat::Jpeg, OutputFormat::Png)],
+            ..TransformConfig::default()
+        };
+        let params = MontageParams {
+            cols: 2,
+            rows: 1,
+            cell: 8,
+            format: Some(OutputFormat::Png),
+            quality: None,
+            avif_unsupported: false,
+        };
+        let err = render_montage(
+            &inputs,
+            &params,
+            &Limits::default(),
+            &config,
+            &AbandonedAvifEncodes::default(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                TransformError::ConversionForbidden {
+                    from: ImageFormat::Jpeg,
+                    to: OutputFormat::Png
+                }
+            ),
+            "{err:?}"
+        );
+    }
+}
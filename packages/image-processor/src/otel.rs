@@ -0,0 +1,77 @@
+//! OpenTelemetry (OTLP/HTTP) でのトレースの送信 (`otel` feature)。
+//!
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` を設定した場合のみ送信する。送信先以外の設定
+//! (ヘッダ、タイムアウト、`OTEL_SERVICE_NAME` 等) は OpenTelemetry の標準の環境変数をそのまま使う。
+
+use axum::extract::Request;
+use axum::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::ExporterBuildError;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use tower_http::trace::{DefaultMakeSpan, MakeSpan};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// `OTEL_SERVICE_NAME` 未設定時のサービス名
+const SERVICE_NAME: &str = "image-processor";
+
+/// tracing のスパンを OTLP で送信するレイヤーと、終了時に flush するためのプロバイダを作る。
+///
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` が未設定または空の場合は None (送信しない)。
+/// 受信したリクエストの `traceparent` を親にできるよう、W3C Trace Context の propagator も登録する。
+pub fn layer<S>()
+-> Result<Option<(OpenTelemetryLayer<S, Tracer>, SdkTracerProvider)>, ExporterBuildError>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    if std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .is_none()
+    {
+        return Ok(None);
+    }
+
+    // 送信先 (OTEL_EXPORTER_OTLP_ENDPOINT + /v1/traces) はエクスポータが環境変数から読み取る
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+    let resource = if std::env::var_os("OTEL_SERVICE_NAME").is_some() {
+        Resource::builder().build()
+    } else {
+        Resource::builder().with_service_name(SERVICE_NAME).build()
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME));
+    Ok(Some((layer, provider)))
+}
+
+/// `TraceLayer` のリクエストのスパンを作り、`traceparent` があればそれを親にする。
+pub fn make_span(request: &Request) -> tracing::Span {
+    let span = DefaultMakeSpan::new().make_span(request);
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+    span
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::{HashMap, HashSet};
+
+use image::{DynamicImage, RgbaImage};
+
+/// 減色の最小・最大色数
+pub const MIN_COLORS: u16 = 2;
+pub const MAX_COLORS: u16 = 256;
+
+/// 色の集計に使う 1 チャンネルあたりのビット数。
+/// 下位ビットをまとめて集計し、ユニーク色が多い画像でもヒストグラムの大きさを抑える
+const HISTOGRAM_BITS: u32 = 5;
+
+/// ヒストグラムの 1 エントリ (同じバケットに入る色の合計)。
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    key: u32,
+    count: u64,
+    sum: [u64; 4],
+    /// バケット内の平均色 (集計後に求める)
+    color: [u8; 4],
+}
+
+impl Bucket {
+    fn mean(&self) -> [u8; 4] {
+        self.sum
+            .map(|s| (s as f64 / self.count as f64).round() as u8)
+    }
+}
+
+/// 代表色とその画素数。
+#[derive(Debug, Clone, Copy)]
+pub struct PaletteColor {
+    pub rgba: [u8; 4],
+    pub count: u64,
+}
+
+/// median cut で `colors` 色以下に減色した画像を返す。
+///
+/// 元の色数が `colors` 以下の場合はそのまま返す。ディザリングは行わない。
+/// アルファも 1 チャンネルとして扱うため、半透明の画素も含めて減色される。
+pub fn quantize(img: &DynamicImage, colors: u16) -> RgbaImage {
+    let mut rgba = img.to_rgba8();
+    let colors = colors.clamp(MIN_COLORS, MAX_COLORS) as usize;
+    if unique_colors_at_most(&rgba, colors) {
+        return rgba;
+    }
+
+    // バケットごとに、属するボックスの平均色へ置き換える
+    let mut palette: HashMap<u32, [u8; 4]> = HashMap::new();
+    for buckets in median_cut(histogram(&rgba), colors) {
+        let color = summarize(&buckets).rgba;
+        palette.extend(buckets.iter().map(|b| (b.key, color)));
+    }
+
+    for pixel in rgba.pixels_mut() {
+        pixel.0 = palette[&bucket_key(pixel.0)];
+    }
+    rgba
+}
+
+/// 画像の代表色を最大 `colors` 色、画素数の多い順に返す。
+///
+/// `quantize` と同じ median cut で減色した各色と、その色に置き換わる画素数を返す。
+/// 元の色数が `colors` 以下の場合は元の色をそのまま数える。
+pub fn palette(img: &DynamicImage, colors: u16) -> Vec<PaletteColor> {
+    let rgba = img.to_rgba8();
+    let colors = colors.clamp(1, MAX_COLORS) as usize;
+    let mut palette: Vec<PaletteColor> = if unique_colors_at_most(&rgba, colors) {
+        let mut counts: HashMap<[u8; 4], u64> = HashMap::new();
+        for pixel in rgba.pixels() {
+            *counts.entry(pixel.0).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .map(|(rgba, count)| PaletteColor { rgba, count })
+            .collect()
+    } else {
+        median_cut(histogram(&rgba), colors)
+            .iter()
+            .map(|buckets| summarize(buckets))
+            .collect()
+    };
+    palette.sort_unstable_by(|a, b| b.count.cmp(&a.count).then(a.rgba.cmp(&b.rgba)));
+    palette
+}
+
+/// 色をバケットに集計する。
+fn histogram(rgba: &RgbaImage) -> Vec<Bucket> {
+    let mut histogram: HashMap<u32, Bucket> = HashMap::new();
+    for pixel in rgba.pixels() {
+        let key = bucket_key(pixel.0);
+        let bucket = histogram.entry(key).or_insert(Bucket {
+            key,
+            count: 0,
+            sum: [0; 4],
+            color: [0; 4],
+        });
+        bucket.count += 1;
+        for (sum, &value) in bucket.sum.iter_mut().zip(pixel.0.iter()) {
+            *sum += value as u64;
+        }
+    }
+
+    histogram
+        .into_values()
+        .map(|b| Bucket {
+            color: b.mean(),
+            ..b
+        })
+        .collect()
+}
+
+/// ボックスに属するバケットの平均色と画素数を返す。
+fn summarize(buckets: &[Bucket]) -> PaletteColor {
+    let total = buckets.iter().fold(
+        Bucket {
+            key: 0,
+            count: 0,
+            sum: [0; 4],
+            color: [0; 4],
+        },
+        |mut acc, b| {
+            acc.count += b.count;
+            for (a, s) in acc.sum.iter_mut().zip(b.sum) {
+                *a += s;
+            }
+            acc
+        },
+    );
+    PaletteColor {
+        rgba: total.mean(),
+        count: total.count,
+    }
+}
+
+/// ユニーク色が `limit` 以下かを、`limit` を超えた時点で打ち切って判定する。
+fn unique_colors_at_most(img: &RgbaImage, limit: usize) -> bool {
+    let mut seen = HashSet::with_capacity(limit + 1);
+    for pixel in img.pixels() {
+        if seen.insert(pixel.0) && seen.len() > limit {
+            return false;
+        }
+    }
+    true
+}
+
+fn bucket_key(pixel: [u8; 4]) -> u32 {
+    let shift = 8 - HISTOGRAM_BITS;
+    pixel.iter().fold(0, |key, &value| {
+        key << HISTOGRAM_BITS | (value >> shift) as u32
+    })
+}
+
+/// 分割対象のボックス。値の幅が最も大きいチャンネルを作成時に求めておく。
+struct ColorBox {
+    buckets: Vec<Bucket>,
+    channel: usize,
+    range: u8,
+}
+
+impl ColorBox {
+    fn new(buckets: Vec<Bucket>) -> Self {
+        let (channel, range) = (0..4)
+            .map(|channel| {
+                let (min, max) = buckets.iter().fold((u8::MAX, u8::MIN), |(min, max), b| {
+                    (min.min(b.color[channel]), max.max(b.color[channel]))
+                });
+                (channel, max.saturating_sub(min))
+            })
+            .max_by_key(|&(_, range)| range)
+            .unwrap_or((0, 0));
+        Self {
+            buckets,
+            channel,
+            range,
+        }
+    }
+}
+
+/// 画素数で重み付けした中央値でボックスを分割し、最大 `colors` 個のボックスにする。
+///
+/// 毎回、値の幅が最も大きいチャンネルを持つボックスをそのチャンネルで分割する。
+fn median_cut(buckets: Vec<Bucket>, colors: usize) -> Vec<Vec<Bucket>> {
+    let mut boxes = vec![ColorBox::new(buckets)];
+    while boxes.len() < colors {
+        let Some(index) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.buckets.len() > 1 && b.range > 0)
+            .max_by_key(|(_, b)| b.range)
+            .map(|(i, _)| i)
+        else {
+            // これ以上分割できない (各ボックスが単色)
+            break;
+        };
+
+        let ColorBox {
+            buckets: mut target,
+            channel,
+            ..
+        } = boxes.swap_remove(index);
+        target.sort_unstable_by_key(|b| b.color[channel]);
+        let half = target.iter().map(|b| b.count).sum::<u64>() / 2;
+        let mut accumulated = 0;
+        let split = target
+            .iter()
+            .position(|b| {
+                accumulated += b.count;
+                accumulated >= half
+            })
+            .map_or(1, |i| i + 1)
+            .clamp(1, target.len() - 1);
+        let upper = target.split_off(split);
+        boxes.push(ColorBox::new(target));
+        boxes.push(ColorBox::new(upper));
+    }
+    boxes.into_iter().map(|b| b.buckets).collect()
+}
@@ -0,0 +1,127 @@
+use bytes::Bytes;
+use image::{DynamicImage, RgbaImage, imageops};
+use serde::Serialize;
+
+use crate::transform::{
+    EmbeddedMetadata, EncodeOptions, Limits, MAX_DIMENSION, OutputFormat, ResizeSettings,
+    TransformConfig, TransformError, calculate_contain_dimensions, decode_image, encode_image,
+    resize_image, resolve_quality, validate_source_dimensions,
+};
+
+const MAX_SPRITE_SIZES: usize = 32;
+
+/// スプライトシート内の 1 枚分の配置。
+#[derive(Debug, Clone, Serialize)]
+pub struct SpriteFrame {
+    /// リクエストされたサイズ (正方形のバウンディングボックス)
+    pub size: u32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// スプライトシートの生成結果。
+#[derive(Debug)]
+pub struct SpriteOutput {
+    pub bytes: Bytes,
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<SpriteFrame>,
+}
+
+/// 複数サイズにリサイズした画像を横一列に並べた PNG スプライトシートを生成する。
+///
+/// 各サイズは `size x size` のバウンディングボックスに contain で収める。
+pub fn render_sprite(
+    input: &Bytes,
+    sizes: &[u32],
+    limits: &Limits,
+    config: &TransformConfig,
+) -> Result<SpriteOutput, TransformError> {
+    if sizes.is_empty() || sizes.len() > MAX_SPRITE_SIZES {
+        return Err(TransformError::InvalidParams(format!(
+            "sizes must contain 1-{MAX_SPRITE_SIZES} values, got {}",
+            sizes.len()
+        )));
+    }
+    if let Some(&size) = sizes.iter().find(|&&s| s == 0 || s > MAX_DIMENSION) {
+        return Err(TransformError::InvalidParams(format!(
+            "each size must be 1-{MAX_DIMENSION}, got {size}"
+        )));
+    }
+
+    // 禁止された変換はデコード前にマジックバイトから判別したフォーマットで弾く
+    config.check_conversion(image::guess_format(input).ok(), OutputFormat::Png)?;
+
+    let decoded = decode_image(input, limits)?;
+    let mut img = decoded.image;
+    if let Some(orientation) = decoded.orientation {
+        img.apply_orientation(orientation);
+    }
+    let (src_w, src_h) = (img.width(), img.height());
+
+    validate_source_dimensions(src_w, src_h, limits)?;
+
+    // 先にレイアウトを計算し、シート全体のサイズを検証してからリサイズする
+    let mut frames = Vec::with_capacity(sizes.len());
+    let mut sheet_w: u32 = 0;
+    let mut sheet_h: u32 = 0;
+    for &size in sizes {
+        let (w, h) = calculate_contain_dimensions(src_w, src_h, Some(size), Some(size));
+        frames.push(SpriteFrame {
+            size,
+            x: sheet_w,
+            y: 0,
+            width: w,
+            height: h,
+        });
+        sheet_w = sheet_w.saturating_add(w);
+        sheet_h = sheet_h.max(h);
+    }
+    if sheet_w > MAX_DIMENSION || sheet_h > MAX_DIMENSION {
+        return Err(TransformError::OutputDimensionsTooLarge {
+            width: sheet_w,
+            height: sheet_h,
+        });
+    }
+
+    let settings = ResizeSettings {
+        premultiply_alpha: img.color().has_alpha(),
+        downscale_filter: config.downscale_filter,
+        upscale_filter: config.upscale_filter,
+        ..ResizeSettings::default()
+    };
+    let mut sheet = RgbaImage::new(sheet_w, sheet_h);
+    for frame in &frames {
+        let resized = if frame.width == src_w && frame.height == src_h {
+            img.to_rgba8()
+        } else {
+            resize_image(&img, frame.width, frame.height, settings, None)?.into_rgba8()
+        };
+        imageops::overlay(&mut sheet, &resized, frame.x as i64, frame.y as i64);
+    }
+
+    let options = EncodeOptions {
+        quality: resolve_quality(OutputFormat::Png, None)?,
+        subsampling: None,
+        progressive: false,
+        metadata: EmbeddedMetadata::default(),
+        near_lossless: None,
+        prefer_palette: false,
+        encoder: config.encoder,
+        colors: None,
+    };
+    let output_bytes = encode_image(
+        &DynamicImage::ImageRgba8(sheet),
+        OutputFormat::Png,
+        &options,
+    )?;
+
+    Ok(SpriteOutput {
+        bytes: Bytes::from(output_bytes),
+        width: sheet_w,
+        height: sheet_h,
+        frames,
+    })
+}
@@ -1,12 +1,34 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use aws_config::Region;
+use aws_config::default_provider::credentials::DefaultCredentialsChain;
 use aws_credential_types::Credentials;
+use aws_credential_types::provider::SharedCredentialsProvider;
 use aws_sdk_s3::Client;
+use aws_sdk_s3::config::timeout::TimeoutConfig;
+use aws_sdk_s3::error::ProvideErrorMetadata;
+use aws_smithy_http_client::tls;
 use bytes::Bytes;
+use tokio::sync::Semaphore;
 
 #[derive(Clone)]
 pub struct R2Client {
     client: Client,
     bucket_name: String,
+    /// R2 への同時リクエスト数の上限。None の場合は無制限
+    connection_limit: Option<Arc<Semaphore>>,
+    /// ボディの読み込みのタイムアウト。None の場合は無制限
+    read_timeout: Option<Duration>,
+}
+
+/// R2 から取得したオブジェクト。
+pub struct StoredObject {
+    pub body: Bytes,
+    /// Range 指定で取得した場合の Content-Range (例: `bytes 0-1023/4096`)
+    pub content_range: Option<String>,
+    /// R2 に保存されている Content-Type
+    pub content_type: Option<String>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -17,77 +39,173 @@ pub enum StorageError {
     #[error("object too large: {size} bytes (max: {max} bytes)")]
     TooLarge { size: u64, max: u64 },
 
+    #[error("range not satisfiable: {range}")]
+    InvalidRange { range: String },
+
+    #[error("timed out reading object body: {key}")]
+    Timeout { key: String },
+
     #[error("storage error: {0}")]
     Internal(String),
 }
 
 /// 最大入力ファイルサイズ: 10MB
-const MAX_INPUT_SIZE: u64 = 10 * 1024 * 1024;
+pub const MAX_INPUT_SIZE: u64 = 10 * 1024 * 1024;
+/// R2 への接続タイムアウトのデフォルト (ms)
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 3_000;
+/// アイドル状態の keep-alive 接続を保持する秒数のデフォルト (hyper のデフォルトと同じ)
+const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+/// R2 への同時リクエスト数のデフォルト
+const DEFAULT_MAX_CONNECTIONS: u64 = 64;
+/// ボディの読み込みのタイムアウトのデフォルト (ms)
+const DEFAULT_READ_TIMEOUT_MS: u64 = 30_000;
 
 impl R2Client {
     /// 環境変数から R2Client を作成する。
     ///
     /// 必須の環境変数:
     /// - R2_ENDPOINT
-    /// - R2_ACCESS_KEY_ID
-    /// - R2_SECRET_ACCESS_KEY
     /// - R2_BUCKET_NAME
+    ///
+    /// 認証情報は R2_ACCESS_KEY_ID / R2_SECRET_ACCESS_KEY を優先し、
+    /// どちらも未設定の場合は AWS の標準プロバイダチェーン
+    /// (AWS_SHARED_CREDENTIALS_FILE / AWS_PROFILE 等) から取得する。
+    ///
+    /// 接続設定 (任意):
+    /// - R2_CONNECT_TIMEOUT_MS: 接続タイムアウト (デフォルト: 3000)
+    /// - R2_POOL_IDLE_TIMEOUT_SECS: keep-alive 接続のアイドル保持秒数 (デフォルト: 90)
+    /// - R2_MAX_CONNECTIONS: 同時リクエスト数の上限、0 で無制限 (デフォルト: 64)
+    /// - R2_READ_TIMEOUT_MS: ボディの読み込みのタイムアウト、0 で無制限 (デフォルト: 30000)
     pub async fn from_env() -> Result<Self, String> {
         let endpoint =
             std::env::var("R2_ENDPOINT").map_err(|_| "R2_ENDPOINT is not set".to_string())?;
-        let access_key_id = std::env::var("R2_ACCESS_KEY_ID")
-            .map_err(|_| "R2_ACCESS_KEY_ID is not set".to_string())?;
-        let secret_access_key = std::env::var("R2_SECRET_ACCESS_KEY")
-            .map_err(|_| "R2_SECRET_ACCESS_KEY is not set".to_string())?;
         let bucket_name =
             std::env::var("R2_BUCKET_NAME").map_err(|_| "R2_BUCKET_NAME is not set".to_string())?;
 
-        let credentials = Credentials::new(
-            access_key_id,
-            secret_access_key,
-            None, // セッショントークン
-            None, // 有効期限
-            "r2-env",
-        );
+        let credentials = match (
+            std::env::var("R2_ACCESS_KEY_ID"),
+            std::env::var("R2_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(access_key_id), Ok(secret_access_key)) => {
+                SharedCredentialsProvider::new(Credentials::new(
+                    access_key_id,
+                    secret_access_key,
+                    None, // セッショントークン
+                    None, // 有効期限
+                    "r2-env",
+                ))
+            }
+            (Err(_), Err(_)) => {
+                tracing::info!("R2 credentials not set in env, using default credential chain");
+                SharedCredentialsProvider::new(DefaultCredentialsChain::builder().build().await)
+            }
+            (Err(_), Ok(_)) => return Err("R2_ACCESS_KEY_ID is not set".to_string()),
+            (Ok(_), Err(_)) => return Err("R2_SECRET_ACCESS_KEY is not set".to_string()),
+        };
+
+        let connect_timeout = env_u64("R2_CONNECT_TIMEOUT_MS", DEFAULT_CONNECT_TIMEOUT_MS)?;
+        let pool_idle_timeout =
+            env_u64("R2_POOL_IDLE_TIMEOUT_SECS", DEFAULT_POOL_IDLE_TIMEOUT_SECS)?;
+        let max_connections = env_u64("R2_MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS)?;
+        let read_timeout = env_u64("R2_READ_TIMEOUT_MS", DEFAULT_READ_TIMEOUT_MS)?;
+
+        let http_client = aws_smithy_http_client::Builder::new()
+            .pool_idle_timeout(Duration::from_secs(pool_idle_timeout))
+            .tls_provider(tls::Provider::Rustls(
+                tls::rustls_provider::CryptoMode::AwsLc,
+            ))
+            .build_https();
+        let timeout_config = TimeoutConfig::builder()
+            .connect_timeout(Duration::from_millis(connect_timeout))
+            .build();
 
         let config = aws_sdk_s3::config::Builder::new()
             .endpoint_url(&endpoint)
             .region(Region::new("auto"))
             .credentials_provider(credentials)
+            .http_client(http_client)
+            .timeout_config(timeout_config)
             .force_path_style(true)
             .behavior_version_latest()
             .build();
 
         let client = Client::from_conf(config);
 
+        let connection_limit = match max_connections {
+            0 => None,
+            n => Some(Arc::new(Semaphore::new(n as usize))),
+        };
+
         Ok(Self {
             client,
             bucket_name,
+            connection_limit,
+            read_timeout: (read_timeout > 0).then(|| Duration::from_millis(read_timeout)),
         })
     }
 
+    /// 接続と認証情報を共有し、取得先のバケットだけを差し替えたクライアントを返す。
+    ///
+    /// 同時リクエスト数の上限もバケット間で共有する。
+    pub fn with_bucket(&self, bucket_name: &str) -> Self {
+        Self {
+            bucket_name: bucket_name.to_string(),
+            ..self.clone()
+        }
+    }
+
     /// キーを指定して R2 からオブジェクトを取得する。
     ///
+    /// `version` を指定した場合はそのバージョンを取得する (未指定時は最新)。
+    /// `range` には HTTP の Range ヘッダ値 (例: `bytes=0-1023`) をそのまま渡す。
     /// content_length が返る場合は事前にサイズをチェックし、
     /// ない場合も読み込み後にサイズをチェックしてメモリ枯渇を防ぐ。
-    pub async fn get_object(&self, key: &str) -> Result<Bytes, StorageError> {
+    pub async fn get_object(
+        &self,
+        key: &str,
+        version: Option<&str>,
+        range: Option<&str>,
+    ) -> Result<StoredObject, StorageError> {
+        // ボディの読み込み完了まで接続を占有するため、関数の終わりまで permit を保持する
+        let _permit = match &self.connection_limit {
+            Some(limit) => Some(
+                limit
+                    .acquire()
+                    .await
+                    .map_err(|e| StorageError::Internal(e.to_string()))?,
+            ),
+            None => None,
+        };
+
         let output = self
             .client
             .get_object()
             .bucket(&self.bucket_name)
             .key(key)
+            .set_version_id(version.map(str::to_string))
+            .set_range(range.map(str::to_string))
             .send()
             .await
             .map_err(|e| {
-                if e.as_service_error().is_some_and(|se| se.is_no_such_key()) {
+                let service_error = e.as_service_error();
+                if service_error
+                    .is_some_and(|se| se.is_no_such_key() || se.code() == Some("NoSuchVersion"))
+                {
                     StorageError::NotFound {
                         key: key.to_string(),
                     }
+                } else if service_error.is_some_and(|se| se.code() == Some("InvalidRange")) {
+                    StorageError::InvalidRange {
+                        range: range.unwrap_or_default().to_string(),
+                    }
                 } else {
                     StorageError::Internal(e.to_string())
                 }
             })?;
 
+        let content_range = output.content_range().map(str::to_string);
+        let content_type = output.content_type().map(str::to_string);
+
         // content_length があれば事前チェック
         if let Some(size) = output.content_length().filter(|&s| s > 0) {
             let size = size as u64;
@@ -99,11 +217,9 @@ impl R2Client {
             }
         }
 
-        let data = output
-            .body
-            .collect()
-            .await
-            .map_err(|e| StorageError::Internal(e.to_string()))?
+        // ヘッダの受信後にボディが途中で止まった場合も、接続を占有し続けないよう打ち切る
+        let data = read_body(output.body.collect(), self.read_timeout, key)
+            .await?
             .into_bytes();
 
         // content_length がない場合も、読み込み後にサイズを確認
@@ -115,6 +231,75 @@ impl R2Client {
             });
         }
 
-        Ok(data)
+        Ok(StoredObject {
+            body: data,
+            content_range,
+            content_type,
+        })
+    }
+}
+
+/// ボディの読み込みを待つ。`read_timeout` を過ぎても終わらない場合は `StorageError::Timeout`。
+async fn read_body<T, E: std::fmt::Display>(
+    collect: impl Future<Output = Result<T, E>>,
+    read_timeout: Option<Duration>,
+    key: &str,
+) -> Result<T, StorageError> {
+    let collected = match read_timeout {
+        Some(timeout) => {
+            tokio::time::timeout(timeout, collect)
+                .await
+                .map_err(|_| StorageError::Timeout {
+                    key: key.to_string(),
+                })?
+        }
+        None => collect.await,
+    };
+    collected.map_err(|e| StorageError::Internal(e.to_string()))
+}
+
+/// 数値の環境変数を読み取る。未設定の場合はデフォルト値、不正な値はエラー。
+fn env_u64(name: &str, default: u64) -> Result<u64, String> {
+    match std::env::var(name) {
+        Ok(v) => v
+            .parse()
+            .map_err(|e| format!("invalid {name} value '{v}': {e}")),
+        Err(_) => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use futures::{StreamExt, TryStreamExt, stream};
+
+    use super::*;
+
+    /// 先頭のチャンクを返した後、`stall` が true なら止まったままになるボディ
+    fn body(stall: bool) -> impl Future<Output = Result<Vec<Bytes>, Infallible>> {
+        let head = stream::iter([Ok(Bytes::from_static(b"head"))]);
+        let rest = if stall {
+            stream::pending().boxed()
+        } else {
+            stream::empty().boxed()
+        };
+        head.chain(rest).try_collect()
+    }
+
+    #[tokio::test]
+    async fn stalled_body_times_out() {
+        let timeout = Some(Duration::from_millis(20));
+        let err = read_body(body(true), timeout, "photo.jpg")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StorageError::Timeout { key } if key == "photo.jpg"));
+    }
+
+    #[tokio::test]
+    async fn complete_body_is_returned_within_timeout() {
+        let timeout = Some(Duration::from_millis(1_000));
+        let chunks = read_body(body(false), timeout, "photo.jpg").await.unwrap();
+        assert_eq!(chunks, [Bytes::from_static(b"head")]);
     }
 }
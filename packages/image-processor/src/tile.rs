@@ -0,0 +1,192 @@
+use bytes::Bytes;
+
+use crate::transform::{
+    AbandonedAvifEncodes, EmbeddedMetadata, EncodeOptions, Limits, OutputFormat, Region,
+    ResizeSettings, TransformConfig, TransformError, TransformOutput, decode_image,
+    determine_output_format, encode_output, resize_image, resolve_quality, validate_quality,
+    validate_source_dimensions,
+};
+
+pub const DEFAULT_TILE_SIZE: u32 = 256;
+const MAX_TILE_SIZE: u32 = 1024;
+
+/// Deep Zoom (DZI) 形式のタイル指定。
+#[derive(Debug, Clone)]
+pub struct TileParams {
+    pub level: u32,
+    pub x: u32,
+    pub y: u32,
+    pub size: u32,
+    pub format: Option<OutputFormat>,
+    pub quality: Option<f32>,
+    /// クライアントが AVIF を表示できない。AVIF になる出力は WebP に置き換える
+    pub avif_unsupported: bool,
+}
+
+/// Deep Zoom ピラミッドから 1 枚のタイルを切り出す。
+///
+/// 最大レベルは原寸 (`ceil(log2(max(w, h)))`)、レベルが 1 下がるごとに縦横 1/2 になる。
+/// タイル間のオーバーラップはなし。
+pub fn render_tile(
+    input: &Bytes,
+    params: &TileParams,
+    limits: &Limits,
+    config: &TransformConfig,
+    abandoned_avif: &AbandonedAvifEncodes,
+) -> Result<TransformOutput, TransformError> {
+    validate_quality(params.quality)?;
+    if params.size == 0 || params.size > MAX_TILE_SIZE {
+        return Err(TransformError::InvalidParams(format!(
+            "size must be 1-{MAX_TILE_SIZE}, got {}",
+            params.size
+        )));
+    }
+
+    // 禁止された変換はデコード前にマジックバイトから判別したフォーマットで弾く
+    // (デコード時も同じ判別結果を使う)
+    let guessed_format = image::guess_format(input).ok();
+    let output_format =
+        determine_output_format(guessed_format, params.format, None, params.avif_unsupported);
+    config.check_conversion(guessed_format, output_format)?;
+    // WebP はロスレス固定のため、AVIF から置き換えた場合は AVIF 用の q を使わない
+    let requested_quality = params.quality.filter(|_| {
+        output_format == determine_output_format(guessed_format, params.format, None, false)
+    });
+    config.check_quality(output_format, requested_quality)?;
+    let quality = resolve_quality(output_format, requested_quality)?;
+
+    let decoded = decode_image(input, limits)?;
+    let source_format = decoded.format;
+    let mut img = decoded.image;
+    if let Some(orientation) = decoded.orientation {
+        img.apply_orientation(orientation);
+    }
+    let (src_w, src_h) = (img.width(), img.height());
+
+    validate_source_dimensions(src_w, src_h, limits)?;
+
+    let max_level = max_level(src_w, src_h);
+    if params.level > max_level {
+        return Err(TransformError::InvalidParams(format!(
+            "z must be 0-{max_level}, got {}",
+            params.level
+        )));
+    }
+
+    // 該当レベルの画像サイズ
+    let divisor = 1u32 << (max_level - params.level);
+    let (level_w, level_h) = (src_w.div_ceil(divisor), src_h.div_ceil(divisor));
+
+    let (cols, rows) = (level_w.div_ceil(params.size), level_h.div_ceil(params.size));
+    if params.x >= cols || params.y >= rows {
+        return Err(TransformError::InvalidParams(format!(
+            "tile ({}, {}) out of range for z={} ({cols}x{rows} tiles)",
+            params.x, params.y, params.level
+        )));
+    }
+
+    // タイルの範囲 (レベル座標系)。右端・下端のタイルは size より小さくなる
+    let tile_x = params.x * params.size;
+    let tile_y = params.y * params.size;
+    let tile_w = params.size.min(level_w - tile_x);
+    let tile_h = params.size.min(level_h - tile_y);
+
+    // レベル座標系からソース座標系へ変換して切り出す
+    let scale_x = src_w as f64 / level_w as f64;
+    let scale_y = src_h as f64 / level_h as f64;
+    let region = Region {
+        left: tile_x as f64 * scale_x,
+        top: tile_y as f64 * scale_y,
+        width: tile_w as f64 * scale_x,
+        height: tile_h as f64 * scale_y,
+    };
+    let settings = ResizeSettings {
+        premultiply_alpha: img.color().has_alpha(),
+        downscale_filter: config.downscale_filter,
+        upscale_filter: config.upscale_filter,
+        ..ResizeSettings::default()
+    };
+    let tile = resize_image(&img, tile_w, tile_h, settings, Some(region))?;
+
+    let options = EncodeOptions {
+        quality,
+        subsampling: None,
+        progressive: false,
+        metadata: EmbeddedMetadata::default(),
+        near_lossless: None,
+        prefer_palette: false,
+        encoder: config.encoder,
+        colors: None,
+    };
+    let (output_bytes, output_format) = encode_output(
+        &tile,
+        output_format,
+        &options,
+        limits,
+        config,
+        abandoned_avif,
+    )?;
+
+    Ok(TransformOutput {
+        bytes: Bytes::from(output_bytes),
+        content_type: output_format.content_type(),
+        width: tile_w,
+        height: tile_h,
+        source_format,
+        quality: config.min_quality(output_format).map(|_| quality),
+    })
+}
+
+/// 原寸に対応するピラミッドの最大レベル (`ceil(log2(max(w, h)))`) を返す。
+fn max_level(width: u32, height: u32) -> u32 {
+    let longest = width.max(height);
+    if longest <= 1 {
+        0
+    } else {
+        u32::BITS - (longest - 1).leading_zeros()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ImageFormat;
+
+    use super::*;
+    use crate::transform::fixtures::truncated_png;
+
+    #[test]
+    fn forbidden_conversion_is_rejected_before_decoding() {
+        // デコードすると CorruptImage になる途切れた PNG でも、変換の禁止が先に返る
+        let config = TransformConfig {
+            forbidden_conversions: vec![(ImageFormat::Png, OutputFormat::Jpeg)],
+            ..TransformConfig::default()
+        };
+        let params = TileParams {
+            level: 0,
+            x: 0,
+            y: 0,
+            size: DEFAULT_TILE_SIZE,
+            format: Some(OutputFormat::Jpeg),
+            quality: None,
+            avif_unsupported: false,
+        };
+        let err = render_tile(
+            &truncated_png(),
+            &params,
+            &Limits::default(),
+            &config,
+            &AbandonedAvifEncodes::default(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                TransformError::ConversionForbidden {
+                    from: ImageFormat::Png,
+                    to: OutputFormat::Jpeg
+                }
+            ),
+            "{err:?}"
+        );
+    }
+}
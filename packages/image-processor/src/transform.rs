@@ -1,18 +1,88 @@
 use bytes::Bytes;
 use fast_image_resize::images::Image;
-use fast_image_resize::{PixelType, ResizeAlg, ResizeOptions, Resizer};
-use image::codecs::avif::AvifEncoder;
+use fast_image_resize::{
+    FilterType, PixelType, ResizeAlg, ResizeOptions, Resizer, create_srgb_mapper,
+};
 use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
 use image::codecs::webp::WebPEncoder;
-use image::{DynamicImage, ImageFormat, ImageReader};
+use image::metadata::Orientation;
+use image::{
+    DynamicImage, ImageDecoder, ImageEncoder, ImageError, ImageFormat, ImageReader, Rgba,
+    RgbaImage, imageops,
+};
+use jpeg_encoder::SamplingFactor;
+use ravif::{BitDepth, Img, RGBA8};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::Cursor;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone)]
+use crate::detect::{
+    SniffedFormat, avif_is_sequence, is_animated, jpeg_has_eoi, png_is_indexed, sniff_format,
+};
+use crate::quantize::{MAX_COLORS, MIN_COLORS, quantize};
+
+#[derive(Debug, Clone, Default)]
 pub struct TransformParams {
     pub width: Option<u32>,
     pub height: Option<u32>,
     pub format: Option<OutputFormat>,
-    pub quality: Option<u8>,
+    /// 1-100。JPEG は整数に丸め、AVIF は小数のまま使う
+    pub quality: Option<f32>,
+    /// リサイズ時のアルファ乗算の上書き (デバッグ用)。None の場合はアルファチャンネルの有無で決める。
+    pub premultiply_alpha: Option<bool>,
+    /// リニア光でリサイズする (画質優先)
+    pub linear_resize: bool,
+    pub mask: Option<Mask>,
+    pub subsampling: Option<ChromaSubsampling>,
+    /// プログレッシブ JPEG で出力する (JPEG のみ)
+    pub progressive: bool,
+    pub orient: Option<Orient>,
+    /// キーの拡張子から推測した出力フォーマット。ソースのフォーマットが判別できない場合に使う
+    pub extension_hint: Option<OutputFormat>,
+    /// 出力に残すメタデータ。None の場合は `Strip::All` と同じ
+    pub strip: Option<Strip>,
+    /// 出力バイト数の上限。指定時は上限に収まる最大の品質を探索する (JPEG/AVIF のみ)
+    pub max_bytes: Option<usize>,
+    /// `q` (省略時のデフォルトを含む) の解釈
+    pub quality_mode: QualityMode,
+    pub fit: Fit,
+    /// ソースより大きく拡大しない
+    pub no_upscale: bool,
+    /// `fit=fill` のパディング色 (RGBA)。None の場合は透明 (JPEG では白)
+    pub background: Option<[u8; 4]>,
+    /// `fit=fill` で切り取る際に出力に残す領域。None の場合は中央で切り取る
+    pub roi: Option<Roi>,
+    /// リサイズ後にぼかす領域
+    pub blur_region: Option<BlurRegion>,
+    /// WebP の near-lossless 前処理レベル (0-100, 100 で無効)
+    pub near_lossless: Option<u8>,
+    /// エンコード前に変換する出力のカラータイプ。None の場合は変換結果のまま
+    pub channels: Option<Channels>,
+    /// ソースの幅 (向き適用後) の下限。下回る場合は変換せずに 422
+    pub min_source_width: Option<u32>,
+    /// エンコード速度の上書き。出力フォーマットに関係する項目のみ指定できる
+    pub encoder: EncoderConfig,
+    /// 減色後の色数 (2-256)。PNG 出力のみ
+    pub colors: Option<u16>,
+    /// 変更がなくても再エンコードする (メタデータの削除や壊れたファイルの正規化のため)
+    pub force_reencode: bool,
+    /// 最も暗い値を黒、最も明るい値を白に引き伸ばす (オートレベル)
+    pub normalize: bool,
+    /// 想定する表示密度の倍率 (1-4)。PNG の pHYs に 72dpi x 倍率を書き込む。
+    /// `w`/`h` への乗算は呼び出し側で済ませておく
+    pub retina: Option<u8>,
+    /// 変換結果が原本より大きい場合に原本を返す (`auto=compress`)。比較は呼び出し側で行う
+    pub auto_compress: bool,
+    /// 途中で切れた JPEG を失敗させず、読み取れたスキャンまででデコードする
+    pub allow_partial: bool,
+    /// クライアントが AVIF を表示できない。AVIF になる出力は WebP に置き換える
+    pub avif_unsupported: bool,
 }
 
 impl TransformParams {
@@ -21,6 +91,594 @@ impl TransformParams {
             || self.height.is_some()
             || self.format.is_some()
             || self.quality.is_some()
+            || self.mask.is_some()
+            || self.subsampling.is_some()
+            || self.progressive
+            || self.orient.is_some()
+            // 原本はメタデータをすべて保持しているため、削除を指定された場合のみ変換が必要
+            || self.strip.is_some_and(|s| s != Strip::None)
+            || self.max_bytes.is_some()
+            || self.near_lossless.is_some()
+            || self.channels.is_some()
+            // 原本をそのまま返すと検証できないため、変換経路に乗せる
+            || self.min_source_width.is_some()
+            || self.encoder != EncoderConfig::default()
+            || self.colors.is_some()
+            || self.force_reencode
+            || self.normalize
+            || self.retina.is_some()
+            || self.blur_region.is_some()
+    }
+
+    /// AVIF から WebP に置き換える場合に、WebP で使えない AVIF 用の指定
+    /// (`q` `max_bytes` `avif_speed`) を取り除いたパラメータを返す。
+    fn without_avif_settings(&self) -> Self {
+        Self {
+            quality: None,
+            max_bytes: None,
+            encoder: EncoderConfig {
+                avif_speed: None,
+                ..self.encoder
+            },
+            ..self.clone()
+        }
+    }
+}
+
+/// 画像の向きの扱い。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Orient {
+    /// EXIF の Orientation に従って回転・反転する
+    #[default]
+    Auto,
+    /// EXIF を無視する (原本に向きが焼き込み済みの場合)
+    None,
+    /// EXIF を無視し、指定角度 (時計回り) で回転する
+    Rotate(u16),
+}
+
+impl Orient {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(Self::Auto),
+            "none" => Some(Self::None),
+            "0" | "90" | "180" | "270" => s.parse().ok().map(Self::Rotate),
+            _ => None,
+        }
+    }
+
+    /// 適用すべき変換を返す。`hint` はデコーダが提供したソースの向き (`DecodedImage::orientation`)。
+    fn orientation(self, hint: Option<Orientation>) -> Option<Orientation> {
+        match self {
+            Self::Auto => hint,
+            Self::None => None,
+            Self::Rotate(90) => Some(Orientation::Rotate90),
+            Self::Rotate(180) => Some(Orientation::Rotate180),
+            Self::Rotate(270) => Some(Orientation::Rotate270),
+            Self::Rotate(_) => None,
+        }
+    }
+}
+
+/// JPEG のクロマサブサンプリング。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    S420,
+    S422,
+    S444,
+}
+
+impl ChromaSubsampling {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s {
+            "420" => Some(Self::S420),
+            "422" => Some(Self::S422),
+            "444" => Some(Self::S444),
+            _ => None,
+        }
+    }
+
+    fn sampling_factor(self) -> SamplingFactor {
+        match self {
+            Self::S420 => SamplingFactor::R_4_2_0,
+            Self::S422 => SamplingFactor::R_4_2_2,
+            Self::S444 => SamplingFactor::R_4_4_4,
+        }
+    }
+}
+
+/// PNG の圧縮レベル。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl PngCompression {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s {
+            "fast" => Some(Self::Fast),
+            "default" => Some(Self::Default),
+            "best" => Some(Self::Best),
+            _ => None,
+        }
+    }
+
+    fn image_compression(self) -> CompressionType {
+        match self {
+            Self::Fast => CompressionType::Fast,
+            Self::Default => CompressionType::Default,
+            Self::Best => CompressionType::Best,
+        }
+    }
+
+    fn png_compression(self) -> png::Compression {
+        match self {
+            Self::Fast => png::Compression::Fast,
+            Self::Default => png::Compression::Balanced,
+            Self::Best => png::Compression::High,
+        }
+    }
+}
+
+/// PNG の行フィルタ。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    /// 行ごとに最適なフィルタを選ぶ
+    Adaptive,
+}
+
+impl PngFilter {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(Self::None),
+            "sub" => Some(Self::Sub),
+            "up" => Some(Self::Up),
+            "avg" => Some(Self::Avg),
+            "paeth" => Some(Self::Paeth),
+            "adaptive" => Some(Self::Adaptive),
+            _ => None,
+        }
+    }
+
+    fn image_filter(self) -> PngFilterType {
+        match self {
+            Self::None => PngFilterType::NoFilter,
+            Self::Sub => PngFilterType::Sub,
+            Self::Up => PngFilterType::Up,
+            Self::Avg => PngFilterType::Avg,
+            Self::Paeth => PngFilterType::Paeth,
+            Self::Adaptive => PngFilterType::Adaptive,
+        }
+    }
+
+    fn png_filter(self) -> png::Filter {
+        match self {
+            Self::None => png::Filter::NoFilter,
+            Self::Sub => png::Filter::Sub,
+            Self::Up => png::Filter::Up,
+            Self::Avg => png::Filter::Avg,
+            Self::Paeth => png::Filter::Paeth,
+            Self::Adaptive => png::Filter::Adaptive,
+        }
+    }
+}
+
+/// フォーマットごとのエンコード速度 (処理時間と出力サイズのトレードオフ) の設定。
+///
+/// 環境変数のデフォルト (`TransformConfig::encoder`) をリクエストごとの指定
+/// (`TransformParams::encoder`) で上書きして使う。None の項目は従来のデフォルトのまま。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncoderConfig {
+    /// AVIF のエンコード速度 (1-10、小さいほど遅く高圧縮)。None の場合は 4
+    pub avif_speed: Option<u8>,
+    /// PNG の圧縮レベル。None の場合は `fast`
+    pub png_compression: Option<PngCompression>,
+    /// PNG の行フィルタ。None の場合は `adaptive`
+    pub png_filter: Option<PngFilter>,
+    /// WebP の method (0-6、大きいほど遅く高圧縮)。
+    /// 指定時は libwebp でエンコードする (メタデータは埋め込まない)。None の場合は image の WebP エンコーダ
+    pub webp_method: Option<u8>,
+}
+
+impl EncoderConfig {
+    /// `overrides` で指定された項目を上書きした設定を返す。
+    pub fn with_overrides(self, overrides: EncoderConfig) -> Self {
+        Self {
+            avif_speed: overrides.avif_speed.or(self.avif_speed),
+            png_compression: overrides.png_compression.or(self.png_compression),
+            png_filter: overrides.png_filter.or(self.png_filter),
+            webp_method: overrides.webp_method.or(self.webp_method),
+        }
+    }
+
+    /// 環境変数の値 (未設定は None) から設定を作る。不正な値はエラー。
+    pub fn parse(
+        avif_speed: Option<&str>,
+        png_compression: Option<&str>,
+        png_filter: Option<&str>,
+        webp_method: Option<&str>,
+    ) -> Result<Self, String> {
+        let number = |name: &str, value: Option<&str>| {
+            value
+                .map(|v| {
+                    v.trim()
+                        .parse::<u8>()
+                        .map_err(|e| format!("invalid {name} '{v}': {e}"))
+                })
+                .transpose()
+        };
+        let config = Self {
+            avif_speed: number("avif_speed", avif_speed)?,
+            png_compression: png_compression
+                .map(|v| {
+                    PngCompression::from_str_param(v.trim())
+                        .ok_or_else(|| format!("unknown png_compression '{v}'"))
+                })
+                .transpose()?,
+            png_filter: png_filter
+                .map(|v| {
+                    PngFilter::from_str_param(v.trim())
+                        .ok_or_else(|| format!("unknown png_filter '{v}'"))
+                })
+                .transpose()?,
+            webp_method: number("webp_method", webp_method)?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 範囲外の値があればエラーを返す。
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(speed) = self.avif_speed
+            && !(1..=10).contains(&speed)
+        {
+            return Err(format!("avif_speed must be 1-10, got {speed}"));
+        }
+        if let Some(method) = self.webp_method
+            && method > 6
+        {
+            return Err(format!("webp_method must be 0-6, got {method}"));
+        }
+        Ok(())
+    }
+
+    /// `format` の出力に関係しない項目が指定されていれば、その名前を返す。
+    fn unsupported_for(&self, format: OutputFormat) -> Option<&'static str> {
+        [
+            ("avif_speed", self.avif_speed.is_some(), OutputFormat::Avif),
+            (
+                "png_compression",
+                self.png_compression.is_some(),
+                OutputFormat::Png,
+            ),
+            ("png_filter", self.png_filter.is_some(), OutputFormat::Png),
+            (
+                "webp_method",
+                self.webp_method.is_some(),
+                OutputFormat::WebP,
+            ),
+        ]
+        .into_iter()
+        .find(|&(_, set, target)| set && target != format)
+        .map(|(name, _, _)| name)
+    }
+}
+
+/// `q` の解釈。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QualityMode {
+    /// エンコーダの品質値としてそのまま使う
+    #[default]
+    Raw,
+    /// JPEG の品質を基準とし、他のフォーマットでは見た目の画質がおおよそそろう値に変換する
+    Perceptual,
+}
+
+impl QualityMode {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s {
+            "raw" => Some(Self::Raw),
+            "perceptual" => Some(Self::Perceptual),
+            _ => None,
+        }
+    }
+
+    /// JPEG 相当の品質を `format` のエンコーダに渡す品質に変換する。
+    ///
+    /// 品質を持たないフォーマット (PNG, ロスレスの WebP) と JPEG はそのまま返す。
+    pub fn map(self, format: OutputFormat, quality: f32) -> f32 {
+        match (self, format) {
+            (Self::Perceptual, OutputFormat::Avif) => {
+                interpolate(&AVIF_PERCEPTUAL_QUALITY, quality)
+            }
+            _ => quality,
+        }
+    }
+}
+
+/// `points` (x の昇順) を線形補間する。範囲外は端の値を使う。
+fn interpolate(points: &[(f32, f32)], x: f32) -> f32 {
+    let (first, last) = (points[0], points[points.len() - 1]);
+    if x <= first.0 {
+        return first.1;
+    }
+    points.windows(2).find(|w| x <= w[1].0).map_or(last.1, |w| {
+        let ((x0, y0), (x1, y1)) = (w[0], w[1]);
+        y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+    })
+}
+
+/// 切り取り時に出力に残す重要な領域 (顔の位置など)。
+///
+/// 向き適用後のソースに対する 0-1 の相対座標で表す。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Roi {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Roi {
+    /// `x,y,w,h` 形式を解釈する。
+    ///
+    /// 各値が 0-1 でない場合、幅・高さが 0 の場合、領域が画像の外にはみ出す場合は None。
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        let values: Vec<f64> = s
+            .split(',')
+            .map(|v| v.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        let [x, y, width, height] = values[..] else {
+            return None;
+        };
+        let in_range = |v: f64| (0.0..=1.0).contains(&v);
+        let valid = [x, y, width, height].into_iter().all(in_range)
+            && width > 0.0
+            && height > 0.0
+            && x + width <= 1.0
+            && y + height <= 1.0;
+        valid.then_some(Self {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// ぼかして隠す矩形領域 (顔・ナンバープレート・身分証等)。
+///
+/// リサイズ後の出力に対する座標で表す。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlurRegion {
+    /// 0-1 の相対座標
+    Relative(Roi),
+    /// ピクセル座標
+    Pixels {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl BlurRegion {
+    /// `x,y,w,h` (相対座標) または `x,y,w,hpx` (ピクセル座標) 形式を解釈する。
+    ///
+    /// 単位は末尾の `px` で明示し、値の大きさからは推測しない (`0,0,1,1px` は 1px 四方)。
+    /// 相対座標は `Roi` と同じ検証、ピクセル座標は 0 以上の整数で幅・高さが 1 以上。
+    /// ピクセル座標が出力に収まるかはリサイズ後に検証する。
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        let Some(pixels) = s.trim_end().strip_suffix("px") else {
+            return Roi::from_str_param(s).map(Self::Relative);
+        };
+        let values: Vec<u32> = pixels
+            .split(',')
+            .map(|v| v.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        let [x, y, width, height] = values[..] else {
+            return None;
+        };
+        (width > 0 && height > 0).then_some(Self::Pixels {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    /// `width`x`height` の画像上のピクセルの矩形 (x, y, w, h) を返す。はみ出す場合は None
+    fn to_pixels(self, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+        match self {
+            Self::Relative(roi) => {
+                let scale = |v: f64, size: u32| (v * size as f64).round() as u32;
+                let (x, y) = (scale(roi.x, width), scale(roi.y, height));
+                // 丸めで幅が 0 にならないよう 1px 以上にし、画像内に収める
+                let w = scale(roi.x + roi.width, width).saturating_sub(x).max(1);
+                let h = scale(roi.y + roi.height, height).saturating_sub(y).max(1);
+                let (x, y) = (
+                    x.min(width.saturating_sub(w)),
+                    y.min(height.saturating_sub(h)),
+                );
+                (w <= width && h <= height).then_some((x, y, w, h))
+            }
+            Self::Pixels {
+                x,
+                y,
+                width: w,
+                height: h,
+            } => {
+                (x.checked_add(w)? <= width && y.checked_add(h)? <= height).then_some((x, y, w, h))
+            }
+        }
+    }
+}
+
+/// `w` / `h` を指定した場合の収め方。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fit {
+    /// アスペクト比を保って `w`x`h` の内側に収める (出力は指定より小さくなりうる)
+    #[default]
+    Contain,
+    /// 常にちょうど `w`x`h` を出力する。cover で拡縮してはみ出しを切り取り、
+    /// 拡大が抑制されて覆いきれない場合は背景色でパディングする
+    Fill,
+    /// contain と同じだが、ソースより大きく拡大しない
+    Inside,
+    /// アスペクト比を保ち、両辺が `w`x`h` 以上になる最小サイズにする (切り取りなし)
+    Outside,
+}
+
+impl Fit {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s {
+            "contain" => Some(Self::Contain),
+            "fill" => Some(Self::Fill),
+            "inside" => Some(Self::Inside),
+            "outside" => Some(Self::Outside),
+            _ => None,
+        }
+    }
+}
+
+/// 出力画像に残すメタデータの範囲。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strip {
+    /// すべて削除する
+    #[default]
+    All,
+    /// 出力フォーマットが対応する限り残す (ICC プロファイル, EXIF)
+    None,
+    /// ICC プロファイルのみ残し、EXIF 等は削除する
+    Metadata,
+}
+
+impl Strip {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s {
+            "all" => Some(Self::All),
+            "none" => Some(Self::None),
+            "metadata" => Some(Self::Metadata),
+            _ => None,
+        }
+    }
+
+    /// デコード時に読み取ったメタデータから、出力に残す分を選び出す。
+    fn select(self, metadata: EmbeddedMetadata) -> EmbeddedMetadata {
+        match self {
+            Self::All => EmbeddedMetadata::default(),
+            Self::Metadata => EmbeddedMetadata {
+                icc_profile: metadata.icc_profile,
+                exif: None,
+            },
+            Self::None => {
+                // 向きはピクセルに適用済みのため、ビューアが再度回転しないよう EXIF から消す
+                let exif = metadata.exif.map(|mut exif| {
+                    let _ = Orientation::remove_from_exif_chunk(&mut exif);
+                    exif
+                });
+                EmbeddedMetadata {
+                    icc_profile: metadata.icc_profile,
+                    exif,
+                }
+            }
+        }
+    }
+}
+
+/// 出力画像のカラータイプ。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channels {
+    Rgb,
+    Rgba,
+    Gray,
+    GrayAlpha,
+}
+
+impl Channels {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s {
+            "rgb" => Some(Self::Rgb),
+            "rgba" => Some(Self::Rgba),
+            "gray" => Some(Self::Gray),
+            "graya" => Some(Self::GrayAlpha),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Rgb => "rgb",
+            Self::Rgba => "rgba",
+            Self::Gray => "gray",
+            Self::GrayAlpha => "graya",
+        }
+    }
+
+    fn has_alpha(self) -> bool {
+        matches!(self, Self::Rgba | Self::GrayAlpha)
+    }
+
+    /// 出力フォーマットがこのカラータイプのまま保存できるかを返す。
+    ///
+    /// JPEG はアルファを持てず、WebP/AVIF はグレースケールを持てない。
+    fn is_supported_by(self, format: OutputFormat) -> bool {
+        match format {
+            OutputFormat::Png => true,
+            OutputFormat::Jpeg => !self.has_alpha(),
+            OutputFormat::WebP | OutputFormat::Avif => matches!(self, Self::Rgb | Self::Rgba),
+        }
+    }
+
+    fn apply(self, img: DynamicImage) -> DynamicImage {
+        match self {
+            Self::Rgb => DynamicImage::ImageRgb8(img.into_rgb8()),
+            Self::Rgba => DynamicImage::ImageRgba8(img.into_rgba8()),
+            Self::Gray => DynamicImage::ImageLuma8(img.into_luma8()),
+            Self::GrayAlpha => DynamicImage::ImageLumaA8(img.into_luma_alpha8()),
+        }
+    }
+}
+
+/// 画像に埋め込まれたメタデータ。
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddedMetadata {
+    pub icc_profile: Option<Vec<u8>>,
+    /// TIFF ヘッダから始まる生の EXIF チャンク (`Exif\0\0` は含まない)
+    pub exif: Option<Vec<u8>>,
+}
+
+/// 出力画像に適用するアルファマスク。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mask {
+    /// 画像中央に内接する円
+    Circle,
+    /// 角丸矩形 (radius は px、短辺の半分を上限とする)
+    Rounded { radius: u32 },
+}
+
+impl Mask {
+    /// ピクセル中心 (px, py) がマスク内に含まれる割合 (0.0-1.0) を返す。
+    ///
+    /// 境界は 1px 幅でアンチエイリアスする。
+    fn coverage(&self, px: f64, py: f64, width: f64, height: f64) -> f64 {
+        let max_radius = width.min(height) / 2.0;
+        let (radius, cx, cy) = match self {
+            Self::Circle => (max_radius, width / 2.0, height / 2.0),
+            Self::Rounded { radius } => {
+                let r = (*radius as f64).min(max_radius);
+                (r, px.clamp(r, width - r), py.clamp(r, height - r))
+            }
+        };
+        let distance = (px - cx).hypot(py - cy);
+        (radius - distance + 0.5).clamp(0.0, 1.0)
     }
 }
 
@@ -33,6 +691,8 @@ pub enum OutputFormat {
 }
 
 impl OutputFormat {
+    pub const ALL: [Self; 4] = [Self::Jpeg, Self::Png, Self::WebP, Self::Avif];
+
     pub fn from_str_param(s: &str) -> Option<Self> {
         match s.to_lowercase().as_str() {
             "jpg" | "jpeg" => Some(Self::Jpeg),
@@ -43,6 +703,25 @@ impl OutputFormat {
         }
     }
 
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    /// ファイル名に使う拡張子
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
     pub fn content_type(&self) -> &'static str {
         match self {
             Self::Jpeg => "image/jpeg",
@@ -58,87 +737,822 @@ pub enum TransformError {
     #[error("invalid parameters: {0}")]
     InvalidParams(String),
 
+    /// ソースの総ピクセル数が `Limits::max_pixels` (`MAX_SOURCE_PIXELS` / `TRUSTED_MAX_PIXELS`) を超える
+    #[error("image resolution {width}x{height} exceeds the maximum of {max_pixels} pixels")]
+    ResolutionTooLarge {
+        width: u32,
+        height: u32,
+        max_pixels: u64,
+    },
+
+    /// 出力の幅・高さが `MAX_DIMENSION` を超える
+    #[error(
+        "output resolution exceeds maximum ({width}x{height} > {MAX_DIMENSION}x{MAX_DIMENSION})"
+    )]
+    OutputDimensionsTooLarge { width: u32, height: u32 },
+
     #[error(
-        "image resolution exceeds maximum ({width}x{height} > {MAX_DIMENSION}x{MAX_DIMENSION})"
+        "quality parameter is not supported for {format:?} (lossless only). use f=jpg or f=avif for lossy output"
     )]
-    ResolutionTooLarge { width: u32, height: u32 },
+    QualityNotSupported { format: OutputFormat },
+
+    #[error("conversion from {from:?} to {to:?} is not allowed")]
+    ConversionForbidden { from: ImageFormat, to: OutputFormat },
+
+    #[error("quality {quality} is below the minimum {min} for {format:?}")]
+    QualityBelowMinimum {
+        format: OutputFormat,
+        quality: f32,
+        min: f32,
+    },
+
+    #[error("source width {width}px is below the required minimum {min}px")]
+    SourceTooSmall { width: u32, min: u32 },
+
+    #[error("upscale factor {factor:.2} exceeds the maximum {max}")]
+    UpscaleTooLarge { factor: f64, max: f64 },
+
+    #[error("cannot fit output within {max_bytes} bytes (smallest: {smallest} bytes)")]
+    BudgetUnreachable { max_bytes: usize, smallest: usize },
+
+    #[error("output size {size} bytes exceeds the maximum {max} bytes")]
+    OutputTooLarge { size: usize, max: usize },
+
+    #[error("empty or non-image object ({size} bytes)")]
+    EmptyInput { size: usize },
+
+    #[error("animated AVIF is not supported")]
+    AnimatedAvif,
+
+    #[error("decode memory limit exceeded: {0}")]
+    DecodeLimitExceeded(String),
+
+    #[error("image data appears truncated or corrupt: {0}")]
+    CorruptImage(String),
+
+    #[error("too many timed-out AVIF encodes are still running ({running})")]
+    AvifEncodersSaturated { running: usize },
+
+    #[error("request deadline exceeded before {stage}")]
+    DeadlineExceeded { stage: &'static str },
 
     #[error("transform failed: {0}")]
     ProcessingFailed(String),
 }
 
-const MAX_DIMENSION: u32 = 4096;
-const MAX_PIXELS: u64 = 16_777_216; // 4096 * 4096
-const DEFAULT_QUALITY: u8 = 80;
+pub const MAX_DIMENSION: u32 = 4096;
+/// ソースの総ピクセル数の上限のデフォルト。出力は `MAX_DIMENSION` で別に制限する
+pub const DEFAULT_MAX_SOURCE_PIXELS: u64 = 16_777_216; // 4096 * 4096
+pub const DEFAULT_TRUSTED_MAX_PIXELS: u64 = 67_108_864; // 8192 * 8192
+/// image crate のデフォルト (512MiB) と同じ
+pub const DEFAULT_DECODE_MEMORY_LIMIT: u64 = 512 * 1024 * 1024;
+const DEFAULT_QUALITY: f32 = 80.0;
+const AVIF_SPEED: u8 = 4;
+/// `quality_mode=perceptual` での JPEG の品質 → AVIF (ravif) の品質のおおよその対応表。
+/// 同じ見た目の画質なら AVIF の方が低い品質値で足りる
+const AVIF_PERCEPTUAL_QUALITY: [(f32, f32); 6] = [
+    (1.0, 1.0),
+    (50.0, 45.0),
+    (70.0, 60.0),
+    (80.0, 68.0),
+    (90.0, 80.0),
+    (100.0, 100.0),
+];
+/// `fit=fill` のデフォルトのパディング色。透明だが、アルファを持たない JPEG では白になる
+const DEFAULT_BACKGROUND: [u8; 4] = [255, 255, 255, 0];
+/// `blur_region` のぼかしの強さ (σ) = 領域の長辺 / この値
+const BLUR_REGION_SIGMA_DIVISOR: f32 = 8.0;
+const MIN_BLUR_REGION_SIGMA: f32 = 4.0;
+/// `retina` の倍率の上限
+pub const MAX_RETINA: u8 = 4;
+/// `retina` の等倍 (1x) の解像度
+const BASE_DPI: f64 = 72.0;
+const METERS_PER_INCH: f64 = 0.0254;
+/// `on_error=image` で返すプレースホルダの色
+const PLACEHOLDER_COLOR: [u8; 3] = [224, 224, 224];
+/// `max_bytes` 指定時のエンコード試行回数の上限 (CPU 使用量を抑えるため)
+const MAX_BUDGET_ENCODES: usize = 7;
+/// これ未満のバイト列はどの画像フォーマットのシグネチャも含み得ない
+const MIN_INPUT_SIZE: usize = 8;
+
+/// ソース画像に適用する上限値。
+///
+/// 通常のリクエストは `Limits::default()` 相当を使い、信頼済みの内部リクエストのみ緩い上限を使う。
+/// 出力サイズはこれとは独立に `MAX_DIMENSION` で制限するため、大きなソースの縮小だけを許可できる。
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// ソースの総ピクセル数の上限。ヘッダで分かる場合はデコード前に検証する
+    pub max_pixels: u64,
+    /// デコード時に確保できるメモリの上限 (bytes)。超えた時点でデコードを中断する
+    pub decode_memory_limit: u64,
+    /// リクエスト全体の期限。デコード・リサイズ・エンコードの各段階の前に確認し、過ぎていれば中断する
+    pub deadline: Option<Instant>,
+}
+
+impl Limits {
+    /// 期限を過ぎていれば `stage` を実行せずにエラーを返す。
+    pub fn check_deadline(&self, stage: &'static str) -> Result<(), TransformError> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(TransformError::DeadlineExceeded { stage })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_pixels: DEFAULT_MAX_SOURCE_PIXELS,
+            decode_memory_limit: DEFAULT_DECODE_MEMORY_LIMIT,
+            deadline: None,
+        }
+    }
+}
+
+/// 運用者が環境変数で設定する変換ポリシー。
+#[derive(Debug, Clone, Default)]
+pub struct TransformConfig {
+    /// 禁止する変換 (ソース → 出力)
+    pub forbidden_conversions: Vec<(ImageFormat, OutputFormat)>,
+    /// JPEG で明示指定できる最小品質 (これ未満は 400)
+    pub min_quality_jpeg: f32,
+    /// AVIF で明示指定できる最小品質 (これ未満は 400)
+    pub min_quality_avif: f32,
+    /// `q` 省略時の品質を出力ピクセル数から決める曲線 (ピクセル数の昇順)。空の場合は一律 80
+    pub quality_curve: Vec<(u64, f32)>,
+    /// 縮小時のリサンプリングフィルタ (デフォルト: Lanczos3)
+    pub downscale_filter: FilterType,
+    /// 拡大時のリサンプリングフィルタ (デフォルト: Lanczos3)
+    pub upscale_filter: FilterType,
+    /// エンコード後の出力バイト数の上限 (超えると 422)。None の場合は無制限
+    pub max_output_bytes: Option<usize>,
+    /// ソースに対する拡大率の上限 (超えると 400)。None の場合は無制限
+    pub max_upscale: Option<f64>,
+    /// AVIF エンコードの制限時間。超えた場合は WebP で返す。None の場合は無制限
+    pub avif_encode_timeout: Option<Duration>,
+    /// 制限時間を超えた後もバックグラウンドで動き続けている AVIF エンコードの数の上限。
+    /// 達している間は制限時間付きの AVIF エンコードを始めずに 503 とする。0 の場合は無制限
+    pub max_abandoned_avif_encodes: usize,
+    /// エンコード速度のデフォルト。リクエストの指定で上書きされる
+    pub encoder: EncoderConfig,
+    /// エンコード結果のマジックバイトが出力フォーマットと一致するかを検証する (エンコーダの退行の検出用)
+    pub verify_output: bool,
+}
+
+impl TransformConfig {
+    /// `png>jpeg,gif>webp` 形式の禁止変換リストを解釈する。
+    pub fn parse_forbidden_conversions(
+        value: &str,
+    ) -> Result<Vec<(ImageFormat, OutputFormat)>, String> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let (source, target) = rule.split_once('>').ok_or_else(|| {
+                    format!("invalid conversion rule '{rule}' (expected src>dst)")
+                })?;
+                let source = ImageFormat::from_extension(source.trim())
+                    .ok_or_else(|| format!("unknown source format in rule '{rule}'"))?;
+                let target = OutputFormat::from_str_param(target.trim())
+                    .ok_or_else(|| format!("unknown output format in rule '{rule}'"))?;
+                Ok((source, target))
+            })
+            .collect()
+    }
+
+    /// `16384:90,1000000:75,16777216:60` 形式 (ピクセル数:品質) の品質曲線を解釈する。
+    pub fn parse_quality_curve(value: &str) -> Result<Vec<(u64, f32)>, String> {
+        let curve = value
+            .split(',')
+            .map(str::trim)
+            .filter(|point| !point.is_empty())
+            .map(|point| {
+                let (pixels, quality) = point
+                    .split_once(':')
+                    .ok_or_else(|| format!("invalid curve point '{point}' (expected pixels:q)"))?;
+                let pixels: u64 = pixels
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|&p| p > 0)
+                    .ok_or_else(|| format!("invalid pixel count in curve point '{point}'"))?;
+                let quality: f32 = quality
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|q| (1.0..=100.0).contains(q))
+                    .ok_or_else(|| format!("quality must be 1-100 in curve point '{point}'"))?;
+                Ok((pixels, quality))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        if curve.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err("curve points must be in strictly increasing pixel order".to_string());
+        }
+        Ok(curve)
+    }
+
+    /// リサンプリングフィルタ名 (`lanczos3`, `catmullrom` 等) を解釈する。
+    pub fn parse_filter(name: &str) -> Result<FilterType, String> {
+        match name.trim().to_lowercase().as_str() {
+            "box" => Ok(FilterType::Box),
+            "bilinear" => Ok(FilterType::Bilinear),
+            "hamming" => Ok(FilterType::Hamming),
+            "catmullrom" => Ok(FilterType::CatmullRom),
+            "mitchell" => Ok(FilterType::Mitchell),
+            "gaussian" => Ok(FilterType::Gaussian),
+            "lanczos3" => Ok(FilterType::Lanczos3),
+            other => Err(format!(
+                "unknown filter '{other}'. supported: box, bilinear, hamming, catmullrom, mitchell, gaussian, lanczos3"
+            )),
+        }
+    }
+
+    /// `q` 省略時の品質を出力ピクセル数から求める。
+    ///
+    /// 曲線の点の間はピクセル数の対数で線形補間し、範囲外は端の値を使う。
+    pub fn default_quality(&self, pixels: u64) -> f32 {
+        let (Some(&first), Some(&last)) = (self.quality_curve.first(), self.quality_curve.last())
+        else {
+            return DEFAULT_QUALITY;
+        };
+        if pixels <= first.0 {
+            return first.1;
+        }
+        if pixels >= last.0 {
+            return last.1;
+        }
+
+        let x = (pixels as f64).log2();
+        self.quality_curve
+            .windows(2)
+            .find(|w| pixels <= w[1].0)
+            .map_or(last.1, |w| {
+                let (x0, q0) = ((w[0].0 as f64).log2(), w[0].1 as f64);
+                let (x1, q1) = ((w[1].0 as f64).log2(), w[1].1 as f64);
+                (q0 + (q1 - q0) * (x - x0) / (x1 - x0)) as f32
+            })
+    }
+
+    /// 明示指定された品質が出力フォーマットの下限を下回っていないかを検証する。
+    pub fn check_quality(
+        &self,
+        format: OutputFormat,
+        requested: Option<f32>,
+    ) -> Result<(), TransformError> {
+        let Some(min) = self.min_quality(format) else {
+            return Ok(());
+        };
+        match requested {
+            Some(quality) if quality < min => Err(TransformError::QualityBelowMinimum {
+                format,
+                quality,
+                min,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// 出力フォーマットの品質の下限を返す。品質を持たないフォーマットは None。
+    pub fn min_quality(&self, format: OutputFormat) -> Option<f32> {
+        match format {
+            OutputFormat::Jpeg => Some(self.min_quality_jpeg),
+            OutputFormat::Avif => Some(self.min_quality_avif),
+            OutputFormat::Png | OutputFormat::WebP => None,
+        }
+    }
+
+    /// ソースに対する拡大率が上限を超えていないかを検証する。
+    ///
+    /// 拡大率は幅・高さの倍率の大きい方。縮小や等倍は常に許可する。
+    pub fn check_upscale(
+        &self,
+        (src_w, src_h): (u32, u32),
+        (dst_w, dst_h): (u32, u32),
+    ) -> Result<(), TransformError> {
+        let Some(max) = self.max_upscale else {
+            return Ok(());
+        };
+        let factor = (dst_w as f64 / src_w as f64).max(dst_h as f64 / src_h as f64);
+        if factor > max {
+            return Err(TransformError::UpscaleTooLarge { factor, max });
+        }
+        Ok(())
+    }
+
+    /// ソース → 出力の変換が許可されているかを検証する。
+    pub fn check_conversion(
+        &self,
+        source: Option<ImageFormat>,
+        target: OutputFormat,
+    ) -> Result<(), TransformError> {
+        let Some(source) = source else {
+            return Ok(());
+        };
+        if self.forbidden_conversions.contains(&(source, target)) {
+            return Err(TransformError::ConversionForbidden {
+                from: source,
+                to: target,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// 変換結果。
+#[derive(Debug)]
+pub struct TransformOutput {
+    pub bytes: Bytes,
+    pub content_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    /// デコード時に判別したソースフォーマット
+    pub source_format: Option<ImageFormat>,
+    /// エンコードに使った品質 (JPEG/AVIF のみ)
+    pub quality: Option<f32>,
+}
+
+/// エンコード前の変換結果。
+pub struct PreparedImage {
+    /// デコード・リサイズ・マスク等の加工を済ませた画像
+    pub image: DynamicImage,
+    pub output_format: OutputFormat,
+    pub source_format: Option<ImageFormat>,
+    /// 出力に残すメタデータ
+    metadata: EmbeddedMetadata,
+    /// AVIF から WebP に置き換えた場合は AVIF 用の指定を取り除いたパラメータ
+    params: TransformParams,
+}
 
 /// 指定されたパラメータに従って画像バイト列を変換する。
 ///
-/// メタデータ (EXIF/XMP) はデコード・エンコードサイクルで削除される。
-/// (変換後バイト列, content_type) を返す。
+/// メタデータは `params.strip` に従って残す (デフォルトではすべて削除される)。
 pub fn transform(
     input: &Bytes,
     params: &TransformParams,
-) -> Result<(Bytes, &'static str), TransformError> {
+    limits: &Limits,
+    config: &TransformConfig,
+    abandoned_avif: &AbandonedAvifEncodes,
+) -> Result<TransformOutput, TransformError> {
+    let PreparedImage {
+        image: resized,
+        mut output_format,
+        source_format,
+        metadata,
+        params,
+    } = prepare_image(input, params, limits, config)?;
+    let params = &params;
+
+    let mut quality = resolve_quality(output_format, params.quality)?;
+    // 品質を持つフォーマットで q 省略時は、出力サイズに応じたデフォルトを使う
+    if params.quality.is_none() && config.min_quality(output_format).is_some() {
+        quality = config.default_quality(resized.width() as u64 * resized.height() as u64);
+    }
+    // 下限の検証は変換前の値で行う (変換後の値は利用者が指定したものではないため)
+    let quality = params.quality_mode.map(output_format, quality);
+
+    let mut options = EncodeOptions {
+        quality,
+        subsampling: params.subsampling,
+        progressive: params.progressive,
+        metadata,
+        near_lossless: params.near_lossless,
+        // インデックスカラーの PNG は RGBA に展開するとサイズが膨らむため、可能ならパレットに戻す
+        // カラータイプが指定された場合はそれに従う
+        prefer_palette: params.channels.is_none()
+            && source_format == Some(ImageFormat::Png)
+            && png_is_indexed(input),
+        encoder: config.encoder.with_overrides(params.encoder),
+        colors: params.colors,
+    };
+    limits.check_deadline("encode")?;
+    let encode = |options: &EncodeOptions| {
+        encode_image_timed(
+            &resized,
+            output_format,
+            options,
+            limits,
+            config,
+            abandoned_avif,
+        )
+    };
+    let output_bytes = match params.max_bytes {
+        Some(max_bytes) => {
+            let min_quality = config.min_quality(output_format).ok_or_else(|| {
+                TransformError::InvalidParams(format!(
+                    "max_bytes parameter is not supported for {output_format:?} (JPEG/AVIF only)"
+                ))
+            })?;
+            encode_within_budget(&options, max_bytes, min_quality, limits, encode)?.map(
+                |(bytes, quality)| {
+                    options.quality = quality;
+                    bytes
+                },
+            )
+        }
+        None => encode(&options)?,
+    };
+    let output_bytes = match output_bytes {
+        Some(bytes) => bytes,
+        None => {
+            output_format = OutputFormat::WebP;
+            encode_avif_fallback(&resized, &options, limits)?
+        }
+    };
+    let output_bytes = match params.retina {
+        Some(retina) => insert_png_density(output_bytes, retina),
+        None => output_bytes,
+    };
+    if config.verify_output {
+        verify_output_format(&output_bytes, output_format)?;
+    }
+    let content_type = output_format.content_type();
+
+    if let Some(max) = config.max_output_bytes
+        && output_bytes.len() > max
+    {
+        tracing::warn!(
+            size = output_bytes.len(),
+            max,
+            params = ?params,
+            output_format = ?output_format,
+            "encoded output exceeds MAX_OUTPUT_BYTES"
+        );
+        return Err(TransformError::OutputTooLarge {
+            size: output_bytes.len(),
+            max,
+        });
+    }
+
+    Ok(TransformOutput {
+        bytes: Bytes::from(output_bytes),
+        content_type,
+        width: resized.width(),
+        height: resized.height(),
+        source_format,
+        quality: config.min_quality(output_format).map(|_| options.quality),
+    })
+}
+
+/// デコードからリサイズ・マスク等の加工までを行い、エンコード前の画像を返す。
+///
+/// 出力フォーマットとそれに対応しないパラメータの検証はデコード前に行う。
+/// ピクセルだけが必要な場合 (代表色の抽出等) はエンコードを省くためにこちらを使う。
+pub fn prepare_image(
+    input: &Bytes,
+    params: &TransformParams,
+    limits: &Limits,
+    config: &TransformConfig,
+) -> Result<PreparedImage, TransformError> {
     validate_params(params)?;
 
-    let (img, source_format) = decode_image(input)?;
+    // 低解像度の原本はデコード前にヘッダだけで弾く。読み取れない場合はデコード時のエラーに任せる
+    if let Some(min) = params.min_source_width
+        && let Some((width, _)) = header_dimensions(input, params.orient.unwrap_or_default())
+        && width < min
+    {
+        return Err(TransformError::SourceTooSmall { width, min });
+    }
+
+    // 上限を超えるソースはデコード (メモリ確保) 前にヘッダだけで弾く
+    if let Some((width, height)) = header_dimensions(input, Orient::None) {
+        validate_source_dimensions(width, height, limits)?;
+    }
+
+    // 禁止された変換はデコード前にマジックバイトから判別したフォーマットで弾く
+    // (デコード時も同じ判別結果を使う)
+    let guessed_format = image::guess_format(input).ok();
+    let mut output_format = determine_output_format(
+        guessed_format,
+        params.format,
+        params.extension_hint,
+        params.avif_unsupported,
+    );
+    // WebP はロスレス固定のため、AVIF から置き換えた場合は AVIF 用の指定を使わない
+    let downgraded;
+    let params = if output_format
+        != determine_output_format(guessed_format, params.format, params.extension_hint, false)
+    {
+        downgraded = params.without_avif_settings();
+        &downgraded
+    } else {
+        params
+    };
+    if params.mask.is_some() {
+        output_format = alpha_capable_format(output_format, params.format.is_some())?;
+    }
+    config.check_conversion(guessed_format, output_format)?;
+    // 出力フォーマットに対応しない指定はデコード前に弾く
+    validate_output_params(params, output_format, config)?;
+
+    limits.check_deadline("decode")?;
+    let decoded = decode_image_with(input, limits, params.allow_partial)?;
+    let source_format = decoded.format;
+    let metadata = params.strip.unwrap_or_default().select(decoded.metadata);
+    let mut img = decoded.image;
+    let orient = params.orient.unwrap_or_default();
+    if let Some(orientation) = orient.orientation(decoded.orientation) {
+        img.apply_orientation(orientation);
+    }
     let (src_w, src_h) = (img.width(), img.height());
 
-    validate_source_dimensions(src_w, src_h)?;
+    validate_source_dimensions(src_w, src_h, limits)?;
+    limits.check_deadline("resize")?;
 
-    let (dst_w, dst_h) = calculate_contain_dimensions(src_w, src_h, params.width, params.height);
-    validate_output_dimensions(dst_w, dst_h)?;
+    // fill のパディング色を範囲の計算に含めないよう、リサイズ前に適用する
+    if params.normalize {
+        normalize_levels(&mut img);
+    }
 
-    let resized = if dst_w != src_w || dst_h != src_h {
-        resize_image(&img, dst_w, dst_h)?
-    } else {
-        img
+    let settings = ResizeSettings {
+        premultiply_alpha: params
+            .premultiply_alpha
+            .unwrap_or_else(|| img.color().has_alpha()),
+        linear_light: params.linear_resize,
+        downscale_filter: config.downscale_filter,
+        upscale_filter: config.upscale_filter,
     };
 
-    let output_format = determine_output_format(source_format, params.format);
+    let resized = match (params.fit, params.width, params.height) {
+        (Fit::Fill, Some(width), Some(height)) => {
+            validate_output_dimensions(width, height)?;
+            // cover の拡大率は幅・高さの倍率の大きい方と一致する。
+            // 拡大しない場合は足りない分をパディングで埋めるため検証不要
+            if !params.no_upscale {
+                config.check_upscale((src_w, src_h), (width, height))?;
+            }
+            let background = params.background.unwrap_or(DEFAULT_BACKGROUND);
+            fill_image(
+                img,
+                width,
+                height,
+                params.no_upscale,
+                background,
+                params.roi,
+                settings,
+            )?
+        }
+        // fill の片方のみの指定は validate_params で弾いている
+        _ => {
+            let (mut dst_w, mut dst_h) =
+                calculate_fit_dimensions(src_w, src_h, params.width, params.height, params.fit);
+            if params.no_upscale && (dst_w > src_w || dst_h > src_h) {
+                (dst_w, dst_h) = (src_w, src_h);
+            }
+            validate_output_dimensions(dst_w, dst_h)?;
+            config.check_upscale((src_w, src_h), (dst_w, dst_h))?;
 
-    // PNG/WebP では quality パラメータを拒否（ロスレス固定のため）
-    let quality = match output_format {
-        OutputFormat::Png | OutputFormat::WebP => {
-            if params.quality.is_some() {
-                return Err(TransformError::InvalidParams(format!(
-                    "quality parameter is not supported for {:?} (lossless only)",
-                    output_format
-                )));
+            if dst_w != src_w || dst_h != src_h {
+                resize_image(&img, dst_w, dst_h, settings, None)?
+            } else {
+                img
             }
-            DEFAULT_QUALITY
         }
-        _ => params.quality.unwrap_or(DEFAULT_QUALITY),
     };
 
-    let content_type = output_format.content_type();
-    let output_bytes = encode_image(&resized, output_format, quality)?;
+    let resized = match params.blur_region {
+        Some(region) => apply_blur_region(resized, region)?,
+        None => resized,
+    };
+
+    let resized = match params.mask {
+        Some(mask) => apply_mask(resized, mask),
+        None => resized,
+    };
+
+    let resized = match params.channels {
+        Some(channels) => channels.apply(resized),
+        None => resized,
+    };
+
+    Ok(PreparedImage {
+        image: resized,
+        output_format,
+        source_format,
+        metadata,
+        params: params.clone(),
+    })
+}
+
+/// 出力フォーマットに応じたエンコード品質を決定する。
+///
+/// PNG/WebP では quality パラメータを拒否する（ロスレス固定のため）。
+pub fn resolve_quality(
+    output_format: OutputFormat,
+    requested: Option<f32>,
+) -> Result<f32, TransformError> {
+    match output_format {
+        OutputFormat::Png | OutputFormat::WebP => {
+            if requested.is_some() {
+                return Err(TransformError::QualityNotSupported {
+                    format: output_format,
+                });
+            }
+            Ok(DEFAULT_QUALITY)
+        }
+        _ => Ok(requested.unwrap_or(DEFAULT_QUALITY)),
+    }
+}
 
-    Ok((Bytes::from(output_bytes), content_type))
+/// デコード結果。
+pub struct DecodedImage {
+    pub image: DynamicImage,
+    pub format: Option<ImageFormat>,
+    /// ソースが指定する向き。None は向きの情報なし (読み取り失敗を含む)。
+    ///
+    /// 現在は EXIF の Orientation のみだが、PDF のページ回転や RAW のメタデータ等、
+    /// フォーマット固有の向きを持つデコーダもここに入れ、`orient=auto` で同じように適用する。
+    pub orientation: Option<Orientation>,
+    pub metadata: EmbeddedMetadata,
 }
 
-/// 画像バイト列をデコードし、DynamicImage と元のフォーマットを返す。
-fn decode_image(input: &Bytes) -> Result<(DynamicImage, Option<ImageFormat>), TransformError> {
-    let reader = ImageReader::new(Cursor::new(input.as_ref()))
+/// 画像バイト列をデコードし、DynamicImage と元のフォーマット、EXIF の向きを返す。
+///
+/// 高圧縮な画像によるメモリ枯渇を防ぐため、`limits.decode_memory_limit` を超える
+/// 確保が必要になった時点でデコードを中断する。
+pub fn decode_image(input: &Bytes, limits: &Limits) -> Result<DecodedImage, TransformError> {
+    decode_image_with(input, limits, false)
+}
+
+/// `allow_partial` が true の場合、EOI のない (途中で切れた) JPEG もベストエフォートでデコードする。
+///
+/// 出力はすべて静止画のため、アニメーション WebP / APNG は 1 フレーム目をデコードする
+/// (WebP はキャンバスに合成した 1 フレーム目、APNG は IDAT のデフォルト画像)。
+///
+/// プログレッシブ JPEG では受信済みのスキャンまでの画質で、ベースライン JPEG では
+/// 受信済みの行まででデコードされ、欠けた部分はグレーになる。JPEG 以外には影響しない。
+fn decode_image_with(
+    input: &Bytes,
+    limits: &Limits,
+    allow_partial: bool,
+) -> Result<DecodedImage, TransformError> {
+    // 空オブジェクトはフォーマット推測で分かりにくいエラーになるため先に弾く
+    if input.len() < MIN_INPUT_SIZE {
+        return Err(TransformError::EmptyInput { size: input.len() });
+    }
+    // 画像シーケンスは 1 フレーム目を取り出せる保証がないため、デコード前に明示的に拒否する
+    if sniff_format(input) == Some(SniffedFormat::Avif) && avif_is_sequence(input) {
+        return Err(TransformError::AnimatedAvif);
+    }
+    if let Some(format) = sniff_format(input)
+        && is_animated(input, format)
+    {
+        tracing::debug!(
+            format = format.name(),
+            "animated source, decoding first frame"
+        );
+    }
+    // JPEG デコーダは途中で切れたデータもエラーにせず残りを埋めて返すため、明示的に検出する
+    if !allow_partial && sniff_format(input) == Some(SniffedFormat::Jpeg) && !jpeg_has_eoi(input) {
+        return Err(TransformError::CorruptImage(
+            "truncated JPEG: missing EOI marker (use allow_partial=true to decode anyway)"
+                .to_string(),
+        ));
+    }
+
+    let source_format = ImageReader::new(Cursor::new(input.as_ref()))
         .with_guessed_format()
-        .map_err(|e| TransformError::ProcessingFailed(format!("failed to guess format: {e}")))?;
+        .map_err(|e| TransformError::ProcessingFailed(format!("failed to guess format: {e}")))?
+        .format();
+
+    // フォーマットを認識できたのにデコードに失敗した場合は、データの破損・途切れとみなす
+    let map_decode_error = |e: ImageError| match (source_format, &e) {
+        (_, ImageError::Limits(_)) => TransformError::DecodeLimitExceeded(e.to_string()),
+        (Some(_), ImageError::Decoding(_) | ImageError::IoError(_)) => {
+            TransformError::CorruptImage(e.to_string())
+        }
+        _ => TransformError::ProcessingFailed(format!("decode failed: {e}")),
+    };
+
+    // デコーダの選択はキーの拡張子ではなくマジックバイトの推測による。組み込んでいるデコーダ
+    // (JPEG/PNG/WebP) はいずれも先頭のマジックバイトを要求し互いに重ならないため、推測した
+    // フォーマットで失敗したデータを他のデコーダで再試行しても成功しない
+    decode_as(input, source_format, limits).map_err(map_decode_error)
+}
+
+/// `format` のデコーダでデコードする。None の場合はフォーマットが不明のまま試す (失敗する)。
+fn decode_as(
+    input: &Bytes,
+    format: Option<ImageFormat>,
+    limits: &Limits,
+) -> Result<DecodedImage, ImageError> {
+    let mut reader = ImageReader::new(Cursor::new(input.as_ref()));
+    if let Some(format) = format {
+        reader.set_format(format);
+    }
+
+    let mut decode_limits = image::Limits::default();
+    decode_limits.max_alloc = Some(limits.decode_memory_limit);
+    reader.limits(decode_limits);
+
+    let mut decoder = reader.into_decoder()?;
+    // 壊れた EXIF で変換全体を失敗させないよう、読み取れない場合は向きを無視する
+    let orientation = orientation_hint(&mut decoder);
+    let metadata = EmbeddedMetadata {
+        icc_profile: decoder.icc_profile().ok().flatten(),
+        exif: decoder.exif_metadata().ok().flatten(),
+    };
+    let image = DynamicImage::from_decoder(decoder)?;
+
+    Ok(DecodedImage {
+        image,
+        format,
+        orientation,
+        metadata,
+    })
+}
+
+/// 変換のピークメモリ (bytes) をデコード前に見積もる。ヘッダを読み取れない場合は None。
+///
+/// デコード後のソース (RGBA 8bit 換算) とリサイズ用の変換コピー、出力バッファの合計。
+/// パラメータが不正で出力サイズを計算できない場合は、出力をソースと同じサイズとみなす。
+pub fn estimate_memory(input: &Bytes, params: &TransformParams) -> Option<u64> {
+    const BYTES_PER_PIXEL: u64 = 4;
+    let (src_w, src_h) = header_dimensions(input, params.orient.unwrap_or_default())?;
+    let (dst_w, dst_h) = output_dimensions(input, params).unwrap_or((src_w, src_h));
+    let source = src_w as u64 * src_h as u64 * BYTES_PER_PIXEL;
+    let output = dst_w as u64 * dst_h as u64 * BYTES_PER_PIXEL;
+    Some(source * 2 + output)
+}
+
+/// デコーダからソースの向きを読み取る。
+fn orientation_hint(decoder: &mut impl ImageDecoder) -> Option<Orientation> {
+    decoder.orientation().ok()
+}
 
-    let source_format = reader.format();
+/// デコード・エンコードを行わずに、変換した場合の出力サイズを返す。
+///
+/// ソースのサイズはヘッダから読み取り、`transform` と同じ fit の計算を行う。
+pub fn output_dimensions(
+    input: &Bytes,
+    params: &TransformParams,
+) -> Result<(u32, u32), TransformError> {
+    validate_params(params)?;
+    let (src_w, src_h) =
+        header_dimensions(input, params.orient.unwrap_or_default()).ok_or_else(|| {
+            TransformError::CorruptImage("failed to read image dimensions".to_string())
+        })?;
 
-    let img = reader
-        .decode()
-        .map_err(|e| TransformError::ProcessingFailed(format!("decode failed: {e}")))?;
+    let (dst_w, dst_h) = match (params.fit, params.width, params.height) {
+        // fill は拡大を抑制してもパディングで指定サイズちょうどにする
+        (Fit::Fill, Some(width), Some(height)) => (width, height),
+        _ => {
+            let (w, h) =
+                calculate_fit_dimensions(src_w, src_h, params.width, params.height, params.fit);
+            if params.no_upscale && (w > src_w || h > src_h) {
+                (src_w, src_h)
+            } else {
+                (w, h)
+            }
+        }
+    };
+    validate_output_dimensions(dst_w, dst_h)?;
+    Ok((dst_w, dst_h))
+}
 
-    Ok((img, source_format))
+/// ピクセルをデコードせずにヘッダを読み、向きを適用した後の幅と高さを返す。
+pub fn header_dimensions(input: &Bytes, orient: Orient) -> Option<(u32, u32)> {
+    let mut decoder = ImageReader::new(Cursor::new(input.as_ref()))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let (width, height) = decoder.dimensions();
+    let hint = orientation_hint(&mut decoder);
+    let swapped = matches!(
+        orient.orientation(hint),
+        Some(
+            Orientation::Rotate90
+                | Orientation::Rotate270
+                | Orientation::Rotate90FlipH
+                | Orientation::Rotate270FlipH
+        )
+    );
+    Some(if swapped {
+        (height, width)
+    } else {
+        (width, height)
+    })
 }
 
 /// ソース画像の総ピクセル数を検証し、メモリ枯渇を防ぐ。
 ///
 /// 個別の幅・高さ制限はせず、ダウンスケールを許可する。
-fn validate_source_dimensions(width: u32, height: u32) -> Result<(), TransformError> {
+pub fn validate_source_dimensions(
+    width: u32,
+    height: u32,
+    limits: &Limits,
+) -> Result<(), TransformError> {
     let total_pixels = width as u64 * height as u64;
-    if total_pixels > MAX_PIXELS {
-        return Err(TransformError::ResolutionTooLarge { width, height });
+    if total_pixels > limits.max_pixels {
+        return Err(TransformError::ResolutionTooLarge {
+            width,
+            height,
+            max_pixels: limits.max_pixels,
+        });
     }
 
     Ok(())
@@ -147,22 +1561,198 @@ fn validate_source_dimensions(width: u32, height: u32) -> Result<(), TransformEr
 /// 出力画像のサイズを検証する。
 fn validate_output_dimensions(width: u32, height: u32) -> Result<(), TransformError> {
     if width > MAX_DIMENSION || height > MAX_DIMENSION {
-        return Err(TransformError::ResolutionTooLarge { width, height });
+        return Err(TransformError::OutputDimensionsTooLarge { width, height });
     }
 
     Ok(())
 }
 
+/// ちょうど `width`x`height` の画像を生成する (`fit=fill`)。
+///
+/// cover で拡縮し、はみ出した部分は中央を残して切り取る。
+/// `no_upscale` で拡大が抑制されて覆いきれない辺は、`background` で中央寄せのパディングを入れる。
+/// 出力サイズの厳密さは拡大抑制より優先する。
+pub fn fill_image(
+    img: DynamicImage,
+    width: u32,
+    height: u32,
+    no_upscale: bool,
+    background: [u8; 4],
+    roi: Option<Roi>,
+    settings: ResizeSettings,
+) -> Result<DynamicImage, TransformError> {
+    let (src_w, src_h) = (img.width() as f64, img.height() as f64);
+    let mut scale = (width as f64 / src_w).max(height as f64 / src_h);
+    if no_upscale {
+        scale = scale.min(1.0);
+    }
+
+    // 拡縮後の画像のうち出力に収まる部分 (はみ出す辺は出力サイズで切り取る)
+    let content_w = ((src_w * scale).round() as u32).clamp(1, width);
+    let content_h = ((src_h * scale).round() as u32).clamp(1, height);
+    let crop_w = (content_w as f64 / scale).min(src_w);
+    let crop_h = (content_h as f64 / scale).min(src_h);
+    let region = Region {
+        left: crop_offset(src_w, crop_w, roi.map(|r| (r.x, r.width))),
+        top: crop_offset(src_h, crop_h, roi.map(|r| (r.y, r.height))),
+        width: crop_w,
+        height: crop_h,
+    };
+
+    let content = if content_w == img.width() && content_h == img.height() {
+        img
+    } else {
+        resize_image(&img, content_w, content_h, settings, Some(region))?
+    };
+    if content_w == width && content_h == height {
+        return Ok(content);
+    }
+
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba(background));
+    imageops::overlay(
+        &mut canvas,
+        &content.to_rgba8(),
+        ((width - content_w) / 2) as i64,
+        ((height - content_h) / 2) as i64,
+    );
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+/// 長さ `source` の辺から長さ `crop` を切り取る開始位置を返す。
+///
+/// `roi` (0-1 の相対位置と長さ) がなければ中央で切り取る。ある場合は領域の中心に合わせ、
+/// 画像の端に収まるよう寄せる。`crop` が領域より長ければ領域全体が切り取り範囲に入る。
+fn crop_offset(source: f64, crop: f64, roi: Option<(f64, f64)>) -> f64 {
+    let Some((start, length)) = roi else {
+        return (source - crop) / 2.0;
+    };
+    let center = (start + length / 2.0) * source;
+    (center - crop / 2.0).clamp(0.0, source - crop)
+}
+
+/// マスク適用時の出力フォーマットを決定する。
+///
+/// JPEG はアルファを保持できないため、明示指定ならエラー、
+/// ソース由来なら PNG に切り替える。
+fn alpha_capable_format(
+    format: OutputFormat,
+    explicitly_requested: bool,
+) -> Result<OutputFormat, TransformError> {
+    match format {
+        OutputFormat::Jpeg if explicitly_requested => Err(TransformError::InvalidParams(
+            "mask requires a format with alpha support (png, webp, avif)".to_string(),
+        )),
+        OutputFormat::Jpeg => Ok(OutputFormat::Png),
+        other => Ok(other),
+    }
+}
+
+/// 領域を切り出してぼかし、元の位置に書き戻す。
+///
+/// ぼかしの強さは領域の長辺に比例させる (小さな領域でも判読できない程度に `MIN_BLUR_REGION_SIGMA` 以上)。
+/// 領域の外の画素を混ぜないよう、切り出した画像だけをぼかす。
+fn apply_blur_region(
+    mut img: DynamicImage,
+    region: BlurRegion,
+) -> Result<DynamicImage, TransformError> {
+    let (x, y, width, height) = region.to_pixels(img.width(), img.height()).ok_or_else(|| {
+        TransformError::InvalidParams(format!(
+            "blur_region exceeds the {}x{} output",
+            img.width(),
+            img.height()
+        ))
+    })?;
+    let sigma = (width.max(height) as f32 / BLUR_REGION_SIGMA_DIVISOR).max(MIN_BLUR_REGION_SIGMA);
+    let blurred = img.crop_imm(x, y, width, height).fast_blur(sigma);
+    imageops::replace(&mut img, &blurred, x as i64, y as i64);
+    Ok(img)
+}
+
+/// ピクセルごとにアルファへマスクを乗算する。
+fn apply_mask(img: DynamicImage, mask: Mask) -> DynamicImage {
+    let mut rgba = img.into_rgba8();
+    let (width, height) = (rgba.width() as f64, rgba.height() as f64);
+
+    for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+        let coverage = mask.coverage(x as f64 + 0.5, y as f64 + 0.5, width, height);
+        pixel[3] = (pixel[3] as f64 * coverage).round() as u8;
+    }
+
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// 全画素の色チャンネルの最小値を黒、最大値を白に線形に引き伸ばす (ImageMagick の `-normalize` 相当)。
+///
+/// 色相が変わらないよう、RGB の全チャンネルで共通の最小値・最大値を使う。アルファは変更しない。
+/// すでに全範囲を使っている画像と単色の画像はそのまま。
+fn normalize_levels(img: &mut DynamicImage) {
+    match img {
+        DynamicImage::ImageLuma8(buf) => stretch_levels(buf, 1, false, 255.0, |v| v as u8),
+        DynamicImage::ImageLumaA8(buf) => stretch_levels(buf, 2, true, 255.0, |v| v as u8),
+        DynamicImage::ImageRgb8(buf) => stretch_levels(buf, 3, false, 255.0, |v| v as u8),
+        DynamicImage::ImageRgba8(buf) => stretch_levels(buf, 4, true, 255.0, |v| v as u8),
+        DynamicImage::ImageLuma16(buf) => stretch_levels(buf, 1, false, 65535.0, |v| v as u16),
+        DynamicImage::ImageLumaA16(buf) => stretch_levels(buf, 2, true, 65535.0, |v| v as u16),
+        DynamicImage::ImageRgb16(buf) => stretch_levels(buf, 3, false, 65535.0, |v| v as u16),
+        DynamicImage::ImageRgba16(buf) => stretch_levels(buf, 4, true, 65535.0, |v| v as u16),
+        DynamicImage::ImageRgb32F(buf) => stretch_levels(buf, 3, false, 1.0, |v| v),
+        DynamicImage::ImageRgba32F(buf) => stretch_levels(buf, 4, true, 1.0, |v| v),
+        other => {
+            let mut rgba = DynamicImage::ImageRgba8(other.to_rgba8());
+            normalize_levels(&mut rgba);
+            *other = rgba;
+        }
+    }
+}
+
+/// `samples` (チャンネル数 `channels` のインターリーブ) の色チャンネルを `0..=full` に引き伸ばす。
+fn stretch_levels<S: Copy + Into<f32>>(
+    samples: &mut [S],
+    channels: usize,
+    has_alpha: bool,
+    full: f32,
+    from_f32: impl Fn(f32) -> S,
+) {
+    let color_channels = if has_alpha { channels - 1 } else { channels };
+    let (min, max) = samples
+        .chunks_exact(channels)
+        .flat_map(|pixel| &pixel[..color_channels])
+        .fold((f32::MAX, f32::MIN), |(min, max), &v| {
+            let v = v.into();
+            (min.min(v), max.max(v))
+        });
+    if max <= min || (min <= 0.0 && max >= full) {
+        return;
+    }
+
+    let scale = full / (max - min);
+    for pixel in samples.chunks_exact_mut(channels) {
+        for v in &mut pixel[..color_channels] {
+            let stretched = (((*v).into() - min) * scale).clamp(0.0, full);
+            // 整数型は丸めてから変換する (`as` は切り捨てのため)
+            *v = from_f32(if full > 1.0 {
+                stretched.round()
+            } else {
+                stretched
+            });
+        }
+    }
+}
+
 /// 出力フォーマットを決定する。
 ///
 /// リクエストされたフォーマットがある場合はそれを使用し、
 /// ない場合はソースフォーマットを維持する。
-/// サポートされていないフォーマットの場合は JPEG にフォールバックする。
-fn determine_output_format(
+/// ソースが出力非対応のフォーマット (または判別不能) の場合はキーの拡張子のヒントを使い、
+/// それもなければ JPEG にフォールバックする。
+/// `avif_unsupported` の場合、AVIF になる出力 (明示指定・ソースの維持とも) は WebP に置き換える。
+pub fn determine_output_format(
     source_format: Option<ImageFormat>,
     requested_format: Option<OutputFormat>,
+    extension_hint: Option<OutputFormat>,
+    avif_unsupported: bool,
 ) -> OutputFormat {
-    requested_format.unwrap_or_else(|| {
+    let format = requested_format.unwrap_or_else(|| {
         source_format
             .and_then(|f| match f {
                 ImageFormat::Jpeg => Some(OutputFormat::Jpeg),
@@ -171,29 +1761,139 @@ fn determine_output_format(
                 ImageFormat::Avif => Some(OutputFormat::Avif),
                 _ => None,
             })
+            .or(extension_hint)
             .unwrap_or(OutputFormat::Jpeg)
-    })
+    });
+    match format {
+        OutputFormat::Avif if avif_unsupported => OutputFormat::WebP,
+        format => format,
+    }
+}
+
+/// quality パラメータの範囲 (1-100) を検証する。
+pub fn validate_quality(quality: Option<f32>) -> Result<(), TransformError> {
+    if let Some(q) = quality
+        && !(1.0..=100.0).contains(&q)
+    {
+        return Err(TransformError::InvalidParams(format!(
+            "quality must be 1-100, got {q}"
+        )));
+    }
+    Ok(())
 }
 
 fn validate_params(params: &TransformParams) -> Result<(), TransformError> {
-    if let Some(q) = params.quality {
-        if q == 0 || q > 100 {
-            return Err(TransformError::InvalidParams(format!(
-                "quality must be 1-100, got {q}"
-            )));
-        }
+    validate_quality(params.quality)?;
+    // 0 倍だと w/h が 0 になるため、サイズより先に検証する
+    if let Some(retina) = params.retina
+        && !(1..=MAX_RETINA).contains(&retina)
+    {
+        return Err(TransformError::InvalidParams(format!(
+            "retina must be 1-{MAX_RETINA}, got {retina}"
+        )));
+    }
+    if let Some(w) = params.width
+        && (w == 0 || w > MAX_DIMENSION)
+    {
+        return Err(TransformError::InvalidParams(format!(
+            "width must be 1-{MAX_DIMENSION}, got {w}"
+        )));
+    }
+    if let Some(h) = params.height
+        && (h == 0 || h > MAX_DIMENSION)
+    {
+        return Err(TransformError::InvalidParams(format!(
+            "height must be 1-{MAX_DIMENSION}, got {h}"
+        )));
+    }
+    if let Some(level) = params.near_lossless
+        && level > 100
+    {
+        return Err(TransformError::InvalidParams(format!(
+            "near_lossless must be 0-100, got {level}"
+        )));
+    }
+    // fill は切り取り・パディングで両辺をそろえるため、片方だけでは出力サイズが決まらない
+    if params.fit == Fit::Fill && (params.width.is_none() || params.height.is_none()) {
+        return Err(TransformError::InvalidParams(
+            "fit=fill requires both width and height".to_string(),
+        ));
+    }
+    if params.max_bytes == Some(0) {
+        return Err(TransformError::InvalidParams(
+            "max_bytes must be greater than 0".to_string(),
+        ));
+    }
+    if let Some(colors) = params.colors
+        && !(MIN_COLORS..=MAX_COLORS).contains(&colors)
+    {
+        return Err(TransformError::InvalidParams(format!(
+            "colors must be {MIN_COLORS}-{MAX_COLORS}, got {colors}"
+        )));
+    }
+    if params.colors.is_some() && params.channels.is_some() {
+        return Err(TransformError::InvalidParams(
+            "colors cannot be combined with channels".to_string(),
+        ));
+    }
+    params
+        .encoder
+        .validate()
+        .map_err(TransformError::InvalidParams)?;
+    Ok(())
+}
+
+/// 出力フォーマットに対応しないパラメータと、品質の下限を検証する。
+///
+/// 出力フォーマットはデコード前に決まるため、デコード・リサイズの前に呼ぶ。
+fn validate_output_params(
+    params: &TransformParams,
+    output_format: OutputFormat,
+    config: &TransformConfig,
+) -> Result<(), TransformError> {
+    config.check_quality(output_format, params.quality)?;
+    resolve_quality(output_format, params.quality)?;
+
+    let only = |name: &str, supported: &str| -> Result<(), TransformError> {
+        Err(TransformError::InvalidParams(format!(
+            "{name} parameter is not supported for {output_format:?} ({supported} only)"
+        )))
+    };
+    if params.subsampling.is_some() && output_format != OutputFormat::Jpeg {
+        return only("subsampling", "JPEG");
+    }
+    if params.progressive && output_format != OutputFormat::Jpeg {
+        return only("progressive", "JPEG");
+    }
+    if params.near_lossless.is_some() && output_format != OutputFormat::WebP {
+        return only("near_lossless", "WebP");
+    }
+    if params.retina.is_some() && output_format != OutputFormat::Png {
+        return only("retina", "PNG");
     }
-    if let Some(w) = params.width {
-        if w == 0 || w > MAX_DIMENSION {
+    if params.colors.is_some() && output_format != OutputFormat::Png {
+        return only("colors", "PNG");
+    }
+    if params.max_bytes.is_some() && config.min_quality(output_format).is_none() {
+        return only("max_bytes", "JPEG/AVIF");
+    }
+    if let Some(name) = params.encoder.unsupported_for(output_format) {
+        return Err(TransformError::InvalidParams(format!(
+            "{name} parameter is not supported for {output_format:?}"
+        )));
+    }
+    if let Some(channels) = params.channels {
+        if !channels.is_supported_by(output_format) {
             return Err(TransformError::InvalidParams(format!(
-                "width must be 1-{MAX_DIMENSION}, got {w}"
+                "channels={} is not supported for {:?}",
+                channels.name(),
+                output_format
             )));
         }
-    }
-    if let Some(h) = params.height {
-        if h == 0 || h > MAX_DIMENSION {
+        if params.mask.is_some() && !channels.has_alpha() {
             return Err(TransformError::InvalidParams(format!(
-                "height must be 1-{MAX_DIMENSION}, got {h}"
+                "mask requires channels with alpha (rgba, graya), got {}",
+                channels.name()
             )));
         }
     }
@@ -206,7 +1906,7 @@ fn validate_params(params: &TransformParams) -> Result<(), TransformError> {
 /// - h のみ: 高さに合わせて拡縮、幅は自動
 /// - 両方: バウンディングボックス内に収める（クロップやパディングなし）
 /// - どちらもなし: 元のサイズを維持
-fn calculate_contain_dimensions(
+pub fn calculate_contain_dimensions(
     src_w: u32,
     src_h: u32,
     target_w: Option<u32>,
@@ -235,11 +1935,75 @@ fn calculate_contain_dimensions(
     }
 }
 
-/// Lanczos3 フィルタを使用して fast_image_resize で DynamicImage をリサイズする。
-fn resize_image(
+/// `fit` に応じて出力サイズを計算する。
+///
+/// ソース 400x200 の場合の例:
+///
+/// | fit     | w=100, h=100 | w=800, h=800 | w=100 のみ | h=800 のみ |
+/// | ------- | ------------ | ------------ | ---------- | ---------- |
+/// | contain | 100x50       | 800x400      | 100x50     | 1600x800   |
+/// | inside  | 100x50       | 400x200      | 100x50     | 400x200    |
+/// | outside | 200x100      | 1600x800     | 100x50     | 1600x800   |
+///
+/// 各辺は倍率を掛けたあと四捨五入する (最小 1px)。outside は倍率を決めた辺がちょうど
+/// 指定値になり、もう一方の辺は指定値以上になる。片方のみの指定では outside も contain と同じ。
+/// `fit=fill` は両方の指定が必須で `fill_image` が扱うため、ここでは contain と同じ。
+pub fn calculate_fit_dimensions(
+    src_w: u32,
+    src_h: u32,
+    target_w: Option<u32>,
+    target_h: Option<u32>,
+    fit: Fit,
+) -> (u32, u32) {
+    match (fit, target_w, target_h) {
+        (Fit::Outside, Some(w), Some(h)) => {
+            let scale = (w as f64 / src_w as f64).max(h as f64 / src_h as f64);
+            let new_w = (src_w as f64 * scale).round() as u32;
+            let new_h = (src_h as f64 * scale).round() as u32;
+            (new_w.max(w), new_h.max(h))
+        }
+        (Fit::Inside, _, _) => {
+            let (new_w, new_h) = calculate_contain_dimensions(src_w, src_h, target_w, target_h);
+            if new_w > src_w || new_h > src_h {
+                (src_w, src_h)
+            } else {
+                (new_w, new_h)
+            }
+        }
+        _ => calculate_contain_dimensions(src_w, src_h, target_w, target_h),
+    }
+}
+
+/// ソース画像上の切り出し領域 (px)。
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub left: f64,
+    pub top: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// リサイズ時の設定。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResizeSettings {
+    /// 畳み込み前にアルファを乗算して透過境界の色にじみを防ぐ
+    pub premultiply_alpha: bool,
+    /// sRGB をリニア光に変換してからリサイズし、高コントラストな境界の暗転を防ぐ
+    pub linear_light: bool,
+    pub downscale_filter: FilterType,
+    pub upscale_filter: FilterType,
+}
+
+/// fast_image_resize で DynamicImage をリサイズする。
+///
+/// 出力の面積が元 (切り出し領域) より大きい場合は拡大用、それ以外は縮小用のフィルタを使う。
+/// `region` を指定した場合はその領域のみを切り出してリサイズする。
+pub fn resize_image(
     img: &DynamicImage,
     dst_w: u32,
     dst_h: u32,
+    settings: ResizeSettings,
+    region: Option<Region>,
 ) -> Result<DynamicImage, TransformError> {
     let src_rgba = img.to_rgba8();
     let (src_w, src_h) = (src_rgba.width(), src_rgba.height());
@@ -249,15 +2013,54 @@ fn resize_image(
             TransformError::ProcessingFailed(format!("failed to create source image: {e}"))
         })?;
 
-    let mut dst_fr = Image::new(dst_w, dst_h, PixelType::U8x4);
+    let (region_w, region_h) = region.map_or((src_w as f64, src_h as f64), |r| (r.width, r.height));
+    let filter = if dst_w as f64 * dst_h as f64 > region_w * region_h {
+        settings.upscale_filter
+    } else {
+        settings.downscale_filter
+    };
 
     let mut resizer = Resizer::new();
-    let options = ResizeOptions::new().resize_alg(ResizeAlg::Convolution(
-        fast_image_resize::FilterType::Lanczos3,
-    ));
-    resizer
-        .resize(&src_fr, &mut dst_fr, Some(&options))
-        .map_err(|e| TransformError::ProcessingFailed(format!("resize failed: {e}")))?;
+    let options = ResizeOptions::new()
+        .resize_alg(ResizeAlg::Convolution(filter))
+        .use_alpha(settings.premultiply_alpha);
+    let options = match region {
+        Some(r) => options.crop(r.left, r.top, r.width, r.height),
+        None => options,
+    };
+    let resize_failed = |e: fast_image_resize::ResizeError| {
+        TransformError::ProcessingFailed(format!("resize failed: {e}"))
+    };
+
+    let dst_fr = if settings.linear_light {
+        // 精度を保つため、リニア光の値は 16bit で保持する
+        let mapper = create_srgb_mapper();
+        let map_failed = |e: fast_image_resize::MappingError| {
+            TransformError::ProcessingFailed(format!("color mapping failed: {e}"))
+        };
+
+        let mut src_linear = Image::new(src_w, src_h, PixelType::U16x4);
+        mapper
+            .forward_map(&src_fr, &mut src_linear)
+            .map_err(map_failed)?;
+
+        let mut dst_linear = Image::new(dst_w, dst_h, PixelType::U16x4);
+        resizer
+            .resize(&src_linear, &mut dst_linear, Some(&options))
+            .map_err(resize_failed)?;
+
+        let mut dst_fr = Image::new(dst_w, dst_h, PixelType::U8x4);
+        mapper
+            .backward_map(&dst_linear, &mut dst_fr)
+            .map_err(map_failed)?;
+        dst_fr
+    } else {
+        let mut dst_fr = Image::new(dst_w, dst_h, PixelType::U8x4);
+        resizer
+            .resize(&src_fr, &mut dst_fr, Some(&options))
+            .map_err(resize_failed)?;
+        dst_fr
+    };
 
     let result_buf =
         image::RgbaImage::from_raw(dst_w, dst_h, dst_fr.into_vec()).ok_or_else(|| {
@@ -267,39 +2070,1071 @@ fn resize_image(
     Ok(DynamicImage::ImageRgba8(result_buf))
 }
 
+/// エンコーダに渡すオプション。
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    pub quality: f32,
+    pub subsampling: Option<ChromaSubsampling>,
+    /// プログレッシブ JPEG で出力する
+    pub progressive: bool,
+    /// 出力に埋め込むメタデータ (AVIF と near-lossless の WebP は非対応のため無視される)
+    pub metadata: EmbeddedMetadata,
+    /// WebP の near-lossless 前処理レベル。None の場合は通常のロスレス
+    pub near_lossless: Option<u8>,
+    /// PNG 出力で、色数が 256 以下ならインデックスカラーでエンコードする
+    pub prefer_palette: bool,
+    /// エンコード速度の設定
+    pub encoder: EncoderConfig,
+    /// PNG 出力で、指定した色数以下に減色してインデックスカラーでエンコードする
+    pub colors: Option<u16>,
+}
+
+/// クロマサブサンプリングやプログレッシブを指定して JPEG をエンコードする。
+fn encode_jpeg_with_options(
+    img: &DynamicImage,
+    quality: u8,
+    options: &EncodeOptions,
+    buf: &mut Cursor<Vec<u8>>,
+) -> Result<(), TransformError> {
+    let (pixels, color_type) = match img {
+        DynamicImage::ImageLuma8(gray) => (
+            Cow::Borrowed(gray.as_raw().as_slice()),
+            jpeg_encoder::ColorType::Luma,
+        ),
+        _ => (
+            Cow::Owned(img.to_rgb8().into_raw()),
+            jpeg_encoder::ColorType::Rgb,
+        ),
+    };
+    let (width, height) = match (u16::try_from(img.width()), u16::try_from(img.height())) {
+        (Ok(w), Ok(h)) => (w, h),
+        _ => {
+            return Err(TransformError::ProcessingFailed(
+                "JPEG encode failed: image too large".to_string(),
+            ));
+        }
+    };
+
+    let mut encoder = jpeg_encoder::Encoder::new(buf, quality);
+    if let Some(subsampling) = options.subsampling {
+        encoder.set_sampling_factor(subsampling.sampling_factor());
+    }
+    encoder.set_progressive(options.progressive);
+    let metadata = &options.metadata;
+    let map_err = |e| TransformError::ProcessingFailed(format!("JPEG encode failed: {e}"));
+    if let Some(icc_profile) = &metadata.icc_profile {
+        encoder.add_icc_profile(icc_profile).map_err(map_err)?;
+    }
+    if let Some(exif) = &metadata.exif {
+        encoder
+            .add_app_segment(1, [b"Exif\0\0".as_slice(), exif].concat())
+            .map_err(map_err)?;
+    }
+    encoder
+        .encode(&pixels, width, height, color_type)
+        .map_err(map_err)
+}
+
+/// image のエンコーダにメタデータを設定する。非対応のエンコーダでは無視する。
+fn set_metadata(encoder: &mut impl ImageEncoder, metadata: &EmbeddedMetadata) {
+    if let Some(icc_profile) = &metadata.icc_profile {
+        let _ = encoder.set_icc_profile(icc_profile.clone());
+    }
+    if let Some(exif) = &metadata.exif {
+        let _ = encoder.set_exif_metadata(exif.clone());
+    }
+}
+
 /// 指定されたフォーマットと品質で DynamicImage をエンコードする。
-fn encode_image(
+pub fn encode_image(
     img: &DynamicImage,
     format: OutputFormat,
-    quality: u8,
+    options: &EncodeOptions,
 ) -> Result<Vec<u8>, TransformError> {
+    // JPEG エンコーダは整数の品質のみ受け付ける
+    let jpeg_quality = options.quality.round() as u8;
     let mut buf = Cursor::new(Vec::new());
 
     match format {
-        OutputFormat::Jpeg => {
-            let encoder = JpegEncoder::new_with_quality(&mut buf, quality);
-            img.to_rgb8().write_with_encoder(encoder).map_err(|e| {
-                TransformError::ProcessingFailed(format!("JPEG encode failed: {e}"))
-            })?;
-        }
+        OutputFormat::Jpeg => match (options.subsampling, options.progressive) {
+            // image の JPEG エンコーダはサブサンプリングとプログレッシブを指定できないため jpeg-encoder を使う
+            (Some(_), _) | (_, true) => {
+                encode_jpeg_with_options(img, jpeg_quality, options, &mut buf)?
+            }
+            (None, false) => {
+                let mut encoder = JpegEncoder::new_with_quality(&mut buf, jpeg_quality);
+                set_metadata(&mut encoder, &options.metadata);
+                // グレースケールはそのまま 1 チャンネルの JPEG にする
+                match img {
+                    DynamicImage::ImageLuma8(gray) => gray.write_with_encoder(encoder),
+                    _ => img.to_rgb8().write_with_encoder(encoder),
+                }
+                .map_err(|e| {
+                    TransformError::ProcessingFailed(format!("JPEG encode failed: {e}"))
+                })?;
+            }
+        },
         OutputFormat::Png => {
-            img.write_to(&mut buf, ImageFormat::Png)
+            // 減色後は色数が 256 以下になるため、必ずインデックスカラーでエンコードできる
+            // (メタデータは埋め込まない)
+            if let Some(colors) = options.colors
+                && let Some(bytes) = encode_png_indexed(
+                    &DynamicImage::ImageRgba8(quantize(img, colors)),
+                    &options.encoder,
+                )?
+            {
+                return Ok(bytes);
+            }
+            // インデックスカラーの経路はメタデータの埋め込みに対応しない
+            if options.prefer_palette
+                && options.metadata.icc_profile.is_none()
+                && options.metadata.exif.is_none()
+                && let Some(bytes) = encode_png_indexed(img, &options.encoder)?
+            {
+                return Ok(bytes);
+            }
+            let mut encoder = PngEncoder::new_with_quality(
+                &mut buf,
+                options.encoder.png_compression.map_or(
+                    CompressionType::default(),
+                    PngCompression::image_compression,
+                ),
+                options
+                    .encoder
+                    .png_filter
+                    .map_or(PngFilterType::default(), PngFilter::image_filter),
+            );
+            set_metadata(&mut encoder, &options.metadata);
+            img.write_with_encoder(encoder)
                 .map_err(|e| TransformError::ProcessingFailed(format!("PNG encode failed: {e}")))?;
         }
-        OutputFormat::WebP => {
-            // image v0.25 の WebP エンコーダはロスレスのみ対応
-            let encoder = WebPEncoder::new_lossless(&mut buf);
-            img.write_with_encoder(encoder).map_err(|e| {
-                TransformError::ProcessingFailed(format!("WebP encode failed: {e}"))
-            })?;
-        }
+        OutputFormat::WebP => match (options.near_lossless, options.encoder.webp_method) {
+            (Some(level), method) => return encode_webp_libwebp(img, level, method),
+            // near-lossless のレベル 100 は前処理なし (通常のロスレス)
+            (None, Some(method)) => return encode_webp_libwebp(img, 100, Some(method)),
+            (None, None) => {
+                // image v0.25 の WebP エンコーダはロスレスのみ対応
+                let mut encoder = WebPEncoder::new_lossless(&mut buf);
+                set_metadata(&mut encoder, &options.metadata);
+                img.write_with_encoder(encoder).map_err(|e| {
+                    TransformError::ProcessingFailed(format!("WebP encode failed: {e}"))
+                })?;
+            }
+        },
         OutputFormat::Avif => {
-            let encoder = AvifEncoder::new_with_speed_quality(&mut buf, 4, quality);
-            img.write_with_encoder(encoder).map_err(|e| {
-                TransformError::ProcessingFailed(format!("AVIF encode failed: {e}"))
-            })?;
+            return encode_avif(img, options.quality, options.encoder.avif_speed);
         }
     }
 
     Ok(buf.into_inner())
 }
+
+/// `max_bytes` 以下に収まる最大の品質を二分探索してエンコードする。
+///
+/// 探索範囲は `min_quality` から `options.quality` までの整数。
+/// エンコード回数は `MAX_BUDGET_ENCODES` までに制限し、収まった中で最も高い品質の結果を返す。
+/// `encode` が None を返した (AVIF が制限時間内に終わらなかった) 場合は探索を打ち切り、
+/// それまでに収まった結果がなければ None を返す。
+fn encode_within_budget(
+    options: &EncodeOptions,
+    max_bytes: usize,
+    min_quality: f32,
+    limits: &Limits,
+    encode: impl Fn(&EncodeOptions) -> Result<Option<Vec<u8>>, TransformError>,
+) -> Result<Option<(Vec<u8>, f32)>, TransformError> {
+    let mut options = options.clone();
+    let ceiling = options.quality;
+
+    // 要求された品質で収まればそのまま返す
+    let Some(bytes) = encode(&options)? else {
+        return Ok(None);
+    };
+    if bytes.len() <= max_bytes {
+        return Ok(Some((bytes, ceiling)));
+    }
+
+    let mut smallest = bytes.len();
+    let mut best = None;
+    let mut timed_out = false;
+    let mut lo = min_quality.max(1.0).ceil() as u32;
+    let mut hi = (ceiling.ceil() as u32).saturating_sub(1);
+    for _ in 1..MAX_BUDGET_ENCODES {
+        if lo > hi {
+            break;
+        }
+        // 1 回のエンコードが重いため、試行ごとに期限を確認する
+        limits.check_deadline("encode")?;
+        let mid = lo + (hi - lo) / 2;
+        options.quality = mid as f32;
+        let Some(bytes) = encode(&options)? else {
+            timed_out = true;
+            break;
+        };
+        smallest = smallest.min(bytes.len());
+        if bytes.len() <= max_bytes {
+            best = Some((bytes, options.quality));
+            lo = mid + 1;
+        } else {
+            // lo は 1 以上のため mid - 1 は負にならない
+            hi = mid - 1;
+        }
+    }
+
+    match best {
+        Some(best) => Ok(Some(best)),
+        None if timed_out => Ok(None),
+        None => Err(TransformError::BudgetUnreachable {
+            max_bytes,
+            smallest,
+        }),
+    }
+}
+
+/// エラー表示用の単色 (ライトグレー) の PNG を生成する。
+pub fn placeholder_image(width: u32, height: u32) -> Result<Vec<u8>, TransformError> {
+    let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+        width,
+        height,
+        image::Rgb(PLACEHOLDER_COLOR),
+    ));
+    let options = EncodeOptions {
+        quality: DEFAULT_QUALITY,
+        subsampling: None,
+        progressive: false,
+        metadata: EmbeddedMetadata::default(),
+        near_lossless: None,
+        prefer_palette: true,
+        encoder: EncoderConfig::default(),
+        colors: None,
+    };
+    encode_image(&img, OutputFormat::Png, &options)
+}
+
+/// 小さな合成画像を指定フォーマットでエンコードし、エンコーダが動作するかを確認する。
+pub fn check_encoder(format: OutputFormat) -> Result<(), TransformError> {
+    let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(8, 8, |x, y| {
+        image::Rgba([(x * 32) as u8, (y * 32) as u8, 128, 255])
+    }));
+    let options = EncodeOptions {
+        quality: DEFAULT_QUALITY,
+        subsampling: None,
+        progressive: false,
+        metadata: EmbeddedMetadata::default(),
+        near_lossless: None,
+        prefer_palette: false,
+        encoder: EncoderConfig::default(),
+        colors: None,
+    };
+    let bytes = encode_image(&img, format, &options)?;
+    if bytes.is_empty() {
+        return Err(TransformError::ProcessingFailed(format!(
+            "{} encoder produced no output",
+            format.name()
+        )));
+    }
+    Ok(())
+}
+
+/// 色数が 256 以下であればインデックスカラーの PNG としてエンコードする。
+///
+/// ビット深度は色数に応じて 1/2/4/8 から最小のものを選ぶ。
+/// 257 色以上の場合は None を返す (呼び出し側で通常の PNG にフォールバックする)。
+fn encode_png_indexed(
+    img: &DynamicImage,
+    encoder_config: &EncoderConfig,
+) -> Result<Option<Vec<u8>>, TransformError> {
+    let rgba = img.to_rgba8();
+    let mut palette: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut indices = Vec::with_capacity(rgba.as_raw().len() / 4);
+    for pixel in rgba.pixels() {
+        let next = palette.len();
+        let index = match palette.get(&pixel.0) {
+            Some(&index) => index,
+            None if next < 256 => {
+                palette.insert(pixel.0, next as u8);
+                next as u8
+            }
+            None => return Ok(None),
+        };
+        indices.push(index);
+    }
+
+    let mut entries = vec![[0u8; 4]; palette.len()];
+    for (color, index) in palette {
+        entries[index as usize] = color;
+    }
+    let (depth, bits) = match entries.len() {
+        0..=2 => (png::BitDepth::One, 1),
+        3..=4 => (png::BitDepth::Two, 2),
+        5..=16 => (png::BitDepth::Four, 4),
+        _ => (png::BitDepth::Eight, 8),
+    };
+
+    // 1 行ごとに MSB 側から詰める (行末の余りビットは 0)
+    let width = rgba.width() as usize;
+    let per_byte = 8 / bits;
+    let mut data = Vec::with_capacity(width.div_ceil(per_byte) * rgba.height() as usize);
+    for row in indices.chunks_exact(width) {
+        for chunk in row.chunks(per_byte) {
+            let byte = chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |acc, (i, &index)| acc | index << (8 - bits * (i + 1)));
+            data.push(byte);
+        }
+    }
+
+    let map_err =
+        |e: png::EncodingError| TransformError::ProcessingFailed(format!("PNG encode failed: {e}"));
+    let mut buf = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buf, rgba.width(), rgba.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(depth);
+    if let Some(compression) = encoder_config.png_compression {
+        encoder.set_compression(compression.png_compression());
+    }
+    if let Some(filter) = encoder_config.png_filter {
+        encoder.set_filter(filter.png_filter());
+    }
+    encoder.set_palette(
+        entries
+            .iter()
+            .flat_map(|c| [c[0], c[1], c[2]])
+            .collect::<Vec<_>>(),
+    );
+    // 完全不透明なら tRNS は不要
+    if entries.iter().any(|c| c[3] != 255) {
+        encoder.set_trns(entries.iter().map(|c| c[3]).collect::<Vec<_>>());
+    }
+    let mut writer = encoder.write_header().map_err(map_err)?;
+    writer.write_image_data(&data).map_err(map_err)?;
+    writer.finish().map_err(map_err)?;
+
+    Ok(Some(buf))
+}
+
+/// エンコード結果のマジックバイトを判別し、`format` の Content-Type と一致しなければ失敗する。
+fn verify_output_format(bytes: &[u8], format: OutputFormat) -> Result<(), TransformError> {
+    let sniffed = sniff_format(bytes);
+    if sniffed.map(|f| f.content_type()) == Some(format.content_type()) {
+        return Ok(());
+    }
+    tracing::error!(
+        expected = format.name(),
+        actual = sniffed.map(|f| f.name()),
+        size = bytes.len(),
+        "encoded output does not match the declared format"
+    );
+    Err(TransformError::ProcessingFailed(format!(
+        "encoded output is not {}",
+        format.name()
+    )))
+}
+
+/// PNG の IHDR の直後に、72dpi x `retina` の pHYs チャンクを挿入する。
+///
+/// デザインツールは pHYs の解像度から表示サイズ (pt) を決めるため、2 倍の画像を 144dpi として扱わせる。
+/// image の PNG エンコーダは pHYs を書き込めないため、エンコード後に挿入する。
+fn insert_png_density(png: Vec<u8>, retina: u8) -> Vec<u8> {
+    // シグネチャ (8) + IHDR チャンク (長さ 4 + 種別 4 + データ 13 + CRC 4)
+    const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+    if png.len() < IHDR_END || &png[12..16] != b"IHDR" {
+        return png;
+    }
+    let pixels_per_meter = (BASE_DPI * retina as f64 / METERS_PER_INCH).round() as u32;
+
+    let mut chunk = Vec::with_capacity(21);
+    chunk.extend_from_slice(&9u32.to_be_bytes());
+    chunk.extend_from_slice(b"pHYs");
+    chunk.extend_from_slice(&pixels_per_meter.to_be_bytes());
+    chunk.extend_from_slice(&pixels_per_meter.to_be_bytes());
+    // 単位: メートル
+    chunk.push(1);
+    // CRC は種別とデータが対象
+    let crc = crc32fast::hash(&chunk[4..]);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+
+    let mut output = Vec::with_capacity(png.len() + chunk.len());
+    output.extend_from_slice(&png[..IHDR_END]);
+    output.extend_from_slice(&chunk);
+    output.extend_from_slice(&png[IHDR_END..]);
+    output
+}
+
+/// libwebp でロスレス WebP (near-lossless 前処理付き) をエンコードする。
+///
+/// image の WebP エンコーダは前処理レベルと method を指定できないため webp (libwebp) を使う。
+/// `level` は 0 が最も強い前処理、100 で前処理なし (通常のロスレスと同じ)。
+/// `method` が None の場合は libwebp のデフォルト (4)。
+fn encode_webp_libwebp(
+    img: &DynamicImage,
+    level: u8,
+    method: Option<u8>,
+) -> Result<Vec<u8>, TransformError> {
+    let rgba = img.to_rgba8();
+    let mut config = webp::WebPConfig::new().map_err(|_| {
+        TransformError::ProcessingFailed("WebP encode failed: invalid config".to_string())
+    })?;
+    config.lossless = 1;
+    config.near_lossless = level as i32;
+    if let Some(method) = method {
+        config.method = method as i32;
+    }
+
+    let encoded = webp::Encoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height())
+        .encode_advanced(&config)
+        .map_err(|e| TransformError::ProcessingFailed(format!("WebP encode failed: {e:?}")))?;
+
+    Ok(encoded.to_vec())
+}
+
+/// 制限時間を超えた後もバックグラウンドで動き続けている AVIF エンコードの数。
+///
+/// 上限 (`TransformConfig::max_abandoned_avif_encodes`) はプロセス全体で数えるため、
+/// `AppState` の 1 つをすべてのリクエストで共有する。
+#[derive(Debug, Clone, Default)]
+pub struct AbandonedAvifEncodes(Arc<AtomicUsize>);
+
+impl AbandonedAvifEncodes {
+    fn count(&self) -> &AtomicUsize {
+        &self.0
+    }
+}
+
+/// 出力をエンコードし、エンコード結果と出力フォーマットを返す。
+///
+/// AVIF が制限時間内に終わらなかった場合は WebP で返す。
+pub fn encode_output(
+    img: &DynamicImage,
+    format: OutputFormat,
+    options: &EncodeOptions,
+    limits: &Limits,
+    config: &TransformConfig,
+    abandoned: &AbandonedAvifEncodes,
+) -> Result<(Vec<u8>, OutputFormat), TransformError> {
+    match encode_image_timed(img, format, options, limits, config, abandoned)? {
+        Some(bytes) => Ok((bytes, format)),
+        None => Ok((
+            encode_avif_fallback(img, options, limits)?,
+            OutputFormat::WebP,
+        )),
+    }
+}
+
+/// `format` でエンコードする。
+///
+/// AVIF は `config.avif_encode_timeout` と期限までの残り時間の短い方を制限時間として
+/// 別スレッドでエンコードし、時間内に終わらなければ None を返す。
+fn encode_image_timed(
+    img: &DynamicImage,
+    format: OutputFormat,
+    options: &EncodeOptions,
+    limits: &Limits,
+    config: &TransformConfig,
+    abandoned: &AbandonedAvifEncodes,
+) -> Result<Option<Vec<u8>>, TransformError> {
+    // max_bytes の探索では試行ごとに呼ばれるため、残り時間もその都度求める
+    let remaining = limits
+        .deadline
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    let timeout = match (config.avif_encode_timeout, remaining) {
+        (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+        (timeout, remaining) => timeout.or(remaining),
+    };
+    match (format, timeout) {
+        (OutputFormat::Avif, Some(timeout)) => encode_avif_with_timeout(
+            img,
+            options.quality,
+            options.encoder.avif_speed,
+            timeout,
+            config.max_abandoned_avif_encodes,
+            abandoned,
+        ),
+        _ => encode_image(img, format, options).map(Some),
+    }
+}
+
+/// 制限時間内に終わらなかった AVIF の代わりに WebP でエンコードする (AVIF と同じくメタデータは埋め込まない)。
+///
+/// 期限切れで打ち切った場合はフォールバックも行わずにエラーとする。
+fn encode_avif_fallback(
+    img: &DynamicImage,
+    options: &EncodeOptions,
+    limits: &Limits,
+) -> Result<Vec<u8>, TransformError> {
+    limits.check_deadline("encode")?;
+    tracing::warn!(
+        width = img.width(),
+        height = img.height(),
+        "AVIF encode timed out, falling back to WebP"
+    );
+    let options = EncodeOptions {
+        metadata: EmbeddedMetadata::default(),
+        ..options.clone()
+    };
+    encode_image(img, OutputFormat::WebP, &options)
+}
+
+/// AVIF エンコードスレッドの状態
+const AVIF_RUNNING: u8 = 0;
+const AVIF_FINISHED: u8 = 1;
+const AVIF_ABANDONED: u8 = 2;
+
+/// 別スレッドで AVIF をエンコードし、`timeout` 以内に終わらなければ None を返す。
+///
+/// ravif はエンコードの中断に対応しないため、タイムアウト後もスレッドは完了まで動き続け、
+/// 結果は破棄される。その間もスレッドは CPU と画像のコピーのメモリを使い続けるが、
+/// 呼び出し側の permit やメモリの予約はレスポンスとともに解放されるため、
+/// 動き続けているスレッドを `abandoned` で数え、`max_abandoned` に達している間は
+/// 新たなエンコードを始めずにエラーとする (無制限にスレッドが溜まるのを防ぐ)。
+fn encode_avif_with_timeout(
+    img: &DynamicImage,
+    quality: f32,
+    speed: Option<u8>,
+    timeout: Duration,
+    max_abandoned: usize,
+    abandoned: &AbandonedAvifEncodes,
+) -> Result<Option<Vec<u8>>, TransformError> {
+    let running = abandoned.count().load(Ordering::Acquire);
+    if max_abandoned > 0 && running >= max_abandoned {
+        tracing::warn!(
+            running,
+            max_abandoned,
+            "too many timed-out AVIF encodes still running, rejecting"
+        );
+        return Err(TransformError::AvifEncodersSaturated { running });
+    }
+
+    let img = DynamicImage::ImageRgba8(img.to_rgba8());
+    let state = Arc::new(AtomicU8::new(AVIF_RUNNING));
+    let (tx, rx) = mpsc::channel();
+    let thread_state = state.clone();
+    let thread_abandoned = abandoned.clone();
+    thread::Builder::new()
+        .name("avif-encode".to_string())
+        .spawn(move || {
+            let _ = tx.send(encode_avif(&img, quality, speed));
+            // 呼び出し側が待つのをやめていた場合は、動き続けていた数から外す
+            let finished = thread_state.compare_exchange(
+                AVIF_RUNNING,
+                AVIF_FINISHED,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+            if finished.is_err() {
+                thread_abandoned.count().fetch_sub(1, Ordering::AcqRel);
+            }
+        })
+        .map_err(|e| {
+            TransformError::ProcessingFailed(format!("failed to spawn AVIF encoder: {e}"))
+        })?;
+
+    let disconnected =
+        || TransformError::ProcessingFailed("AVIF encoder exited without a result".to_string());
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result.map(Some),
+        Err(RecvTimeoutError::Timeout) => {
+            // スレッドが減らす前に必ず数えるよう、状態を変える前に加算する
+            abandoned.count().fetch_add(1, Ordering::AcqRel);
+            let abandon = state.compare_exchange(
+                AVIF_RUNNING,
+                AVIF_ABANDONED,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+            if abandon.is_ok() {
+                return Ok(None);
+            }
+            // タイムアウトの直後に終わっていた場合は結果を使う
+            abandoned.count().fetch_sub(1, Ordering::AcqRel);
+            match rx.try_recv() {
+                Ok(result) => result.map(Some),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => Err(disconnected()),
+            }
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(disconnected()),
+    }
+}
+
+/// AVIF をエンコードする。
+///
+/// image の AvifEncoder は整数の品質しか受け付けないため、小数の品質を
+/// そのまま渡せるよう ravif を直接使う (設定は image の AvifEncoder と同じ)。
+/// `speed` が None の場合は `AVIF_SPEED`。
+fn encode_avif(
+    img: &DynamicImage,
+    quality: f32,
+    speed: Option<u8>,
+) -> Result<Vec<u8>, TransformError> {
+    let rgba = img.to_rgba8();
+    let pixels: Vec<RGBA8> = rgba
+        .as_raw()
+        .chunks_exact(4)
+        .map(|p| RGBA8::new(p[0], p[1], p[2], p[3]))
+        .collect();
+
+    // 完全不透明な場合、ravif はアルファチャンネルを省略する
+    let encoded = ravif::Encoder::new()
+        .with_quality(quality)
+        .with_alpha_quality(quality)
+        .with_speed(speed.unwrap_or(AVIF_SPEED))
+        .with_bit_depth(BitDepth::Eight)
+        .encode_rgba(Img::new(
+            pixels.as_slice(),
+            rgba.width() as usize,
+            rgba.height() as usize,
+        ))
+        .map_err(|e| TransformError::ProcessingFailed(format!("AVIF encode failed: {e}")))?;
+
+    Ok(encoded.avif_file)
+}
+
+/// 他のモジュールのテストでも使う入力画像。
+#[cfg(test)]
+pub mod fixtures {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+    use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+
+    /// ノイズ入りの RGB 画像 (JPEG で圧縮が効きにくい)
+    pub fn noise_image(width: u32, height: u32) -> DynamicImage {
+        let mut seed = 0x2545_f491u32;
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |_, _| {
+            let mut next = || {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            };
+            Rgb([next(), next(), next()])
+        }))
+    }
+
+    pub fn encode_as(img: &DynamicImage, format: ImageFormat) -> Bytes {
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, format).unwrap();
+        Bytes::from(buf.into_inner())
+    }
+
+    /// 後半を切り落とした PNG。フォーマットは判別できるが、デコードすると CorruptImage になる
+    pub fn truncated_png() -> Bytes {
+        let png = encode_as(&noise_image(64, 64), ImageFormat::Png);
+        png.slice(..png.len() / 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{encode_as, noise_image, truncated_png};
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba};
+
+    /// `value(x, y)` の輝度で塗ったグレーの RGB 画像
+    fn gray_image(width: u32, height: u32, value: impl Fn(u32, u32) -> u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let v = value(x, y);
+            Rgb([v, v, v])
+        }))
+    }
+
+    fn expired_limits() -> Limits {
+        Limits {
+            deadline: Some(Instant::now()),
+            ..Limits::default()
+        }
+    }
+
+    /// デフォルトの上限と設定で変換する
+    fn transform_default(
+        input: &Bytes,
+        params: &TransformParams,
+    ) -> Result<TransformOutput, TransformError> {
+        transform(
+            input,
+            params,
+            &Limits::default(),
+            &TransformConfig::default(),
+            &AbandonedAvifEncodes::default(),
+        )
+    }
+
+    /// `settings` で `width`x`height` にリサイズした RGBA 画像
+    fn resize_rgba(
+        img: &DynamicImage,
+        width: u32,
+        height: u32,
+        settings: ResizeSettings,
+    ) -> RgbaImage {
+        resize_image(img, width, height, settings, None)
+            .unwrap()
+            .into_rgba8()
+    }
+
+    /// デコードに失敗することを確かめ、そのエラーを返す
+    fn decode_error(input: &Bytes) -> TransformError {
+        decode_image(input, &Limits::default())
+            .err()
+            .expect("input should not decode")
+    }
+
+    #[test]
+    fn expired_deadline_stops_before_decode() {
+        let input = encode_as(&noise_image(8, 8), ImageFormat::Png);
+        let err = transform(
+            &input,
+            &TransformParams::default(),
+            &expired_limits(),
+            &TransformConfig::default(),
+            &AbandonedAvifEncodes::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            TransformError::DeadlineExceeded { stage: "decode" }
+        ));
+    }
+
+    #[test]
+    fn budget_search_checks_deadline_between_attempts() {
+        let options = EncodeOptions {
+            quality: 90.0,
+            subsampling: None,
+            progressive: false,
+            metadata: EmbeddedMetadata::default(),
+            near_lossless: None,
+            prefer_palette: false,
+            encoder: EncoderConfig::default(),
+            colors: None,
+        };
+        let img = noise_image(64, 64);
+        let err = encode_within_budget(&options, 100, 1.0, &expired_limits(), |options| {
+            encode_image(&img, OutputFormat::Jpeg, options).map(Some)
+        })
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            TransformError::DeadlineExceeded { stage: "encode" }
+        ));
+    }
+
+    #[test]
+    fn truncated_png_is_reported_as_corrupt() {
+        let err = decode_error(&truncated_png());
+        assert!(matches!(err, TransformError::CorruptImage(_)), "{err:?}");
+    }
+
+    #[test]
+    fn truncated_jpeg_is_rejected_unless_partial_is_allowed() {
+        let jpeg = encode_as(&noise_image(32, 32), ImageFormat::Jpeg);
+        let truncated = jpeg.slice(..jpeg.len() / 2);
+        let err = decode_error(&truncated);
+        assert!(matches!(err, TransformError::CorruptImage(_)), "{err:?}");
+
+        let decoded = decode_image_with(&truncated, &Limits::default(), true).unwrap();
+        assert_eq!((decoded.image.width(), decoded.image.height()), (32, 32));
+    }
+
+    #[test]
+    fn jpeg_with_data_after_eoi_decodes_without_partial() {
+        // Motion Photo のように EOI の後ろに別のデータが続く JPEG
+        let jpeg = encode_as(&noise_image(32, 32), ImageFormat::Jpeg);
+        let with_trailer = Bytes::from([&jpeg[..], b"\0\0\0\x18ftypmp42trailer"].concat());
+        let decoded = decode_image(&with_trailer, &Limits::default()).unwrap();
+        assert_eq!((decoded.image.width(), decoded.image.height()), (32, 32));
+    }
+
+    #[test]
+    fn png_under_jpeg_key_is_decoded_as_png() {
+        // キーの拡張子 (.jpg) ではなくマジックバイトでデコードし、元のフォーマットで出力する
+        let input = encode_as(&noise_image(16, 16), ImageFormat::Png);
+        let params = TransformParams {
+            width: Some(8),
+            extension_hint: Some(OutputFormat::Jpeg),
+            ..TransformParams::default()
+        };
+        let output = transform_default(&input, &params).unwrap();
+        assert_eq!(output.source_format, Some(ImageFormat::Png));
+        assert_eq!(output.content_type, OutputFormat::Png.content_type());
+        assert_eq!((output.width, output.height), (8, 8));
+    }
+
+    #[test]
+    fn source_pixel_limit_is_reported_with_the_effective_limit() {
+        let input = encode_as(&noise_image(16, 16), ImageFormat::Png);
+        let limits = Limits {
+            max_pixels: 100,
+            ..Limits::default()
+        };
+        let err = transform(
+            &input,
+            &TransformParams::default(),
+            &limits,
+            &TransformConfig::default(),
+            &AbandonedAvifEncodes::default(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                TransformError::ResolutionTooLarge {
+                    width: 16,
+                    height: 16,
+                    max_pixels: 100
+                }
+            ),
+            "{err:?}"
+        );
+        assert!(err.to_string().contains("100 pixels"), "{err}");
+    }
+
+    /// 透明 (RGB は黒) の背景に不透明な赤い円を描いた画像
+    fn transparent_circle(size: u32) -> DynamicImage {
+        let center = size as f32 / 2.0;
+        let radius = size as f32 / 3.0;
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(size, size, |x, y| {
+            let (dx, dy) = (x as f32 + 0.5 - center, y as f32 + 0.5 - center);
+            if dx * dx + dy * dy <= radius * radius {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        }))
+    }
+
+    #[test]
+    fn premultiplied_resize_keeps_edge_color() {
+        let img = transparent_circle(64);
+        let resize = |premultiply_alpha| {
+            let settings = ResizeSettings {
+                premultiply_alpha,
+                ..ResizeSettings::default()
+            };
+            resize_rgba(&img, 16, 16, settings)
+        };
+        let (plain, premultiplied) = (resize(false), resize(true));
+
+        // 半透明になった境界の画素のみを比較する
+        let edges: Vec<_> = plain
+            .pixels()
+            .zip(premultiplied.pixels())
+            .filter(|(p, _)| (32..224).contains(&p[3]))
+            .collect();
+        assert!(!edges.is_empty());
+        for (plain, premultiplied) in edges {
+            assert!(premultiplied[0] >= 250, "premultiplied: {premultiplied:?}");
+            assert!(plain[0] < premultiplied[0], "plain: {plain:?}");
+        }
+    }
+
+    #[test]
+    fn empty_input_is_rejected_before_decoding() {
+        for input in [Bytes::new(), Bytes::from_static(b"\x89PNG")] {
+            let err = transform_default(&input, &TransformParams::default()).unwrap_err();
+            assert!(
+                matches!(err, TransformError::EmptyInput { size } if size == input.len()),
+                "{err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn linear_resize_keeps_checkerboard_brightness() {
+        // resize_image は RGBA に変換してからリサイズするため、不透明な RGB でも結果は同じ
+        let checkerboard = gray_image(64, 64, |x, y| if (x + y) % 2 == 0 { 0 } else { 255 });
+        let mean_gray = |linear_light| {
+            let settings = ResizeSettings {
+                linear_light,
+                ..ResizeSettings::default()
+            };
+            let resized = resize_rgba(&checkerboard, 8, 8, settings);
+            let sum: u32 = resized.pixels().map(|p| p[0] as u32).sum();
+            sum as f32 / (8 * 8) as f32
+        };
+
+        // 黒と白の平均は、sRGB の値のままだと 128 付近、リニア光では約 188 (輝度 50%) になる
+        let (srgb, linear) = (mean_gray(false), mean_gray(true));
+        assert!((srgb - 128.0).abs() < 4.0, "srgb: {srgb}");
+        assert!((linear - 188.0).abs() < 4.0, "linear: {linear}");
+    }
+
+    #[test]
+    fn upscale_uses_configured_upscale_filter() {
+        // 左半分が黒、右半分が白の 2x2 画像を 8x8 に拡大する
+        let source = gray_image(2, 2, |x, _| if x == 0 { 0 } else { 255 });
+        let input = encode_as(&source, ImageFormat::Png);
+        let params = TransformParams {
+            width: Some(8),
+            format: Some(OutputFormat::Png),
+            ..TransformParams::default()
+        };
+        let upscale = |downscale: &str, upscale: &str| {
+            let config = TransformConfig {
+                downscale_filter: TransformConfig::parse_filter(downscale).unwrap(),
+                upscale_filter: TransformConfig::parse_filter(upscale).unwrap(),
+                ..TransformConfig::default()
+            };
+            let output = transform(
+                &input,
+                &params,
+                &Limits::default(),
+                &config,
+                &AbandonedAvifEncodes::default(),
+            )
+            .unwrap();
+            let decoded = image::load_from_memory(&output.bytes).unwrap().into_luma8();
+            assert_eq!(decoded.dimensions(), (8, 8));
+            decoded.into_raw()
+        };
+
+        // box は補間しないため、黒と白のみになる
+        let boxed = upscale("bilinear", "box");
+        assert!(boxed.iter().all(|&v| v == 0 || v == 255), "{boxed:?}");
+        let smooth = upscale("box", "bilinear");
+        assert!(smooth.iter().any(|&v| v != 0 && v != 255), "{smooth:?}");
+    }
+
+    #[test]
+    fn forbidden_conversion_is_rejected_before_decoding() {
+        // デコードすると CorruptImage になる途切れた PNG でも、変換の禁止が先に返る
+        let config = TransformConfig {
+            forbidden_conversions: vec![(ImageFormat::Png, OutputFormat::Jpeg)],
+            ..TransformConfig::default()
+        };
+        let params = TransformParams {
+            format: Some(OutputFormat::Jpeg),
+            ..TransformParams::default()
+        };
+        let err = transform(
+            &truncated_png(),
+            &params,
+            &Limits::default(),
+            &config,
+            &AbandonedAvifEncodes::default(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                TransformError::ConversionForbidden {
+                    from: ImageFormat::Png,
+                    to: OutputFormat::Jpeg
+                }
+            ),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn blur_region_unit_is_explicit() {
+        assert_eq!(
+            BlurRegion::from_str_param("0.25,0.5,0.5,0.25"),
+            Roi::from_str_param("0.25,0.5,0.5,0.25").map(BlurRegion::Relative)
+        );
+        assert_eq!(
+            BlurRegion::from_str_param("10,20,30,40px"),
+            Some(BlurRegion::Pixels {
+                x: 10,
+                y: 20,
+                width: 30,
+                height: 40
+            })
+        );
+        // 1 以下の値でも px を付ければピクセル座標
+        assert_eq!(
+            BlurRegion::from_str_param("0,0,1,1px"),
+            Some(BlurRegion::Pixels {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1
+            })
+        );
+        for s in [
+            // px のない 1 を超える値は相対座標として不正
+            "10,20,30,40",
+            "0,0,1,1.5",
+            "0.5,0,0.6,1",
+            "0,0,0,1",
+            "1.5,0,10,10px",
+            "-1,0,10,10px",
+            "0,0,0,10px",
+            "0,0,10px",
+            "0,0,10,10pxx",
+        ] {
+            assert_eq!(BlurRegion::from_str_param(s), None, "{s}");
+        }
+    }
+
+    #[test]
+    fn blur_region_outside_the_output_is_rejected() {
+        let input = encode_as(&noise_image(32, 32), ImageFormat::Png);
+        let blur = |region: &str, width: Option<u32>| {
+            let params = TransformParams {
+                width,
+                blur_region: BlurRegion::from_str_param(region),
+                ..TransformParams::default()
+            };
+            transform_default(&input, &params)
+        };
+
+        assert!(blur("0,0,32,32px", None).is_ok());
+        assert!(blur("0.5,0.5,0.5,0.5", Some(16)).is_ok());
+        // ピクセル座標はリサイズ後の出力に対して検証する
+        for (region, width) in [("0,0,32,32px", Some(16)), ("30,0,4,4px", None)] {
+            let err = blur(region, width).unwrap_err();
+            assert!(
+                matches!(err, TransformError::InvalidParams(ref m) if m.contains("blur_region")),
+                "{region}: {err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported_output_params_are_rejected_before_decoding() {
+        // デコードすると CorruptImage になる途切れた PNG でも、パラメータのエラーが先に返る
+        let truncated = truncated_png();
+        let cases = [
+            TransformParams {
+                subsampling: Some(ChromaSubsampling::S444),
+                ..TransformParams::default()
+            },
+            TransformParams {
+                progressive: true,
+                ..TransformParams::default()
+            },
+            TransformParams {
+                near_lossless: Some(60),
+                ..TransformParams::default()
+            },
+            TransformParams {
+                format: Some(OutputFormat::Jpeg),
+                retina: Some(2),
+                ..TransformParams::default()
+            },
+            TransformParams {
+                format: Some(OutputFormat::Jpeg),
+                colors: Some(16),
+                ..TransformParams::default()
+            },
+            TransformParams {
+                max_bytes: Some(1000),
+                ..TransformParams::default()
+            },
+            TransformParams {
+                quality: Some(80.0),
+                ..TransformParams::default()
+            },
+        ];
+        for params in cases {
+            let err = transform_default(&truncated, &params).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    TransformError::InvalidParams(_) | TransformError::QualityNotSupported { .. }
+                ),
+                "{params:?}: {err:?}"
+            );
+        }
+    }
+}